@@ -59,6 +59,7 @@ find fs/btrfs -name Makefile \
     -e BOOTPARAM_HARDLOCKUP_PANIC \
     -e DETECT_HUNG_TASK \
     -e WQ_WATCHDOG \
+    -e MAGIC_SYSRQ \
     --set-val DEFAULT_HUNG_TASK_TIMEOUT 140 \
     --set-val RCU_CPU_STALL_TIMEOUT 100 \
     -e UBSAN \
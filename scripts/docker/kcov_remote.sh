@@ -0,0 +1,70 @@
+#!/bin/bash
+#
+# Annotate btrfs workers and kthreads with kcov remote sections so background
+# work a test case triggers counts as its coverage. The runner enables remote
+# kcov with a common handle that every task it forks inherits. We record that
+# handle when work is queued (or when the kthreads are started at mount time)
+# and collect coverage under it while the work runs.
+#
+# Anchors are matched against source lines instead of shipping a diff so this
+# keeps applying as the kernel moves. Fails the build if an anchor is gone.
+#
+# Run this inside kernel source tree root.
+
+set -eu
+
+# annotate FILE FUNCTION ANCHOR before|after LINE
+#
+# Insert LINE before or after the first line in FILE that starts with ANCHOR,
+# using the same indentation. Only FUNCTION's body is searched unless FUNCTION
+# is empty.
+annotate() {
+    FUNC="$2" ANCHOR="$3" WHERE="$4" LINE="$5" perl -i -ne '
+        $in = $ENV{FUNC} eq ""
+            || ((/^\S.*\b\Q$ENV{FUNC}\E\(/ && !/;\s*$/) .. /^}/);
+        if (!$done && $in && /^(\s*)\Q$ENV{ANCHOR}\E/) {
+            print "$1$ENV{LINE}\n" if $ENV{WHERE} eq "before";
+            print;
+            print "$1$ENV{LINE}\n" if $ENV{WHERE} eq "after";
+            $done = 1;
+            next;
+        }
+        print;
+        END { die "anchor not found: $ENV{FUNC} $ENV{ANCHOR}\n" unless $done }
+    ' "$1"
+}
+
+# Work queued onto btrfs workqueues: endio, delayed refs, async reclaim,
+# scrub, etc.
+annotate fs/btrfs/async-thread.h "" \
+    "struct btrfs_workqueue *wq;" after "u64 kcov_handle;"
+annotate fs/btrfs/async-thread.c "" \
+    "#include" before "#include <linux/kcov.h>"
+annotate fs/btrfs/async-thread.c btrfs_queue_work \
+    "work->wq = wq;" after "work->kcov_handle = kcov_common_handle();"
+# The work may be freed by the time func returns so don't touch it after
+annotate fs/btrfs/async-thread.c btrfs_work_helper \
+    "work->func(work);" before "kcov_remote_start_common(work->kcov_handle);"
+annotate fs/btrfs/async-thread.c btrfs_work_helper \
+    "work->func(work);" after "kcov_remote_stop();"
+
+# Per mount kthreads. The mounting task's handle is recorded in open_ctree().
+annotate "$(grep -l "struct task_struct \*transaction_kthread;" fs/btrfs/*.h)" "" \
+    "struct task_struct *transaction_kthread;" after "u64 kcov_handle;"
+annotate fs/btrfs/disk-io.c "" \
+    "#include" before "#include <linux/kcov.h>"
+annotate fs/btrfs/disk-io.c open_ctree \
+    "fs_info->cleaner_kthread = kthread_run(" before \
+    "fs_info->kcov_handle = kcov_common_handle();"
+annotate fs/btrfs/disk-io.c cleaner_kthread \
+    "set_bit(BTRFS_FS_CLEANER_RUNNING, &fs_info->flags);" after \
+    "kcov_remote_start_common(fs_info->kcov_handle);"
+annotate fs/btrfs/disk-io.c cleaner_kthread \
+    "clear_and_wake_up_bit(BTRFS_FS_CLEANER_RUNNING, &fs_info->flags);" before \
+    "kcov_remote_stop();"
+annotate fs/btrfs/disk-io.c transaction_kthread \
+    "mutex_lock(&fs_info->transaction_kthread_mutex);" before \
+    "kcov_remote_start_common(fs_info->kcov_handle);"
+annotate fs/btrfs/disk-io.c transaction_kthread \
+    "mutex_unlock(&fs_info->transaction_kthread_mutex);" after \
+    "kcov_remote_stop();"
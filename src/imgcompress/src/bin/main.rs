@@ -3,8 +3,9 @@ use std::io::{Read, Write};
 use std::path::PathBuf;
 
 use anyhow::Result;
+use imgcompress::{CompressOptions, DecompressOptions, HeaderField};
 use rmp_serde::{decode::from_read_ref, Serializer};
-use serde::Serialize;
+use serde::{de::IgnoredAny, Serialize};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -22,6 +23,9 @@ enum Command {
         input: PathBuf,
         #[structopt(parse(from_os_str))]
         output: PathBuf,
+        /// Also fuzz the data extents files point at, not just metadata
+        #[structopt(long)]
+        data: bool,
     },
     /// Decompress an imgcompress'd btrfs image
     Decompress {
@@ -29,15 +33,33 @@ enum Command {
         input: PathBuf,
         #[structopt(parse(from_os_str))]
         output: PathBuf,
+        /// Node header field to leave as fuzzed instead of restoring. Every field is restored
+        /// by default. May be given more than once. Available fields are bytenr, fsid,
+        /// chunk-tree-uuid and generation.
+        #[structopt(long, number_of_values = 1)]
+        no_header_fixup: Vec<HeaderField>,
+    },
+    /// Explain the metadata changes between two imgcompress'd images of the same base image, eg
+    /// a seed and a test case fuzzed from it
+    Diff {
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+    },
+    /// Print everything in a raw or imgcompress'd btrfs image, even if it's corrupt
+    Dump {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
     },
 }
 
-fn compress(input: PathBuf, output: PathBuf) -> Result<()> {
+fn compress(input: PathBuf, output: PathBuf, opts: CompressOptions) -> Result<()> {
     let mut input = OpenOptions::new().read(true).open(input)?;
 
     let mut input_image = Vec::new();
     input.read_to_end(&mut input_image)?;
-    let compressed_image = imgcompress::compress(&input_image)?;
+    let compressed_image = imgcompress::compress_with(&input_image, &opts)?;
 
     let output = OpenOptions::new()
         .create(true)
@@ -49,13 +71,32 @@ fn compress(input: PathBuf, output: PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn decompress(input: PathBuf, output: PathBuf) -> Result<()> {
+/// Deserialize an `imgcompress compress`d image
+fn parse_image(serialized_input: &[u8]) -> Result<imgcompress::CompressedBtrfsImage> {
+    // Fuzzer inputs wrap the image in a tuple along with the program to run. We only care about
+    // the image here.
+    match from_read_ref(serialized_input) {
+        Ok(image) => Ok(image),
+        Err(_) => {
+            let (image, _): (_, IgnoredAny) = from_read_ref(serialized_input)?;
+            Ok(image)
+        }
+    }
+}
+
+/// Read an `imgcompress compress`d image
+fn read_image(input: PathBuf) -> Result<imgcompress::CompressedBtrfsImage> {
     let mut input = OpenOptions::new().read(true).open(input)?;
 
     let mut serialized_input = Vec::new();
     input.read_to_end(&mut serialized_input)?;
-    let deserialized_input: imgcompress::CompressedBtrfsImage = from_read_ref(&serialized_input)?;
-    let decompressed_image = imgcompress::decompress(&deserialized_input)?;
+
+    parse_image(&serialized_input)
+}
+
+fn decompress(input: PathBuf, output: PathBuf, opts: DecompressOptions) -> Result<()> {
+    let deserialized_input = read_image(input)?;
+    let decompressed_image = imgcompress::decompress_with(&deserialized_input, &opts)?;
 
     let mut output = OpenOptions::new()
         .create(true)
@@ -67,11 +108,58 @@ fn decompress(input: PathBuf, output: PathBuf) -> Result<()> {
     Ok(())
 }
 
+fn diff(old: PathBuf, new: PathBuf) -> Result<()> {
+    let old = read_image(old)?;
+    let new = read_image(new)?;
+
+    let lines = imgcompress::diff::diff(&old, &new)?;
+    if lines.is_empty() {
+        println!("No metadata changes");
+    }
+    for line in lines {
+        println!("{}", line);
+    }
+
+    Ok(())
+}
+
+fn dump(input: PathBuf) -> Result<()> {
+    let mut input = OpenOptions::new().read(true).open(input)?;
+
+    let mut buf = Vec::new();
+    input.read_to_end(&mut buf)?;
+    // Raw images never deserialize b/c they start with zeroes
+    let image = match parse_image(&buf) {
+        Ok(image) => imgcompress::decompress(&image)?,
+        Err(_) => buf,
+    };
+
+    print!("{}", imgcompress::dump::dump(&image));
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let opts = Opt::from_args();
 
     match opts.cmd {
-        Command::Compress { input, output } => compress(input, output),
-        Command::Decompress { input, output } => decompress(input, output),
+        Command::Compress {
+            input,
+            output,
+            data,
+        } => compress(input, output, CompressOptions { data }),
+        Command::Decompress {
+            input,
+            output,
+            no_header_fixup,
+        } => decompress(
+            input,
+            output,
+            DecompressOptions {
+                skip_header_fixups: no_header_fixup,
+            },
+        ),
+        Command::Diff { old, new } => diff(old, new),
+        Command::Dump { input } => dump(input),
     }
 }
@@ -1,13 +1,31 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
-use std::mem::size_of;
+use std::mem::{size_of, size_of_val};
 
 use anyhow::{anyhow, bail, Context, Result};
 use zstd::stream::encode_all;
 
-use crate::chunk_tree::{ChunkTreeCache, ChunkTreeKey, ChunkTreeValue};
+use crate::chunk_tree::{ChunkTreeCache, ChunkTreeKey, ChunkTreeStripe, ChunkTreeValue, Profile};
+use crate::csum;
+use crate::fields::{Layout, StructAt, StructKind};
+use crate::parse;
 use crate::structs::*;
 use crate::tree;
-use crate::CompressedBtrfsImage;
+use crate::{CompressOptions, CompressedBtrfsImage, DataCsum, MetadataExtent};
+
+/// Compressed extents are never bigger than this on disk. Bigger data extents are skipped b/c
+/// they'd only bloat test cases.
+const MAX_DATA_EXTENT_SIZE: u64 = 128 << 10;
+
+/// A `BTRFS_EXTENT_CSUM_KEY` item
+struct CsumItem {
+    /// Logical address of the data sector the first csum covers
+    logical: u64,
+    /// Physical address of the item payload in every local copy of its leaf
+    physical: Vec<u64>,
+    /// Size of the payload
+    size: usize,
+}
 
 /// Helper struct to compress a valid btrfs image.
 ///
@@ -17,6 +35,22 @@ pub struct Btrfs<'a> {
     image: &'a [u8],
     superblock: &'a BtrfsSuperblock,
     chunk_tree_cache: ChunkTreeCache,
+    /// Device id of `image`. Used to pick out the stripes that live on this device.
+    devid: u64,
+    /// Logical addresses of every node walked so far. Guards against blockptr loops and keeps
+    /// nodes shared between trees (eg snapshots) from being annotated twice.
+    visited: HashSet<u64>,
+    /// Set while walking trees referenced by `BtrfsSuperblock::root_backups`. Those can point at
+    /// blocks that have since been reused, so nodes that don't look like they belong there are
+    /// skipped instead of annotated (which would make `decompress` rewrite their csum).
+    walking_backup_root: bool,
+    /// Set if file data should be fuzzed too. See `CompressOptions::data`.
+    fuzz_data: bool,
+    /// Every regular data extent referenced by a `BtrfsFileExtentItem` walked so far, logical
+    /// address to size on disk. Only collected if `fuzz_data` is set.
+    data_extents: BTreeMap<u64, u64>,
+    /// Every data csum item walked so far. Only collected if `fuzz_data` is set.
+    csum_items: Vec<CsumItem>,
 }
 
 impl<'a> Btrfs<'a> {
@@ -27,34 +61,49 @@ impl<'a> Btrfs<'a> {
         let superblock =
             parse_superblock(img).with_context(|| "Failed to parse superblock".to_string())?;
 
+        let devid = superblock.dev_item.devid.get();
+
         // Bootstraup chunk tree
         let mut chunk_tree_cache = bootstrap_chunk_tree(superblock)
             .with_context(|| "Failed to boostrap chunk tree".to_string())?;
 
-        // Read root chunk tree node
-        let chunk_root = read_root_node(img, superblock.chunk_root, &chunk_tree_cache)
-            .with_context(|| "Failed to read chunk tree root".to_string())?;
-
         // Read rest of chunk tree
-        read_chunk_tree(img, &chunk_root, &mut chunk_tree_cache, &superblock)
-            .with_context(|| "Failed to read chunk tree".to_string())?;
+        read_chunk_tree(
+            img,
+            superblock.chunk_root.get(),
+            &mut chunk_tree_cache,
+            superblock,
+            0,
+            &mut HashSet::new(),
+        )
+        .with_context(|| "Failed to read chunk tree".to_string())?;
 
         Ok(Self {
             image: img,
             superblock,
             chunk_tree_cache,
+            devid,
+            visited: HashSet::new(),
+            walking_backup_root: false,
+            fuzz_data: false,
+            data_extents: BTreeMap::new(),
+            csum_items: Vec::new(),
         })
     }
 
     /// Compress the image
-    pub fn compress(&self) -> Result<CompressedBtrfsImage> {
-        let mut compressed = CompressedBtrfsImage::default();
-
-        // Compress and save base image
-        compressed.base = encode_all(self.image, 0)?;
-
-        // Save node size b/c the value in the superblock could get fuzzed to something else
-        compressed.node_size = self.superblock.node_size.try_into()?;
+    pub fn compress(&mut self, opts: &CompressOptions) -> Result<CompressedBtrfsImage> {
+        let mut compressed = CompressedBtrfsImage {
+            // Compress and save base image
+            base: encode_all(self.image, 0)?,
+            // Save node size, sector size and csum type b/c the values in the superblock could
+            // get fuzzed to something else
+            node_size: self.superblock.node_size.get().try_into()?,
+            sector_size: self.superblock.sector_size.get().try_into()?,
+            csum_type: self.superblock.csum_type.get(),
+            ..Default::default()
+        };
+        self.fuzz_data = opts.data;
 
         // Save all superblocks
         self.save_superblocks(&mut compressed)?;
@@ -65,119 +114,418 @@ impl<'a> Btrfs<'a> {
 
         // The log tree seems to be maintained separately from the root tree, so parse everything
         // in there separately
-        if self.superblock.log_root != 0 {
-            self.parse_tree(self.superblock.log_root, &mut compressed)?;
+        if self.superblock.log_root.get() != 0 {
+            self.parse_tree(self.superblock.log_root.get(), 0, &mut compressed)?;
         }
 
+        // Backup roots are what `usebackuproot` and `rescue=` fall back on. Trees that are still
+        // current were already walked above so this only picks up older ones.
+        self.parse_backup_roots(&mut compressed);
+
+        if self.fuzz_data {
+            self.save_data(&mut compressed)?;
+        }
+
+        // Save where every field is so tools can target them without re-parsing the image
+        compressed.fields = compressed.compute_field_map(self.image)?;
+
         Ok(compressed)
     }
 
+    /// Remember the data extents and data csums referenced by the leaf `node`
+    fn collect_data_refs(&mut self, node: &[u8], items: &[BtrfsItem], mirrors: &[u64]) {
+        for item in items {
+            match (item.key.objectid.get(), item.key.ty) {
+                (_, BTRFS_EXTENT_DATA_KEY) => {
+                    // Inline extents are too short to parse and already part of the leaf
+                    let extent = match tree::parse_item::<BtrfsFileExtentItem>(node, item) {
+                        Ok(e) => e,
+                        Err(_) => continue,
+                    };
+                    // Holes have a zero `disk_bytenr`
+                    if extent.ty != BTRFS_FILE_EXTENT_REG || extent.disk_bytenr.get() == 0 {
+                        continue;
+                    }
+
+                    self.data_extents
+                        .insert(extent.disk_bytenr.get(), extent.disk_num_bytes.get());
+                }
+                (BTRFS_EXTENT_CSUM_OBJECTID, BTRFS_EXTENT_CSUM_KEY) => {
+                    let offset = (size_of::<BtrfsHeader>() + item.offset.get() as usize) as u64;
+                    self.csum_items.push(CsumItem {
+                        logical: item.key.offset.get(),
+                        physical: mirrors.iter().map(|m| m + offset).collect(),
+                        size: item.size.get() as usize,
+                    });
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Mark every data extent found while walking the trees as fuzzable and record which data
+    /// csums `decompress` has to recompute
+    fn save_data(&self, compressed: &mut CompressedBtrfsImage) -> Result<()> {
+        let sector_size = self.superblock.sector_size.get() as u64;
+        if sector_size == 0 {
+            bail!("Sector size is 0");
+        }
+
+        // Physical address of the first local copy of every fuzzable data sector
+        let mut sectors: HashMap<u64, u64> = HashMap::new();
+        // Physical address of every local copy of every fuzzable data sector
+        let mut copies: BTreeSet<u64> = BTreeSet::new();
+        for (logical, size) in &self.data_extents {
+            if *size > MAX_DATA_EXTENT_SIZE {
+                println!(
+                    "Warning: Skipping data extent logical addr={} b/c it's {} bytes",
+                    logical, size
+                );
+                continue;
+            }
+
+            for i in 0..(size / sector_size) {
+                let sector = logical + i * sector_size;
+                let local: Vec<u64> = self
+                    .chunk_tree_cache
+                    .mirrors(sector)
+                    .iter()
+                    .filter(|m| m.devid == self.devid)
+                    .map(|m| m.offset)
+                    .filter(|p| p.saturating_add(sector_size) <= self.image.len() as u64)
+                    .collect();
+                if let Some(first) = local.first() {
+                    sectors.insert(sector, *first);
+                }
+                copies.extend(local);
+            }
+        }
+
+        // Merge contiguous sectors so each extent gets a single entry
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for physical in copies {
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == physical => *len += sector_size,
+                _ => runs.push((physical, sector_size)),
+            }
+        }
+        for (physical, len) in runs {
+            let begin: usize = physical.try_into()?;
+            let end = begin + len as usize;
+            compressed.mark_as_data(physical, &self.image[begin..end])?;
+        }
+
+        let csum_size = csum::csum_size(compressed.csum_type)?;
+        for item in &self.csum_items {
+            for i in 0..(item.size / csum_size) {
+                let sector = item.logical + i as u64 * sector_size;
+                if let Some(data) = sectors.get(&sector) {
+                    compressed.data_csums.push(DataCsum {
+                        data: *data,
+                        csums: item
+                            .physical
+                            .iter()
+                            .map(|p| p + (i * csum_size) as u64)
+                            .collect(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Walk every tree referenced by a slot in `BtrfsSuperblock::root_backups`.
+    ///
+    /// Backup roots are best effort: trees that fail to parse are skipped with a warning.
+    fn parse_backup_roots(&mut self, compressed: &mut CompressedBtrfsImage) {
+        let superblock = self.superblock;
+        self.walking_backup_root = true;
+
+        for (i, backup) in superblock.root_backups.iter().enumerate() {
+            // The root tree is the only one that references other trees
+            let roots = [
+                ("tree_root", backup.tree_root.get(), true),
+                ("chunk_root", backup.chunk_root.get(), false),
+                ("extent_root", backup.extent_root.get(), false),
+                ("fs_root", backup.fs_root.get(), false),
+                ("dev_root", backup.dev_root.get(), false),
+                ("csum_root", backup.csum_root.get(), false),
+            ];
+            for (name, logical, is_root_tree) in &roots {
+                if *logical == 0 {
+                    continue;
+                }
+
+                let res = if *is_root_tree {
+                    self.parse_root_tree_node(*logical, 0, compressed)
+                } else {
+                    self.parse_tree(*logical, 0, compressed)
+                };
+                if let Err(e) = res {
+                    println!(
+                        "Warning: Skipping root_backups[{}].{}={}: {:#}",
+                        i, name, logical, e
+                    );
+                }
+            }
+        }
+
+        self.walking_backup_root = false;
+    }
+
+    /// Whether `node` should be walked. Only ever false while walking a backup root, where
+    /// `node` has to be at `logical` and have a valid csum to be trusted.
+    fn is_trusted_node(&self, logical: u64, node: &[u8]) -> Result<bool> {
+        if !self.walking_backup_root {
+            return Ok(true);
+        }
+
+        let header = tree::parse_btrfs_header(node)?;
+        if header.bytenr.get() != logical {
+            return Ok(false);
+        }
+        let csum = csum::checksum(self.superblock.csum_type.get(), &node[BTRFS_CSUM_SIZE..])?;
+
+        Ok(header.csum[..csum.len()] == csum[..])
+    }
+
+    /// Save the primary superblock and every mirror that's actually there.
+    ///
+    /// `decompress` rewrites the magic and `bytenr` of every saved copy, so mirrors with the
+    /// wrong magic (eg leftovers from a previous filesystem) are skipped to keep the image intact.
     fn save_superblocks(&self, compressed: &mut CompressedBtrfsImage) -> Result<()> {
-        // First superblock has to exist
-        compressed.mark_as_metadata(
-            BTRFS_SUPERBLOCK_OFFSET.try_into()?,
-            &self.image[BTRFS_SUPERBLOCK_OFFSET..(BTRFS_SUPERBLOCK_OFFSET + BTRFS_SUPERBLOCK_SIZE)],
-            true,
-        )?;
-
-        for offset in &[BTRFS_SUPERBLOCK_OFFSET2, BTRFS_SUPERBLOCK_OFFSET3] {
+        for offset in &BTRFS_SUPERBLOCK_OFFSETS {
             let offset = *offset;
-            if self.image.len() >= (offset + BTRFS_SUPERBLOCK_SIZE) {
-                compressed.mark_as_metadata(
-                    offset.try_into()?,
-                    &self.image[offset..(offset + BTRFS_SUPERBLOCK_SIZE)],
-                    true,
-                )?;
+            if self.image.len() < offset + BTRFS_SUPERBLOCK_SIZE {
+                continue;
+            }
+            let superblock: &BtrfsSuperblock = match parse::read(self.image, offset) {
+                Ok(sb) => sb,
+                Err(_) => continue,
+            };
+            // First superblock was already checked in `parse_superblock()`
+            if superblock.magic != BTRFS_SUPERBLOCK_MAGIC {
+                continue;
             }
+
+            compressed.mark_as_metadata(
+                offset.try_into()?,
+                &self.image[offset..(offset + BTRFS_SUPERBLOCK_SIZE)],
+                true,
+                None,
+            )?;
         }
 
         Ok(())
     }
 
-    fn parse_root_tree(&self, compressed: &mut CompressedBtrfsImage) -> Result<()> {
-        let physical = self
-            .chunk_tree_cache
-            .offset(self.superblock.root)
-            .ok_or_else(|| anyhow!("Root tree root logical addr not mapped"))?;
-        let node = read_root_node(self.image, self.superblock.root, &self.chunk_tree_cache)
-            .with_context(|| "Failed to read root tree root".to_string())?;
+    fn parse_root_tree(&mut self, compressed: &mut CompressedBtrfsImage) -> Result<()> {
+        self.parse_root_tree_node(self.superblock.root.get(), 0, compressed)
+    }
+
+    /// Whether the node at `logical`, `depth` levels below the root of its tree, should be
+    /// walked. Nodes that were already walked or are impossibly deep are skipped.
+    fn should_walk(&mut self, logical: u64, depth: usize) -> bool {
+        if depth >= BTRFS_MAX_LEVEL as usize {
+            println!(
+                "Warning: Skipping node logical addr={} b/c it's deeper than BTRFS_MAX_LEVEL",
+                logical
+            );
+            return false;
+        }
+
+        self.visited.insert(logical)
+    }
+
+    /// Returns the physical offsets of every copy of `logical` that lives in this image.
+    ///
+    /// Copies that live on other devices (eg the second half of a RAID1 mirror) are skipped
+    /// with a warning b/c they aren't in `self.image`.
+    fn local_mirrors(&self, logical: u64) -> Result<Vec<u64>> {
+        let mirrors = self.chunk_tree_cache.mirrors(logical);
+        if mirrors.is_empty() {
+            bail!("Node logical addr={} not mapped", logical);
+        }
+
+        let local: Vec<u64> = mirrors
+            .iter()
+            .filter(|m| m.devid == self.devid)
+            .map(|m| m.offset)
+            .collect();
+        if local.len() != mirrors.len() {
+            println!(
+                "Warning: {} of {} copies of logical addr={} are on other devices",
+                mirrors.len() - local.len(),
+                mirrors.len(),
+                logical
+            );
+        }
+
+        Ok(local)
+    }
+
+    /// Mark `len` bytes starting `start` bytes into the node at `logical` as metadata in every
+    /// local copy of the node.
+    fn mark_node_as_metadata(
+        &self,
+        logical: u64,
+        mirrors: &[u64],
+        start: usize,
+        len: usize,
+        needs_csum_fixup: bool,
+        compressed: &mut CompressedBtrfsImage,
+    ) -> Result<()> {
+        for physical in mirrors {
+            let physical: usize = (*physical).try_into()?;
+            let begin = physical.saturating_add(start);
+            // Only the first copy was bounds checked when the node was read
+            let bytes = self
+                .image
+                .get(begin..begin.saturating_add(len))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Copy of node at physical addr={} extends past end of image",
+                        physical
+                    )
+                })?;
+            // Only extents that start with the header get node header fixups
+            let logical = if start == 0 { Some(logical) } else { None };
+            compressed.mark_as_metadata(begin.try_into()?, bytes, needs_csum_fixup, logical)?;
+        }
+
+        Ok(())
+    }
+
+    /// Walk a node in the root tree and every tree referenced by a `BtrfsRootItem` under it.
+    ///
+    /// The root tree can have more than one level on larger images (or images with lots of
+    /// subvolumes) so internal nodes have to be recursed through just like in `parse_tree`.
+    fn parse_root_tree_node(
+        &mut self,
+        logical: u64,
+        depth: usize,
+        compressed: &mut CompressedBtrfsImage,
+    ) -> Result<()> {
+        if !self.should_walk(logical, depth) {
+            return Ok(());
+        }
+
+        let mirrors = self.local_mirrors(logical)?;
+        let physical = match mirrors.first() {
+            Some(p) => *p,
+            None => return Ok(()),
+        };
+        let node = read_node(self.image, physical, self.superblock.node_size.get())
+            .with_context(|| "Failed to read root tree node".to_string())?;
+        if !self.is_trusted_node(logical, node)? {
+            println!(
+                "Warning: Skipping stale node logical addr={} in a backup root",
+                logical
+            );
+            return Ok(());
+        }
 
         let header = tree::parse_btrfs_header(node)?;
 
         if header.level == 0 {
             // Store the header b/c it's metadata
-            let metadata_size =
-                size_of::<BtrfsHeader>() + (header.nritems as usize * size_of::<BtrfsItem>());
-            compressed.mark_as_metadata(physical, &node[..metadata_size], true)?;
+            let items = tree::parse_btrfs_leaf(node)?;
+            let metadata_size = size_of::<BtrfsHeader>() + size_of_val(items);
+            self.mark_node_as_metadata(logical, &mirrors, 0, metadata_size, true, compressed)?;
 
             // Now recursively walk the tree
-            let items = tree::parse_btrfs_leaf(node)?;
             for item in items.iter().rev() {
                 if item.key.ty != BTRFS_ROOT_ITEM_KEY {
                     continue;
                 }
 
-                let root_item = unsafe {
-                    &*(node
-                        .as_ptr()
-                        .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                        as *const BtrfsRootItem)
-                };
-
-                self.parse_tree(root_item.bytenr, compressed)?;
+                let root_item = tree::parse_item::<BtrfsRootItem>(node, item)?;
+                self.parse_tree(root_item.bytenr.get(), 0, compressed)?;
             }
         } else {
-            bail!("The root tree root should only contain one level")
+            // Internal root tree nodes only hold pointers to more root tree nodes
+            let ptrs = tree::parse_btrfs_node(node)?;
+            let metadata_size = size_of::<BtrfsHeader>() + size_of_val(ptrs);
+            self.mark_node_as_metadata(logical, &mirrors, 0, metadata_size, true, compressed)?;
+
+            for ptr in ptrs {
+                self.parse_root_tree_node(ptr.blockptr.get(), depth + 1, compressed)?;
+            }
         }
 
         Ok(())
     }
 
-    fn parse_tree(&self, logical: u64, compressed: &mut CompressedBtrfsImage) -> Result<()> {
-        let physical = self
-            .chunk_tree_cache
-            .offset(logical)
-            .ok_or_else(|| anyhow!("Node logical addr={} not mapped", logical))?;
-        let node = read_root_node(self.image, logical, &self.chunk_tree_cache)
+    fn parse_tree(
+        &mut self,
+        logical: u64,
+        depth: usize,
+        compressed: &mut CompressedBtrfsImage,
+    ) -> Result<()> {
+        if !self.should_walk(logical, depth) {
+            return Ok(());
+        }
+
+        let mirrors = self.local_mirrors(logical)?;
+        let physical = match mirrors.first() {
+            Some(p) => *p,
+            None => return Ok(()),
+        };
+        let node = read_node(self.image, physical, self.superblock.node_size.get())
             .with_context(|| "Failed to read node".to_string())?;
+        if !self.is_trusted_node(logical, node)? {
+            println!(
+                "Warning: Skipping stale node logical addr={} in a backup root",
+                logical
+            );
+            return Ok(());
+        }
 
         // Store the header b/c it's metadata
         let header = tree::parse_btrfs_header(node)?;
-        let mut metadata_size = size_of::<BtrfsHeader>();
 
         if header.level == 0 {
             // First annotate header
-            metadata_size += header.nritems as usize * size_of::<BtrfsItem>();
-            compressed.mark_as_metadata(physical, &node[..metadata_size], true)?;
+            let items = tree::parse_btrfs_leaf(node)?;
+            let metadata_size = size_of::<BtrfsHeader>() + size_of_val(items);
+            self.mark_node_as_metadata(logical, &mirrors, 0, metadata_size, true, compressed)?;
+
+            // Stale backup roots could point at data that's since been reused
+            if self.fuzz_data && !self.walking_backup_root {
+                self.collect_data_refs(node, items, &mirrors);
+            }
 
             // Now annotate payloads
             //
             // First find the "left most" payload item. Leaf payloads grow from right
             // to left while `BtrfsItem` structs grow left to right.
-            let mut lowest_offset = None;
-            for item in tree::parse_btrfs_leaf(node)? {
-                if lowest_offset.is_none() || item.offset < lowest_offset.unwrap() {
-                    lowest_offset = Some(item.offset);
-                }
-            }
-
+            let lowest_offset = items.iter().map(|item| item.offset.get()).min();
             if let Some(lowest) = lowest_offset {
-                let physical: usize = physical.try_into()?;
                 let lowest: usize = lowest.try_into()?;
-                let node_size: usize = self.superblock.node_size.try_into()?;
-                let start: usize = physical + size_of::<BtrfsHeader>() + lowest;
-                let end: usize = physical + node_size;
-                compressed.mark_as_metadata(start.try_into()?, &self.image[start..end], false)?;
+                let node_size = node.len();
+                let start: usize = size_of::<BtrfsHeader>() + lowest;
+                if start > node_size {
+                    bail!("Item offset={} is past the end of the leaf", lowest);
+                }
+                self.mark_node_as_metadata(
+                    logical,
+                    &mirrors,
+                    start,
+                    node_size - start,
+                    false,
+                    compressed,
+                )?;
             }
         } else {
             // We're at an internal node: there's no payload
-            metadata_size += header.nritems as usize * size_of::<BtrfsKeyPtr>();
-            compressed.mark_as_metadata(physical, &node[..metadata_size], true)?;
+            let ptrs = tree::parse_btrfs_node(node)?;
+            let metadata_size = size_of::<BtrfsHeader>() + size_of_val(ptrs);
+            self.mark_node_as_metadata(logical, &mirrors, 0, metadata_size, true, compressed)?;
 
             // Recursively visit children
-            let ptrs = tree::parse_btrfs_node(node)?;
             for ptr in ptrs {
-                self.parse_tree(ptr.blockptr, compressed)?;
+                self.parse_tree(ptr.blockptr.get(), depth + 1, compressed)?;
             }
         }
 
@@ -186,12 +534,8 @@ impl<'a> Btrfs<'a> {
 }
 
 fn parse_superblock(img: &[u8]) -> Result<&BtrfsSuperblock> {
-    if BTRFS_SUPERBLOCK_OFFSET + BTRFS_SUPERBLOCK_SIZE > img.len() {
-        bail!("Image to small to contain superblock");
-    }
-
-    let superblock_ptr = img[BTRFS_SUPERBLOCK_OFFSET..].as_ptr() as *const BtrfsSuperblock;
-    let superblock = unsafe { &*superblock_ptr };
+    let superblock: &BtrfsSuperblock = parse::read(img, BTRFS_SUPERBLOCK_OFFSET)
+        .with_context(|| "Image to small to contain superblock".to_string())?;
 
     if superblock.magic != BTRFS_SUPERBLOCK_MAGIC {
         bail!("Superblock magic is wrong");
@@ -200,19 +544,185 @@ fn parse_superblock(img: &[u8]) -> Result<&BtrfsSuperblock> {
     Ok(superblock)
 }
 
+/// Describe the on-disk structures inside `metadata`, one of the extents of `image`. Offsets are
+/// relative to the start of the extent.
+///
+/// `base` is the decompressed base image of `image`. Structures are decoded out of it rather than
+/// out of the possibly fuzzed `image.data`.
+pub(crate) fn extent_structs(
+    image: &CompressedBtrfsImage,
+    base: &[u8],
+    metadata: &MetadataExtent,
+) -> Result<Vec<StructAt>> {
+    if metadata.is_data {
+        return Ok(Vec::new());
+    }
+
+    if metadata.is_superblock() {
+        return Ok(vec![StructAt::new::<BtrfsSuperblock>(
+            StructKind::Superblock,
+            0,
+        )]);
+    }
+
+    let node_size: u32 = image.node_size.try_into()?;
+    if metadata.needs_csum_fixup {
+        let node = read_node(base, metadata.offset, node_size)?;
+        let header = tree::parse_btrfs_header(node)?;
+        return Ok(if header.level == 0 {
+            node_structs::<BtrfsItem>(header, StructKind::Item)
+        } else {
+            node_structs::<BtrfsKeyPtr>(header, StructKind::KeyPtr)
+        });
+    }
+
+    // Leaf payloads are stored apart from the header and items of their node
+    let node_start = image
+        .metadata
+        .iter()
+        .filter(|m| {
+            m.needs_csum_fixup
+                && !m.is_superblock()
+                && m.offset <= metadata.offset
+                && metadata.offset - m.offset < u64::from(node_size)
+        })
+        .map(|m| m.offset)
+        .max()
+        .ok_or_else(|| {
+            anyhow!(
+                "No node header found for leaf payload at physical addr={}",
+                metadata.offset
+            )
+        })?;
+    let node = read_node(base, node_start, node_size)?;
+
+    payload_structs(
+        node,
+        (metadata.offset - node_start) as usize,
+        node_size as usize,
+    )
+}
+
+/// Describe the header and the item (or key ptr) array at the start of a node
+fn node_structs<T: Layout>(header: &BtrfsHeader, kind: StructKind) -> Vec<StructAt> {
+    let mut structs = vec![StructAt::new::<BtrfsHeader>(StructKind::Header, 0)];
+    for i in 0..(header.nritems.get() as usize) {
+        structs.push(StructAt::new::<T>(
+            kind,
+            size_of::<BtrfsHeader>() + i * size_of::<T>(),
+        ));
+    }
+
+    structs
+}
+
+/// Describe the leaf payloads that begin `start` bytes into `node`.
+///
+/// Only item types we have structs for are described. Offsets are relative to `start`.
+fn payload_structs(node: &[u8], start: usize, node_size: usize) -> Result<Vec<StructAt>> {
+    let mut structs = Vec::new();
+    for item in tree::parse_btrfs_leaf(node)? {
+        let (kind, size, layout) = match item.key.ty {
+            BTRFS_INODE_ITEM_KEY => (
+                StructKind::InodeItem,
+                size_of::<BtrfsInodeItem>(),
+                BtrfsInodeItem::layout(),
+            ),
+            BTRFS_INODE_REF_KEY => (
+                StructKind::InodeRef,
+                size_of::<BtrfsInodeRef>(),
+                BtrfsInodeRef::layout(),
+            ),
+            BTRFS_ROOT_ITEM_KEY => (
+                StructKind::RootItem,
+                size_of::<BtrfsRootItem>(),
+                BtrfsRootItem::layout(),
+            ),
+            BTRFS_DIR_ITEM_KEY | BTRFS_DIR_INDEX_KEY | BTRFS_XATTR_ITEM_KEY => (
+                StructKind::DirItem,
+                size_of::<BtrfsDirItem>(),
+                BtrfsDirItem::layout(),
+            ),
+            BTRFS_EXTENT_DATA_KEY => {
+                // Inline extents stop at `disk_bytenr`. Their data is stored from there on.
+                let size = size_of::<BtrfsFileExtentItem>().min(item.size.get() as usize);
+                let layout = BtrfsFileExtentItem::layout()
+                    .into_iter()
+                    .filter(|f| f.offset + f.size <= size)
+                    .collect();
+                (StructKind::FileExtentItem, size, layout)
+            }
+            _ => continue,
+        };
+
+        // Skip payloads that don't fit where the item says they are
+        let offset = size_of::<BtrfsHeader>() + item.offset.get() as usize;
+        if offset < start || offset + size > node_size || size > item.size.get() as usize {
+            continue;
+        }
+
+        structs.push(StructAt {
+            kind,
+            offset: offset - start,
+            layout,
+        });
+    }
+
+    Ok(structs)
+}
+
+/// Parse a `BtrfsChunk` and all of its trailing stripes
+///
+/// `buf` must begin at the `BtrfsChunk`. Returns the parsed chunk and the number of bytes the
+/// chunk item (including stripes) takes up.
+pub(crate) fn parse_chunk(buf: &[u8]) -> Result<(&BtrfsChunk, ChunkTreeValue, usize)> {
+    let chunk: &BtrfsChunk =
+        parse::read(buf, 0).with_context(|| "short chunk item read".to_string())?;
+    if chunk.num_stripes.get() == 0 {
+        bail!("num_stripes cannot be 0");
+    }
+
+    // The first stripe is embedded in `BtrfsChunk`, the rest immediately follow it
+    let stripes_offset = size_of::<BtrfsChunk>() - size_of::<BtrfsStripe>();
+    let stripes: &[BtrfsStripe] =
+        parse::read_array(buf, stripes_offset, chunk.num_stripes.get() as usize)
+            .with_context(|| "short chunk item + stripe read".to_string())?;
+    let chunk_item_size = stripes_offset + size_of_val(stripes);
+
+    let value = ChunkTreeValue {
+        profile: Profile::from_chunk_type(chunk.ty.get()),
+        stripe_len: chunk.stripe_len.get(),
+        sub_stripes: chunk.sub_stripes.get(),
+        stripes: stripes
+            .iter()
+            .map(|stripe| ChunkTreeStripe {
+                devid: stripe.devid.get(),
+                offset: stripe.offset.get(),
+            })
+            .collect(),
+    };
+
+    Ok((chunk, value, chunk_item_size))
+}
+
 fn bootstrap_chunk_tree(superblock: &BtrfsSuperblock) -> Result<ChunkTreeCache> {
-    let array_size = superblock.sys_chunk_array_size as usize;
+    let array_size = superblock.sys_chunk_array_size.get() as usize;
+    if array_size > superblock.sys_chunk_array.len() {
+        bail!(
+            "sys_chunk_array_size={} is larger than sys_chunk_array",
+            array_size
+        );
+    }
     let mut offset: usize = 0;
     let mut chunk_tree_cache = ChunkTreeCache::default();
 
     while offset < array_size {
         let key_size = std::mem::size_of::<BtrfsKey>();
-        if offset + key_size > array_size as usize {
+        if offset + key_size > array_size {
             bail!("Short key read");
         }
 
-        let key_slice = &superblock.sys_chunk_array[offset..];
-        let key = unsafe { &*(key_slice.as_ptr() as *const BtrfsKey) };
+        let key: &BtrfsKey = parse::read(&superblock.sys_chunk_array, offset)?;
         if key.ty != BTRFS_CHUNK_ITEM_KEY {
             bail!(
                 "Unknown item type={} in sys_array at offset={}",
@@ -222,117 +732,493 @@ fn bootstrap_chunk_tree(superblock: &BtrfsSuperblock) -> Result<ChunkTreeCache>
         }
         offset += key_size;
 
-        if offset + std::mem::size_of::<BtrfsChunk>() > array_size {
-            bail!("short chunk item read");
-        }
-
-        let chunk_slice = &superblock.sys_chunk_array[offset..];
-        let chunk = unsafe { &*(chunk_slice.as_ptr() as *const BtrfsChunk) };
-        if chunk.num_stripes == 0 {
-            bail!("num_stripes cannot be 0");
-        }
+        let (chunk, value, chunk_item_size) =
+            parse_chunk(&superblock.sys_chunk_array[offset..array_size])?;
 
-        // To keep things simple, we'll only process 1 stripe, as stripes should have
-        // identical content. The device the stripe is on will be the device passed in
-        // via cmd line args.
-        let num_stripes = chunk.num_stripes; // copy to prevent unaligned access
-        if num_stripes != 1 {
-            println!(
-                "Warning: {} stripes detected but only processing 1",
-                num_stripes
-            );
-        }
-
-        // Add chunk to cache if not already in cache
-        let logical = key.offset;
-        if chunk_tree_cache.offset(logical).is_none() {
-            chunk_tree_cache.insert(
-                ChunkTreeKey {
-                    start: logical,
-                    size: chunk.length,
-                },
-                ChunkTreeValue {
-                    offset: chunk.stripe.offset,
-                },
-            );
+        let key = ChunkTreeKey {
+            start: key.offset.get(),
+            size: chunk.length.get(),
+        };
+        if let Err(e) = chunk_tree_cache.insert(key, value) {
+            println!("Warning: Skipping sys_chunk_array chunk: {:#}", e);
         }
 
-        // Despite only processing one stripe, we need to be careful to skip over the
-        // entire chunk item.
-        let chunk_item_size = std::mem::size_of::<BtrfsChunk>()
-            + (std::mem::size_of::<BtrfsStripe>() * (chunk.num_stripes as usize - 1));
-        if offset + chunk_item_size > array_size {
-            bail!("short chunk item + stripe read");
-        }
         offset += chunk_item_size;
     }
 
     Ok(chunk_tree_cache)
 }
 
-fn read_root_node<'a>(img: &'a [u8], logical: u64, cache: &ChunkTreeCache) -> Result<&'a [u8]> {
-    let size: usize = cache
-        .mapping_kv(logical)
-        .ok_or_else(|| anyhow!("Root node logical addr not mapped"))?
-        .0
-        .size
-        .try_into()?;
-    let physical: usize = cache
-        .offset(logical)
-        .ok_or_else(|| anyhow!("Root node logical addr not mapped"))?
-        .try_into()?;
-    let end = physical + size;
-
-    Ok(&img[physical..end])
+/// Read the node at `physical`
+fn read_node(img: &[u8], physical: u64, node_size: u32) -> Result<&[u8]> {
+    let physical: usize = physical.try_into()?;
+    match physical.checked_add(node_size as usize) {
+        Some(end) if end <= img.len() => Ok(&img[physical..end]),
+        _ => bail!(
+            "Node at physical addr={} extends past end of image",
+            physical
+        ),
+    }
+}
+
+/// Read the first copy of the node at `logical` that lives on device `devid`
+fn read_local_node<'a>(
+    img: &'a [u8],
+    logical: u64,
+    cache: &ChunkTreeCache,
+    devid: u64,
+    node_size: u32,
+) -> Result<&'a [u8]> {
+    let physical = cache
+        .mirrors(logical)
+        .iter()
+        .find(|m| m.devid == devid)
+        .ok_or_else(|| {
+            anyhow!(
+                "Node logical addr={} not mapped on devid={}",
+                logical,
+                devid
+            )
+        })?
+        .offset;
+
+    read_node(img, physical, node_size)
 }
 
+/// Walk the chunk tree node at `logical`, `depth` levels below the chunk root, and add every
+/// chunk under it to `chunk_tree_cache`
 fn read_chunk_tree(
     img: &[u8],
-    root: &[u8],
+    logical: u64,
     chunk_tree_cache: &mut ChunkTreeCache,
     superblock: &BtrfsSuperblock,
+    depth: usize,
+    visited: &mut HashSet<u64>,
 ) -> Result<()> {
-    let header = tree::parse_btrfs_header(root)?;
+    if depth >= BTRFS_MAX_LEVEL as usize {
+        println!(
+            "Warning: Skipping chunk tree node logical addr={} b/c it's deeper than BTRFS_MAX_LEVEL",
+            logical
+        );
+        return Ok(());
+    }
+    if !visited.insert(logical) {
+        println!(
+            "Warning: Skipping chunk tree node logical addr={} b/c it was already read",
+            logical
+        );
+        return Ok(());
+    }
+
+    let node = read_local_node(
+        img,
+        logical,
+        chunk_tree_cache,
+        superblock.dev_item.devid.get(),
+        superblock.node_size.get(),
+    )?;
+    let header = tree::parse_btrfs_header(node)?;
 
     // Level 0 is leaf node, !0 is internal node
     if header.level == 0 {
-        let items = tree::parse_btrfs_leaf(root)?;
+        let items = tree::parse_btrfs_leaf(node)?;
         for item in items {
             if item.key.ty != BTRFS_CHUNK_ITEM_KEY {
                 continue;
             }
 
-            let chunk = unsafe {
-                // `item.offset` is offset from data portion of `BtrfsLeaf` where associated
-                // `BtrfsChunk` starts
-                &*(root
-                    .as_ptr()
-                    .add(std::mem::size_of::<BtrfsHeader>() + item.offset as usize)
-                    as *const BtrfsChunk)
+            let (chunk, value, _) = parse_chunk(tree::item_data(node, item)?)?;
+            let key = ChunkTreeKey {
+                start: item.key.offset.get(),
+                size: chunk.length.get(),
             };
-
-            chunk_tree_cache.insert(
-                ChunkTreeKey {
-                    start: item.key.offset,
-                    size: chunk.length,
-                },
-                ChunkTreeValue {
-                    offset: chunk.stripe.offset,
-                },
-            );
+            if let Err(e) = chunk_tree_cache.insert(key, value) {
+                println!("Warning: Skipping chunk tree chunk: {:#}", e);
+            }
         }
     } else {
-        let ptrs = tree::parse_btrfs_node(root)?;
+        let ptrs = tree::parse_btrfs_node(node)?;
         for ptr in ptrs {
-            let physical: usize = chunk_tree_cache
-                .offset(ptr.blockptr)
-                .ok_or_else(|| anyhow!("Chunk tree node not mapped"))?
-                .try_into()?;
-            let end: usize = physical + superblock.node_size as usize;
-
-            read_chunk_tree(img, &img[physical..end], chunk_tree_cache, superblock)?;
+            read_chunk_tree(
+                img,
+                ptr.blockptr.get(),
+                chunk_tree_cache,
+                superblock,
+                depth + 1,
+                visited,
+            )?;
         }
     }
 
     Ok(())
 }
+
+/// Node size of the images built by `identity_mapped_image()`
+#[cfg(test)]
+const TEST_NODE_SIZE: usize = 4096;
+/// Sector size of the images built by `identity_mapped_image()`
+#[cfg(test)]
+const TEST_SECTOR_SIZE: usize = 4096;
+
+/// Build a `len` byte image with only a superblock whose sys_chunk_array maps the whole image
+/// with a single chunk, so logical addresses are physical addresses. The superblock points at the
+/// chunk tree root `chunk_root` and the root tree root `root`, which are left for the caller to
+/// fill in.
+///
+/// Also returns the key of the chunk.
+#[cfg(test)]
+fn identity_mapped_image(len: usize, chunk_root: usize, root: usize) -> (Vec<u8>, BtrfsKey) {
+    use crate::parse::put;
+
+    let mut img = vec![0; len];
+
+    let mut superblock: BtrfsSuperblock = parse::zeroed();
+    superblock.magic = BTRFS_SUPERBLOCK_MAGIC;
+    superblock.node_size.set(TEST_NODE_SIZE as u32);
+    superblock.sector_size.set(TEST_SECTOR_SIZE as u32);
+    superblock.chunk_root.set(chunk_root as u64);
+    superblock.root.set(root as u64);
+    superblock.dev_item.devid.set(1);
+    let mut chunk: BtrfsChunk = parse::zeroed();
+    chunk.length.set(1 << 20);
+    chunk.stripe_len.set(BTRFS_STRIPE_LEN);
+    chunk.num_stripes.set(1);
+    chunk.stripe.devid.set(1);
+    let chunk_key = BtrfsKey {
+        objectid: 256.into(),
+        ty: BTRFS_CHUNK_ITEM_KEY,
+        offset: 0.into(),
+    };
+    put(&mut superblock.sys_chunk_array, 0, chunk_key);
+    put(
+        &mut superblock.sys_chunk_array,
+        size_of::<BtrfsKey>(),
+        chunk,
+    );
+    superblock
+        .sys_chunk_array_size
+        .set((size_of::<BtrfsKey>() + size_of::<BtrfsChunk>()) as u32);
+    put(&mut img, BTRFS_SUPERBLOCK_OFFSET, superblock);
+
+    (img, chunk_key)
+}
+
+#[test]
+fn test_corrupt_tree_pointers() {
+    use crate::parse::put;
+
+    const NODE_SIZE: usize = TEST_NODE_SIZE;
+    const CHUNK_ROOT: usize = 0x20000;
+    const ROOT: usize = 0x21000;
+    const FS_ROOT: usize = 0x22000;
+    const FS_NODES: usize = 10;
+
+    let (mut img, key) = identity_mapped_image(FS_ROOT + FS_NODES * NODE_SIZE, CHUNK_ROOT, ROOT);
+
+    let key_ptr = |blockptr: usize| BtrfsKeyPtr {
+        key,
+        blockptr: (blockptr as u64).into(),
+        generation: 0.into(),
+    };
+
+    // Chunk tree root that points at itself
+    let mut header: BtrfsHeader = parse::zeroed();
+    header.level = 1;
+    header.nritems.set(1);
+    put(&mut img, CHUNK_ROOT, header);
+    put(
+        &mut img,
+        CHUNK_ROOT + size_of::<BtrfsHeader>(),
+        key_ptr(CHUNK_ROOT),
+    );
+
+    // Root tree with a single fs tree
+    header.level = 0;
+    put(&mut img, ROOT, header);
+    let mut root_item: BtrfsRootItem = parse::zeroed();
+    root_item.bytenr.set(FS_ROOT as u64);
+    let item_offset = NODE_SIZE - size_of::<BtrfsHeader>() - size_of::<BtrfsRootItem>();
+    put(
+        &mut img,
+        ROOT + size_of::<BtrfsHeader>(),
+        BtrfsItem {
+            key: BtrfsKey {
+                objectid: 5.into(),
+                ty: BTRFS_ROOT_ITEM_KEY,
+                offset: 0.into(),
+            },
+            offset: (item_offset as u32).into(),
+            size: (size_of::<BtrfsRootItem>() as u32).into(),
+        },
+    );
+    put(
+        &mut img,
+        ROOT + size_of::<BtrfsHeader>() + item_offset,
+        root_item,
+    );
+
+    // Fs tree that's a chain of internal nodes deeper than BTRFS_MAX_LEVEL. Every node also
+    // points back at the root.
+    header.level = 1;
+    header.nritems.set(2);
+    for i in 0..FS_NODES {
+        let node = FS_ROOT + i * NODE_SIZE;
+        put(&mut img, node, header);
+        put(
+            &mut img,
+            node + size_of::<BtrfsHeader>(),
+            key_ptr(node + NODE_SIZE),
+        );
+        put(
+            &mut img,
+            node + size_of::<BtrfsHeader>() + size_of::<BtrfsKeyPtr>(),
+            key_ptr(FS_ROOT),
+        );
+    }
+
+    let compressed = Btrfs::new(&img)
+        .unwrap()
+        .compress(&CompressOptions::default())
+        .unwrap();
+
+    // Superblock, root tree header, and one header per fs tree level
+    let metadata_offsets: Vec<u64> = compressed.metadata.iter().map(|m| m.offset).collect();
+    assert_eq!(metadata_offsets.len(), 2 + BTRFS_MAX_LEVEL as usize);
+    let deepest = (FS_ROOT + (BTRFS_MAX_LEVEL as usize - 1) * NODE_SIZE) as u64;
+    assert!(metadata_offsets.contains(&deepest));
+    assert!(!metadata_offsets.contains(&(deepest + NODE_SIZE as u64)));
+}
+
+#[test]
+fn test_fuzz_data() {
+    use crate::parse::put;
+
+    const NODE_SIZE: usize = TEST_NODE_SIZE;
+    const SECTOR_SIZE: usize = TEST_SECTOR_SIZE;
+    const CHUNK_ROOT: usize = 0x20000;
+    const ROOT: usize = 0x21000;
+    const FS_ROOT: usize = 0x22000;
+    const CSUM_ROOT: usize = 0x23000;
+    const DATA: usize = 0x30000;
+
+    let (mut img, _) = identity_mapped_image(DATA + 2 * SECTOR_SIZE, CHUNK_ROOT, ROOT);
+    for (i, b) in img[DATA..].iter_mut().enumerate() {
+        *b = i as u8;
+    }
+
+    // Write a leaf at `node` holding a single item with `payload`
+    fn put_leaf(img: &mut [u8], node: usize, key: BtrfsKey, payload: &[u8]) {
+        let mut header: BtrfsHeader = parse::zeroed();
+        header.bytenr.set(node as u64);
+        header.nritems.set(1);
+        put(img, node, header);
+
+        let item_offset = NODE_SIZE - size_of::<BtrfsHeader>() - payload.len();
+        put(
+            img,
+            node + size_of::<BtrfsHeader>(),
+            BtrfsItem {
+                key,
+                offset: (item_offset as u32).into(),
+                size: (payload.len() as u32).into(),
+            },
+        );
+        let begin = node + size_of::<BtrfsHeader>() + item_offset;
+        img[begin..(begin + payload.len())].copy_from_slice(payload);
+    }
+
+    // Empty chunk tree
+    let mut header: BtrfsHeader = parse::zeroed();
+    header.bytenr.set(CHUNK_ROOT as u64);
+    put(&mut img, CHUNK_ROOT, header);
+
+    // Root tree with an fs tree and a csum tree
+    let root_item = |bytenr: usize| {
+        let mut root_item: BtrfsRootItem = parse::zeroed();
+        root_item.bytenr.set(bytenr as u64);
+        root_item
+    };
+    let mut header: BtrfsHeader = parse::zeroed();
+    header.bytenr.set(ROOT as u64);
+    header.nritems.set(2);
+    put(&mut img, ROOT, header);
+    for (i, (objectid, bytenr)) in [(5, FS_ROOT), (BTRFS_CSUM_TREE_OBJECTID, CSUM_ROOT)]
+        .iter()
+        .enumerate()
+    {
+        let item_offset =
+            NODE_SIZE - size_of::<BtrfsHeader>() - (i + 1) * size_of::<BtrfsRootItem>();
+        put(
+            &mut img,
+            ROOT + size_of::<BtrfsHeader>() + i * size_of::<BtrfsItem>(),
+            BtrfsItem {
+                key: BtrfsKey {
+                    objectid: (*objectid).into(),
+                    ty: BTRFS_ROOT_ITEM_KEY,
+                    offset: 0.into(),
+                },
+                offset: (item_offset as u32).into(),
+                size: (size_of::<BtrfsRootItem>() as u32).into(),
+            },
+        );
+        put(
+            &mut img,
+            ROOT + size_of::<BtrfsHeader>() + item_offset,
+            root_item(*bytenr),
+        );
+    }
+
+    // Fs tree with a single file pointing at the data extent
+    let mut extent: BtrfsFileExtentItem = parse::zeroed();
+    extent.ty = BTRFS_FILE_EXTENT_REG;
+    extent.disk_bytenr.set(DATA as u64);
+    extent.disk_num_bytes.set(2 * SECTOR_SIZE as u64);
+    let mut payload = vec![0; size_of::<BtrfsFileExtentItem>()];
+    put(&mut payload, 0, extent);
+    let key = BtrfsKey {
+        objectid: 257.into(),
+        ty: BTRFS_EXTENT_DATA_KEY,
+        offset: 0.into(),
+    };
+    put_leaf(&mut img, FS_ROOT, key, &payload);
+
+    // Csum tree with (wrong) csums for both data sectors
+    let key = BtrfsKey {
+        objectid: BTRFS_EXTENT_CSUM_OBJECTID.into(),
+        ty: BTRFS_EXTENT_CSUM_KEY,
+        offset: (DATA as u64).into(),
+    };
+    put_leaf(&mut img, CSUM_ROOT, key, &[0; 8]);
+    let csums = CSUM_ROOT + NODE_SIZE - 8;
+
+    // Data is only annotated if asked for
+    let compressed = Btrfs::new(&img)
+        .unwrap()
+        .compress(&CompressOptions::default())
+        .unwrap();
+    assert!(compressed.metadata.iter().all(|m| !m.is_data));
+
+    let opts = CompressOptions { data: true };
+    let mut compressed = Btrfs::new(&img).unwrap().compress(&opts).unwrap();
+    let (data, range) = compressed
+        .extents()
+        .find(|(m, _)| m.is_data)
+        .expect("Failed to find data extent");
+    assert_eq!(data.offset, DATA as u64);
+    assert_eq!(data.size, 2 * SECTOR_SIZE as u64);
+    assert!(!data.needs_csum_fixup);
+
+    // Fuzz the first sector. Both csums should be valid afterwards.
+    compressed.data[range.start] ^= 0xFF;
+    let image = crate::decompress(&compressed).unwrap();
+    assert_eq!(image[DATA], 0xFF);
+    for i in 0..2 {
+        let sector = DATA + i * SECTOR_SIZE;
+        let expected = csum::checksum(
+            BTRFS_CSUM_TYPE_CRC32,
+            &image[sector..(sector + SECTOR_SIZE)],
+        )
+        .unwrap();
+        assert_eq!(image[(csums + i * 4)..(csums + i * 4 + 4)], expected[..]);
+    }
+}
+
+#[test]
+fn test_root_tree_internal_node() {
+    use crate::parse::put;
+
+    const NODE_SIZE: usize = TEST_NODE_SIZE;
+    const CHUNK_ROOT: usize = 0x20000;
+    const ROOT: usize = 0x21000;
+    const ROOT_LEAVES: [usize; 2] = [0x22000, 0x23000];
+    const EXTENT_ROOT: usize = 0x24000;
+    const DEV_ROOT: usize = 0x25000;
+    const CSUM_ROOT: usize = 0x26000;
+
+    let (mut img, chunk_key) = identity_mapped_image(CSUM_ROOT + NODE_SIZE, CHUNK_ROOT, ROOT);
+
+    // Empty chunk tree, extent tree, dev tree, and csum tree
+    for node in [CHUNK_ROOT, EXTENT_ROOT, DEV_ROOT, CSUM_ROOT].iter() {
+        let mut header: BtrfsHeader = parse::zeroed();
+        header.bytenr.set(*node as u64);
+        put(&mut img, *node, header);
+    }
+
+    // Root tree root is an internal node pointing at two leaves
+    let mut header: BtrfsHeader = parse::zeroed();
+    header.bytenr.set(ROOT as u64);
+    header.level = 1;
+    header.nritems.set(ROOT_LEAVES.len() as u32);
+    put(&mut img, ROOT, header);
+    for (i, leaf) in ROOT_LEAVES.iter().enumerate() {
+        put(
+            &mut img,
+            ROOT + size_of::<BtrfsHeader>() + i * size_of::<BtrfsKeyPtr>(),
+            BtrfsKeyPtr {
+                key: chunk_key,
+                blockptr: (*leaf as u64).into(),
+                generation: 0.into(),
+            },
+        );
+    }
+
+    // First leaf holds the extent tree (2) and dev tree (4), the second the csum tree
+    let leaves: [&[(u64, usize)]; 2] = [
+        &[(2, EXTENT_ROOT), (4, DEV_ROOT)],
+        &[(BTRFS_CSUM_TREE_OBJECTID, CSUM_ROOT)],
+    ];
+    for (leaf, roots) in ROOT_LEAVES.iter().zip(leaves.iter()) {
+        let mut header: BtrfsHeader = parse::zeroed();
+        header.bytenr.set(*leaf as u64);
+        header.nritems.set(roots.len() as u32);
+        put(&mut img, *leaf, header);
+        for (i, (objectid, bytenr)) in roots.iter().enumerate() {
+            let item_offset =
+                NODE_SIZE - size_of::<BtrfsHeader>() - (i + 1) * size_of::<BtrfsRootItem>();
+            put(
+                &mut img,
+                leaf + size_of::<BtrfsHeader>() + i * size_of::<BtrfsItem>(),
+                BtrfsItem {
+                    key: BtrfsKey {
+                        objectid: (*objectid).into(),
+                        ty: BTRFS_ROOT_ITEM_KEY,
+                        offset: 0.into(),
+                    },
+                    offset: (item_offset as u32).into(),
+                    size: (size_of::<BtrfsRootItem>() as u32).into(),
+                },
+            );
+            let mut root_item: BtrfsRootItem = parse::zeroed();
+            root_item.bytenr.set(*bytenr as u64);
+            put(
+                &mut img,
+                leaf + size_of::<BtrfsHeader>() + item_offset,
+                root_item,
+            );
+        }
+    }
+
+    let compressed = Btrfs::new(&img)
+        .unwrap()
+        .compress(&CompressOptions::default())
+        .unwrap();
+
+    // Every tree under both root tree leaves is found
+    let metadata_offsets: Vec<u64> = compressed.metadata.iter().map(|m| m.offset).collect();
+    for node in [ROOT, ROOT_LEAVES[0], ROOT_LEAVES[1]].iter() {
+        assert!(metadata_offsets.contains(&(*node as u64)));
+    }
+    for (name, node) in [
+        ("extent", EXTENT_ROOT),
+        ("dev", DEV_ROOT),
+        ("csum", CSUM_ROOT),
+    ]
+    .iter()
+    {
+        assert!(
+            metadata_offsets.contains(&(*node as u64)),
+            "{} tree not found",
+            name
+        );
+    }
+}
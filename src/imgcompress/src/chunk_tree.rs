@@ -1,11 +1,83 @@
+use anyhow::{bail, Result};
+
+use crate::structs::*;
+
 #[derive(Default, Clone, Copy)]
 pub struct ChunkTreeKey {
     pub start: u64,
     pub size: u64,
 }
 
-#[derive(Default, Clone, Copy)]
+/// RAID profile of a chunk. Derived from the `BTRFS_BLOCK_GROUP_*` bits in `BtrfsChunk::ty`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Single,
+    Dup,
+    Raid0,
+    Raid1,
+    Raid10,
+    Raid5,
+    Raid6,
+}
+
+impl Profile {
+    pub fn from_chunk_type(ty: u64) -> Self {
+        if ty & BTRFS_BLOCK_GROUP_DUP != 0 {
+            Profile::Dup
+        } else if ty & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+            Profile::Raid0
+        } else if ty
+            & (BTRFS_BLOCK_GROUP_RAID1 | BTRFS_BLOCK_GROUP_RAID1C3 | BTRFS_BLOCK_GROUP_RAID1C4)
+            != 0
+        {
+            Profile::Raid1
+        } else if ty & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+            Profile::Raid10
+        } else if ty & BTRFS_BLOCK_GROUP_RAID5 != 0 {
+            Profile::Raid5
+        } else if ty & BTRFS_BLOCK_GROUP_RAID6 != 0 {
+            Profile::Raid6
+        } else {
+            Profile::Single
+        }
+    }
+}
+
+/// A single stripe of a chunk
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkTreeStripe {
+    /// Device the stripe lives on
+    pub devid: u64,
+    /// Physical offset of the stripe on `devid`
+    pub offset: u64,
+}
+
+#[derive(Clone)]
 pub struct ChunkTreeValue {
+    pub profile: Profile,
+    pub stripe_len: u64,
+    /// Only meaningful for RAID10
+    pub sub_stripes: u16,
+    pub stripes: Vec<ChunkTreeStripe>,
+}
+
+impl ChunkTreeValue {
+    /// Helper to create a mapping for a chunk with a single stripe on device 1
+    #[cfg(test)]
+    pub fn single(offset: u64) -> Self {
+        Self {
+            profile: Profile::Single,
+            stripe_len: BTRFS_STRIPE_LEN,
+            sub_stripes: 0,
+            stripes: vec![ChunkTreeStripe { devid: 1, offset }],
+        }
+    }
+}
+
+/// A physical location of a logical address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhysicalAddr {
+    pub devid: u64,
     pub offset: u64,
 }
 
@@ -15,42 +87,140 @@ pub struct ChunkTreeCache {
 }
 
 impl ChunkTreeCache {
-    pub fn insert(&mut self, key: ChunkTreeKey, value: ChunkTreeValue) {
+    /// Add a mapping for the chunk at `key`.
+    ///
+    /// Inserting the exact same range twice is a no-op b/c chunks in the sys_chunk_array are in
+    /// the chunk tree too. Any other overlap is an error.
+    pub fn insert(&mut self, key: ChunkTreeKey, value: ChunkTreeValue) -> Result<()> {
+        if key.start.checked_add(key.size).is_none() {
+            bail!(
+                "Chunk at logical={} size={} overflows the address space",
+                key.start,
+                key.size
+            );
+        }
+
+        if self
+            .inner
+            .iter()
+            .any(|(k, _)| k.start == key.start && k.size == key.size)
+        {
+            return Ok(());
+        }
+
         if self.contains_overlapping(&key) {
-            panic!("overlapping chunk range detected");
+            bail!(
+                "Chunk at logical={} size={} overlaps another chunk",
+                key.start,
+                key.size
+            );
         }
 
         self.inner.push((key, value));
+
+        Ok(())
     }
 
-    pub fn mapping_kv(&self, logical: u64) -> Option<(ChunkTreeKey, ChunkTreeValue)> {
+    pub fn mapping_kv(&self, logical: u64) -> Option<(&ChunkTreeKey, &ChunkTreeValue)> {
         for (k, v) in &self.inner {
-            if logical >= k.start && logical < (k.start + k.size) {
-                return Some((*k, *v));
+            if logical >= k.start && logical - k.start < k.size {
+                return Some((k, v));
             }
         }
 
         None
     }
 
+    /// Physical offset of the first copy of `logical`
+    #[cfg(test)]
     pub fn offset(&self, logical: u64) -> Option<u64> {
-        if let Some((k, v)) = self.mapping_kv(logical) {
-            Some(v.offset + (logical - k.start))
-        } else {
-            None
-        }
+        self.mirrors(logical).first().map(|addr| addr.offset)
     }
 
-    fn contains_overlapping(&self, key: &ChunkTreeKey) -> bool {
-        for (k, _) in &self.inner {
-            if (key.start > k.start && key.start < (k.start + k.size))
-                || ((key.start + key.size) > k.start && (key.start + key.size) < (k.start + k.size))
-            {
-                return true;
+    /// Every physical copy of `logical`, across all devices.
+    ///
+    /// Parity stripes for RAID5/6 are not included as they do not hold a copy of the data.
+    pub fn mirrors(&self, logical: u64) -> Vec<PhysicalAddr> {
+        let (k, v) = match self.mapping_kv(logical) {
+            Some(kv) => kv,
+            None => return Vec::new(),
+        };
+        let num_stripes = v.stripes.len() as u64;
+        if num_stripes == 0 {
+            return Vec::new();
+        }
+
+        // Stripe offsets come straight off disk so any of the arithmetic below can overflow. Copies
+        // that would land past the end of the address space are dropped.
+        let chunk_offset = logical - k.start;
+        let addr = |idx: u64, stripe_nr: u64| {
+            let stripe = &v.stripes[idx as usize];
+            let offset = stripe_nr
+                .checked_mul(v.stripe_len)?
+                .checked_add(chunk_offset % v.stripe_len)?
+                .checked_add(stripe.offset)?;
+            Some(PhysicalAddr {
+                devid: stripe.devid,
+                offset,
+            })
+        };
+
+        // See `btrfs_map_block()` in fs/btrfs/volumes.c
+        match v.profile {
+            Profile::Single | Profile::Dup | Profile::Raid1 => v
+                .stripes
+                .iter()
+                .filter_map(|stripe| {
+                    Some(PhysicalAddr {
+                        devid: stripe.devid,
+                        offset: stripe.offset.checked_add(chunk_offset)?,
+                    })
+                })
+                .collect(),
+            _ if v.stripe_len == 0 => Vec::new(),
+            Profile::Raid0 => {
+                let stripe_nr = chunk_offset / v.stripe_len;
+                addr(stripe_nr % num_stripes, stripe_nr / num_stripes)
+                    .into_iter()
+                    .collect()
+            }
+            Profile::Raid10 => {
+                let sub_stripes = u64::from(v.sub_stripes).max(1);
+                let factor = (num_stripes / sub_stripes).max(1);
+                let stripe_nr = chunk_offset / v.stripe_len;
+                let first = (stripe_nr % factor) * sub_stripes;
+                (first..(first + sub_stripes).min(num_stripes))
+                    .filter_map(|idx| addr(idx, stripe_nr / factor))
+                    .collect()
+            }
+            Profile::Raid5 | Profile::Raid6 => {
+                let nr_parity = if v.profile == Profile::Raid5 { 1 } else { 2 };
+                if num_stripes <= nr_parity {
+                    return Vec::new();
+                }
+                let nr_data = num_stripes - nr_parity;
+                let stripe_nr = chunk_offset / v.stripe_len;
+                let data_idx = stripe_nr % nr_data;
+                let stripe_nr = stripe_nr / nr_data;
+                // Parity is rotated across stripes
+                addr((stripe_nr + data_idx) % num_stripes, stripe_nr)
+                    .into_iter()
+                    .collect()
             }
         }
+    }
 
-        false
+    /// Every mapping in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = &(ChunkTreeKey, ChunkTreeValue)> {
+        self.inner.iter()
+    }
+
+    /// Whether any byte of `key` is already mapped
+    fn contains_overlapping(&self, key: &ChunkTreeKey) -> bool {
+        let end = key.start.saturating_add(key.size);
+        self.inner
+            .iter()
+            .any(|(k, _)| key.start < k.start.saturating_add(k.size) && k.start < end)
     }
 }
 
@@ -59,12 +229,14 @@ fn test_ctc_basic() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
         ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
+        ChunkTreeValue::single(123),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 5, size: 5 },
-        ChunkTreeValue { offset: 234 },
-    );
+        ChunkTreeValue::single(234),
+    )
+    .unwrap();
 
     assert_eq!(tree.offset(0), Some(123));
     assert_eq!(tree.offset(1), Some(124));
@@ -78,24 +250,29 @@ fn test_ctc_random_order() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
         ChunkTreeKey { start: 10, size: 3 },
-        ChunkTreeValue { offset: 345 },
-    );
+        ChunkTreeValue::single(345),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 25, size: 5 },
-        ChunkTreeValue { offset: 456 },
-    );
+        ChunkTreeValue::single(456),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 15, size: 5 },
-        ChunkTreeValue { offset: 567 },
-    );
+        ChunkTreeValue::single(567),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
+        ChunkTreeValue::single(123),
+    )
+    .unwrap();
     tree.insert(
         ChunkTreeKey { start: 5, size: 5 },
-        ChunkTreeValue { offset: 234 },
-    );
+        ChunkTreeValue::single(234),
+    )
+    .unwrap();
 
     assert_eq!(tree.offset(0), Some(123));
     assert_eq!(tree.offset(1), Some(124));
@@ -109,35 +286,235 @@ fn test_ctc_random_order() {
 }
 
 #[test]
-#[should_panic]
 fn test_ctc_edge_overlap() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
         ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
-    );
-    tree.insert(
-        ChunkTreeKey { start: 4, size: 5 },
-        ChunkTreeValue { offset: 234 },
-    );
-
-    // unreached
-    assert!(false);
+        ChunkTreeValue::single(123),
+    )
+    .unwrap();
+    assert!(tree
+        .insert(
+            ChunkTreeKey { start: 4, size: 5 },
+            ChunkTreeValue::single(234),
+        )
+        .is_err());
+    assert_eq!(tree.offset(4), Some(127));
 }
 
 #[test]
-#[should_panic]
 fn test_ctc_inside_overlap() {
     let mut tree = ChunkTreeCache::default();
     tree.insert(
         ChunkTreeKey { start: 0, size: 5 },
-        ChunkTreeValue { offset: 123 },
+        ChunkTreeValue::single(123),
+    )
+    .unwrap();
+    assert!(tree
+        .insert(
+            ChunkTreeKey { start: 1, size: 2 },
+            ChunkTreeValue::single(234),
+        )
+        .is_err());
+    assert_eq!(tree.offset(1), Some(124));
+}
+
+#[test]
+fn test_ctc_bad_chunks() {
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(
+        ChunkTreeKey { start: 10, size: 5 },
+        ChunkTreeValue::single(123),
+    )
+    .unwrap();
+
+    // Same range again is fine
+    tree.insert(
+        ChunkTreeKey { start: 10, size: 5 },
+        ChunkTreeValue::single(123),
+    )
+    .unwrap();
+    // Range that covers an existing chunk
+    assert!(tree
+        .insert(
+            ChunkTreeKey { start: 0, size: 20 },
+            ChunkTreeValue::single(234),
+        )
+        .is_err());
+    // Range that wraps around
+    assert!(tree
+        .insert(
+            ChunkTreeKey {
+                start: 20,
+                size: u64::MAX,
+            },
+            ChunkTreeValue::single(234),
+        )
+        .is_err());
+    assert_eq!(tree.iter().count(), 1);
+
+    // Stripe offset that overflows when mapped
+    tree.insert(
+        ChunkTreeKey {
+            start: 100,
+            size: 10,
+        },
+        ChunkTreeValue::single(u64::MAX - 5),
+    )
+    .unwrap();
+    assert_eq!(tree.offset(105), Some(u64::MAX));
+    assert!(tree.mirrors(106).is_empty());
+}
+
+#[test]
+fn test_ctc_dup() {
+    let mut tree = ChunkTreeCache::default();
+    tree.insert(
+        ChunkTreeKey {
+            start: 100,
+            size: 50,
+        },
+        ChunkTreeValue {
+            profile: Profile::Dup,
+            stripe_len: BTRFS_STRIPE_LEN,
+            sub_stripes: 0,
+            stripes: vec![
+                ChunkTreeStripe {
+                    devid: 1,
+                    offset: 1000,
+                },
+                ChunkTreeStripe {
+                    devid: 1,
+                    offset: 2000,
+                },
+            ],
+        },
+    )
+    .unwrap();
+
+    assert_eq!(tree.offset(110), Some(1010));
+    assert_eq!(
+        tree.mirrors(110),
+        vec![
+            PhysicalAddr {
+                devid: 1,
+                offset: 1010
+            },
+            PhysicalAddr {
+                devid: 1,
+                offset: 2010
+            }
+        ]
     );
+    assert!(tree.mirrors(150).is_empty());
+}
+
+#[test]
+fn test_ctc_raid10() {
+    let mut tree = ChunkTreeCache::default();
+    let stripes = (1..=4)
+        .map(|devid| ChunkTreeStripe {
+            devid,
+            offset: devid * 1000,
+        })
+        .collect();
     tree.insert(
-        ChunkTreeKey { start: 1, size: 2 },
-        ChunkTreeValue { offset: 234 },
+        ChunkTreeKey {
+            start: 0,
+            size: 400,
+        },
+        ChunkTreeValue {
+            profile: Profile::Raid10,
+            stripe_len: 10,
+            sub_stripes: 2,
+            stripes,
+        },
+    )
+    .unwrap();
+
+    // First stripe is mirrored on devices 1 and 2
+    assert_eq!(
+        tree.mirrors(5),
+        vec![
+            PhysicalAddr {
+                devid: 1,
+                offset: 1005
+            },
+            PhysicalAddr {
+                devid: 2,
+                offset: 2005
+            }
+        ]
+    );
+    // Second stripe is mirrored on devices 3 and 4
+    assert_eq!(
+        tree.mirrors(15)[1],
+        PhysicalAddr {
+            devid: 4,
+            offset: 4005
+        }
+    );
+    // Third stripe wraps back around to devices 1 and 2
+    assert_eq!(
+        tree.mirrors(25)[0],
+        PhysicalAddr {
+            devid: 1,
+            offset: 1015
+        }
     );
+}
+
+#[test]
+fn test_ctc_raid5() {
+    let mut tree = ChunkTreeCache::default();
+    let stripes = (1..=3)
+        .map(|devid| ChunkTreeStripe {
+            devid,
+            offset: devid * 1000,
+        })
+        .collect();
+    tree.insert(
+        ChunkTreeKey {
+            start: 0,
+            size: 400,
+        },
+        ChunkTreeValue {
+            profile: Profile::Raid5,
+            stripe_len: 10,
+            sub_stripes: 0,
+            stripes,
+        },
+    )
+    .unwrap();
 
-    // unreached
-    assert!(false);
+    // First full stripe: data on devices 1 and 2, parity on 3
+    assert_eq!(
+        tree.mirrors(5),
+        vec![PhysicalAddr {
+            devid: 1,
+            offset: 1005
+        }]
+    );
+    assert_eq!(
+        tree.mirrors(15),
+        vec![PhysicalAddr {
+            devid: 2,
+            offset: 2005
+        }]
+    );
+    // Second full stripe: parity rotates to device 1
+    assert_eq!(
+        tree.mirrors(25),
+        vec![PhysicalAddr {
+            devid: 2,
+            offset: 2015
+        }]
+    );
+    assert_eq!(
+        tree.mirrors(35),
+        vec![PhysicalAddr {
+            devid: 3,
+            offset: 3015
+        }]
+    );
 }
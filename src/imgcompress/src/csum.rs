@@ -0,0 +1,82 @@
+use anyhow::{bail, Result};
+use crc32c::crc32c_append;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh64::xxh64;
+
+use crate::structs::*;
+
+/// Calculate the checksum of `buf` using the algorithm identified by `csum_type`.
+///
+/// The returned digest is only as long as the algorithm's output, which may be shorter than
+/// `BTRFS_CSUM_SIZE` (eg 4 bytes for crc32c).
+pub fn checksum(csum_type: u16, buf: &[u8]) -> Result<Vec<u8>> {
+    let csum = match csum_type {
+        BTRFS_CSUM_TYPE_CRC32 => crc32c_append(BTRFS_CSUM_CRC32_SEED, buf)
+            .to_le_bytes()
+            .to_vec(),
+        BTRFS_CSUM_TYPE_XXHASH => xxh64(buf, 0).to_le_bytes().to_vec(),
+        BTRFS_CSUM_TYPE_SHA256 => Sha256::digest(buf).to_vec(),
+        BTRFS_CSUM_TYPE_BLAKE2 => blake2b_simd::Params::new()
+            .hash_length(BTRFS_CSUM_SIZE)
+            .hash(buf)
+            .as_bytes()
+            .to_vec(),
+        _ => bail!("Unsupported csum type={}", csum_type),
+    };
+    assert!(csum.len() <= BTRFS_CSUM_SIZE);
+
+    Ok(csum)
+}
+
+/// Size of the checksums the algorithm identified by `csum_type` produces
+pub fn csum_size(csum_type: u16) -> Result<usize> {
+    let size = match csum_type {
+        BTRFS_CSUM_TYPE_CRC32 => 4,
+        BTRFS_CSUM_TYPE_XXHASH => 8,
+        BTRFS_CSUM_TYPE_SHA256 | BTRFS_CSUM_TYPE_BLAKE2 => BTRFS_CSUM_SIZE,
+        _ => bail!("Unsupported csum type={}", csum_type),
+    };
+
+    Ok(size)
+}
+
+#[test]
+fn test_checksum_known_values() {
+    let buf = b"btrfs";
+
+    let crc = checksum(BTRFS_CSUM_TYPE_CRC32, buf).unwrap();
+    assert_eq!(crc, crc32c_append(0, buf).to_le_bytes());
+
+    let xxhash = checksum(BTRFS_CSUM_TYPE_XXHASH, buf).unwrap();
+    assert_eq!(xxhash, xxh64(buf, 0).to_le_bytes());
+
+    let sha = checksum(BTRFS_CSUM_TYPE_SHA256, b"").unwrap();
+    assert_eq!(sha.len(), BTRFS_CSUM_SIZE);
+    assert_eq!(
+        sha[..4],
+        [0xe3, 0xb0, 0xc4, 0x42],
+        "sha256 of empty string is wrong"
+    );
+
+    let blake = checksum(BTRFS_CSUM_TYPE_BLAKE2, b"").unwrap();
+    assert_eq!(blake.len(), BTRFS_CSUM_SIZE);
+
+    for ty in &[
+        BTRFS_CSUM_TYPE_CRC32,
+        BTRFS_CSUM_TYPE_XXHASH,
+        BTRFS_CSUM_TYPE_SHA256,
+        BTRFS_CSUM_TYPE_BLAKE2,
+    ] {
+        assert_eq!(csum_size(*ty).unwrap(), checksum(*ty, buf).unwrap().len());
+    }
+    assert_eq!(
+        blake[..4],
+        [0x0e, 0x57, 0x51, 0xc0],
+        "blake2b-256 of empty string is wrong"
+    );
+}
+
+#[test]
+fn test_checksum_unknown_type() {
+    assert!(checksum(4, b"btrfs").is_err());
+}
@@ -0,0 +1,472 @@
+use std::convert::TryInto;
+use std::mem::size_of;
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+
+use crate::structs::*;
+use crate::tree;
+use crate::{CompressedBtrfsImage, Field};
+
+/// Arrays larger than this many bytes only have their changed elements described
+const MAX_WHOLE_ARRAY: usize = 32;
+
+/// A run of bytes in `CompressedBtrfsImage::data` that differs between two versions of `data`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Range in `CompressedBtrfsImage::data`
+    pub range: Range<usize>,
+    /// Offset in the decompressed image the change lands at
+    pub physical: u64,
+    /// Field the change is in. `None` if the bytes aren't part of any known field.
+    pub field: Option<Field>,
+}
+
+impl Change {
+    /// Describe the change, eg `header.nritems 3 -> 4294967295`
+    ///
+    /// Only the changed elements of large arrays are described, eg
+    /// `superblock.sys_chunk_array[17] 0 -> 90`.
+    pub fn describe(&self, old: &[u8], new: &[u8]) -> String {
+        let (old, new) = (&old[self.range.clone()], &new[self.range.clone()]);
+        let field = match &self.field {
+            Some(f) => f,
+            // Untyped bytes are always printed as hex
+            None => {
+                return format!(
+                    "bytes@0x{:x} {} -> {}",
+                    self.physical,
+                    format_value(old, 0),
+                    format_value(new, 0)
+                )
+            }
+        };
+
+        let name = format!("{}.{}", field.kind.prefix(), field.name);
+        let width = field.width as usize;
+        if old.len() <= MAX_WHOLE_ARRAY || width == 0 {
+            return format!(
+                "{} {} -> {}",
+                name,
+                format_value(old, width),
+                format_value(new, width)
+            );
+        }
+
+        old.chunks(width)
+            .zip(new.chunks(width))
+            .enumerate()
+            .filter(|(_, (o, n))| o != n)
+            .map(|(i, (o, n))| {
+                format!(
+                    "{}[{}] {} -> {}",
+                    name,
+                    i,
+                    format_value(o, width),
+                    format_value(n, width)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Format integers as little endian decimals and everything else as hex
+pub(crate) fn format_value(bytes: &[u8], width: usize) -> String {
+    match (bytes.len(), width) {
+        (1, 1) => bytes[0].to_string(),
+        (2, 2) => u16::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+        (4, 4) => u32::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+        (8, 8) => u64::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+        _ => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+/// Format a key like btrfs-progs does, eg `(256 INODE_ITEM 0)`
+pub fn format_key(key: &BtrfsKey) -> String {
+    let ty = match key_type_name(key.ty) {
+        Some(name) => name.to_string(),
+        None => format!("UNKNOWN.{}", key.ty),
+    };
+
+    format!("({} {} {})", key.objectid, ty, key.offset)
+}
+
+/// Every field, and every run of bytes outside of a known field, that differs between `old` and
+/// `new`. Both must be laid out like `image.data`.
+pub fn changes(image: &CompressedBtrfsImage, old: &[u8], new: &[u8]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for ((metadata, extent), extent_fields) in image.extents().zip(&image.fields) {
+        if old[extent.clone()] == new[extent.clone()] {
+            continue;
+        }
+
+        let physical = |idx: usize| metadata.offset + (idx - extent.start) as u64;
+        let mut covered = vec![false; extent.len()];
+        for field in extent_fields {
+            let range = field.offset as usize..(field.offset + field.size) as usize;
+            for c in &mut covered[(range.start - extent.start)..(range.end - extent.start)] {
+                *c = true;
+            }
+
+            if old[range.clone()] != new[range.clone()] {
+                changes.push(Change {
+                    physical: physical(range.start),
+                    range,
+                    field: Some(field.clone()),
+                });
+            }
+        }
+
+        // Item payloads we don't have a layout for aren't covered by fields
+        let mut run_start = None;
+        for idx in extent.clone() {
+            let changed = !covered[idx - extent.start] && old[idx] != new[idx];
+            match (changed, run_start) {
+                (true, None) => run_start = Some(idx),
+                (false, Some(start)) => {
+                    changes.push(Change {
+                        physical: physical(start),
+                        range: start..idx,
+                        field: None,
+                    });
+                    run_start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(start) = run_start {
+            changes.push(Change {
+                physical: physical(start),
+                range: start..extent.end,
+                field: None,
+            });
+        }
+    }
+
+    changes.sort_by_key(|c| c.range.start);
+    changes
+}
+
+/// Describe where byte `idx` of `data` lives in the filesystem, eg
+/// `leaf at logical 30474240, item 3 key (256 INODE_ITEM 0)`
+///
+/// Node headers and items are decoded out of `data`.
+fn location(image: &CompressedBtrfsImage, data: &[u8], idx: usize) -> String {
+    let extents: Vec<_> = image.extents().collect();
+    let (metadata, range) = match extents.iter().find(|(_, r)| r.contains(&idx)) {
+        Some(e) => e,
+        None => return format!("data offset {}", idx),
+    };
+    let physical = metadata.offset + (idx - range.start) as u64;
+
+    if metadata.is_superblock() {
+        return format!("superblock at physical {}", metadata.offset);
+    }
+    if metadata.is_data {
+        return format!("data at physical {}", physical);
+    }
+
+    // Leaf payloads are stored apart from the header and items of their node
+    let (header_extent, header_range) = if metadata.needs_csum_fixup {
+        (*metadata, range.clone())
+    } else {
+        match extents
+            .iter()
+            .filter(|(m, _)| {
+                m.needs_csum_fixup
+                    && !m.is_superblock()
+                    && m.offset <= metadata.offset
+                    && metadata.offset - m.offset < image.node_size as u64
+            })
+            .max_by_key(|(m, _)| m.offset)
+        {
+            Some((m, r)) => (*m, r.clone()),
+            None => return format!("physical {}", physical),
+        }
+    };
+
+    let node = &data[header_range];
+    let header = match tree::parse_btrfs_header(node) {
+        Ok(h) => h,
+        Err(_) => return format!("physical {}", physical),
+    };
+    let kind = if header.level == 0 { "leaf" } else { "node" };
+    let node_location = format!("{} at logical {}", kind, header.bytenr);
+
+    // Offset into the node
+    let rel = (physical - header_extent.offset) as usize;
+    if rel < size_of::<BtrfsHeader>() {
+        return node_location;
+    }
+
+    if header.level != 0 {
+        let i = (rel - size_of::<BtrfsHeader>()) / size_of::<BtrfsKeyPtr>();
+        return match tree::parse_slot::<BtrfsKeyPtr>(node, i) {
+            Some(ptr) => format!("{}, ptr {} key {}", node_location, i, format_key(&ptr.key)),
+            None => node_location,
+        };
+    }
+
+    // Only trust as many items as were stored rather than `nritems`, which could be fuzzed
+    let nr_slots = (node.len() - size_of::<BtrfsHeader>()) / size_of::<BtrfsItem>();
+    let in_item_array = rel < size_of::<BtrfsHeader>() + nr_slots * size_of::<BtrfsItem>();
+    for i in 0..nr_slots {
+        let item = match tree::parse_slot::<BtrfsItem>(node, i) {
+            Some(item) => item,
+            None => break,
+        };
+
+        let begin = size_of::<BtrfsHeader>() + item.offset.get() as usize;
+        let end = begin + item.size.get() as usize;
+        let slot_begin = size_of::<BtrfsHeader>() + i * size_of::<BtrfsItem>();
+        let hit = if in_item_array {
+            rel >= slot_begin && rel < slot_begin + size_of::<BtrfsItem>()
+        } else {
+            rel >= begin && rel < end
+        };
+
+        if hit {
+            return format!(
+                "{}, item {} key {}",
+                node_location,
+                i,
+                format_key(&item.key)
+            );
+        }
+    }
+
+    node_location
+}
+
+/// Explain every difference between `old` and `new`, one line per changed field, eg
+/// `leaf at logical 30474240, item 3 key (256 INODE_ITEM 0): inode.size 4096 -> 0`
+///
+/// Both must be laid out like `image.data`. Structures are decoded out of `old`.
+pub fn explain(image: &CompressedBtrfsImage, old: &[u8], new: &[u8]) -> Vec<String> {
+    changes(image, old, new)
+        .iter()
+        .map(|c| {
+            format!(
+                "{}: {}",
+                location(image, old, c.range.start),
+                c.describe(old, new)
+            )
+        })
+        .collect()
+}
+
+/// Explain every metadata difference between two images compressed from the same base image
+pub fn diff(old: &CompressedBtrfsImage, new: &CompressedBtrfsImage) -> Result<Vec<String>> {
+    if old.base != new.base {
+        bail!("Images don't share a base image");
+    }
+
+    let same_layout = old.metadata.len() == new.metadata.len()
+        && old
+            .metadata
+            .iter()
+            .zip(&new.metadata)
+            .all(|(a, b)| a.offset == b.offset && a.size == b.size);
+    if !same_layout || old.data.len() != new.data.len() {
+        bail!("Images have different metadata layouts");
+    }
+
+    Ok(explain(old, &old.data, &new.data))
+}
+
+/// Zeroed image with two 16 byte metadata extents at physical 0x1000 and 0x8000. The first maps
+/// header fields `bytenr` and `nritems`, the second only `bytenr`, so both also have bytes outside
+/// of any known field.
+///
+/// Test fixture shared with the minimizer's tests. Not part of the API.
+#[doc(hidden)]
+pub fn changes_test_image() -> CompressedBtrfsImage {
+    use crate::{MetadataExtent, StructKind};
+
+    let field = |name: &str, offset: u64, size: u64| Field {
+        kind: StructKind::Header,
+        name: name.to_string(),
+        offset,
+        size,
+        width: size as u8,
+    };
+
+    CompressedBtrfsImage {
+        metadata: vec![
+            MetadataExtent {
+                needs_csum_fixup: false,
+                offset: 0x1000,
+                size: 16,
+                ..Default::default()
+            },
+            MetadataExtent {
+                needs_csum_fixup: false,
+                offset: 0x8000,
+                size: 16,
+                ..Default::default()
+            },
+        ],
+        data: vec![0; 32],
+        fields: vec![
+            vec![field("bytenr", 0, 8), field("nritems", 8, 4)],
+            vec![field("bytenr", 16, 8)],
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_changes() {
+    let image = changes_test_image();
+    let old = image.data.clone();
+    assert!(changes(&image, &old, &old).is_empty());
+
+    let mut new = old.clone();
+    new[9] = 1;
+    new[14] = 2;
+    new[15] = 3;
+    new[25] = 4;
+
+    let changes = changes(&image, &old, &new);
+    let ranges: Vec<_> = changes.iter().map(|c| c.range.clone()).collect();
+    assert_eq!(ranges, vec![8..12, 14..16, 25..26]);
+    assert_eq!(changes[0].physical, 0x1008);
+    assert_eq!(changes[0].field.as_ref().unwrap().name, "nritems");
+    assert_eq!(changes[1].field, None);
+    assert_eq!(changes[2].physical, 0x8009);
+
+    assert_eq!(changes[0].describe(&old, &new), "header.nritems 0 -> 256");
+    assert_eq!(changes[1].describe(&old, &new), "bytes@0x100e 0000 -> 0203");
+}
+
+#[cfg(test)]
+fn test_image() -> CompressedBtrfsImage {
+    use crate::parse;
+    use zstd::stream::encode_all;
+
+    const NODE_SIZE: usize = 4096;
+    const LOGICAL: u64 = 30474240;
+    const PHYSICAL: u64 = 0x100000;
+
+    let inode_offset = NODE_SIZE - size_of::<BtrfsHeader>() - size_of::<BtrfsInodeItem>();
+    let mut node = vec![0; NODE_SIZE];
+    let header: &mut BtrfsHeader = parse::read_mut(&mut node, 0).unwrap();
+    header.bytenr.set(LOGICAL);
+    header.nritems.set(2);
+    header.level = 0;
+
+    let item: &mut BtrfsItem = parse::read_mut(&mut node, size_of::<BtrfsHeader>()).unwrap();
+    item.key.objectid.set(256);
+    item.key.ty = BTRFS_INODE_ITEM_KEY;
+    item.offset.set(inode_offset as u32);
+    item.size.set(size_of::<BtrfsInodeItem>() as u32);
+    let offset = size_of::<BtrfsHeader>() + size_of::<BtrfsItem>();
+    let item: &mut BtrfsItem = parse::read_mut(&mut node, offset).unwrap();
+    item.key.objectid.set(256);
+    item.key.ty = 100;
+    item.offset.set(inode_offset as u32 - 8);
+    item.size.set(8);
+
+    let offset = size_of::<BtrfsHeader>() + inode_offset;
+    let inode: &mut BtrfsInodeItem = parse::read_mut(&mut node, offset).unwrap();
+    inode.size.set(4096);
+
+    let mut base = vec![0; PHYSICAL as usize + NODE_SIZE];
+    base[PHYSICAL as usize..].copy_from_slice(&node);
+    let mut image = CompressedBtrfsImage {
+        base: encode_all(base.as_slice(), 0).unwrap(),
+        node_size: NODE_SIZE,
+        ..Default::default()
+    };
+
+    let items_end = size_of::<BtrfsHeader>() + 2 * size_of::<BtrfsItem>();
+    image
+        .mark_as_metadata(PHYSICAL, &node[..items_end], true, Some(PHYSICAL))
+        .unwrap();
+
+    let payload_start = size_of::<BtrfsHeader>() + inode_offset - 8;
+    image
+        .mark_as_metadata(
+            PHYSICAL + payload_start as u64,
+            &node[payload_start..],
+            false,
+            None,
+        )
+        .unwrap();
+    image.fields = image.compute_field_map(&base).unwrap();
+
+    image
+}
+
+#[test]
+fn test_explain() {
+    let image = test_image();
+    let old = image.data.clone();
+    assert!(explain(&image, &old, &old).is_empty());
+
+    let (_, payload) = image.extents().nth(1).unwrap();
+    let mut new = old.clone();
+    // header.nritems
+    new[96] = 0xff;
+    // Second item's key offset
+    new[size_of::<BtrfsHeader>() + size_of::<BtrfsItem>() + 9] = 1;
+    // Untyped payload of the second item
+    new[payload.start + 2] = 0xab;
+    // inode.size
+    let size_offset = payload.start + 8 + 16;
+    new[size_offset..size_offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+    assert_eq!(
+        explain(&image, &old, &new),
+        vec![
+            "leaf at logical 30474240: header.nritems 2 -> 255",
+            "leaf at logical 30474240, item 1 key (256 UNKNOWN.100 0): item.key.offset 0 -> 1",
+            "leaf at logical 30474240, item 1 key (256 UNKNOWN.100 0): bytes@0x100f5a 00 -> ab",
+            "leaf at logical 30474240, item 0 key (256 INODE_ITEM 0): \
+             inode.size 4096 -> 18446744073709551615",
+        ]
+    );
+}
+
+#[test]
+fn test_diff() {
+    let old = test_image();
+    let mut new = test_image();
+    new.data[96] = 3;
+    assert_eq!(
+        diff(&old, &new).unwrap(),
+        vec!["leaf at logical 30474240: header.nritems 2 -> 3"]
+    );
+
+    new.metadata.pop();
+    assert!(diff(&old, &new).is_err());
+
+    let mut new = test_image();
+    new.base = vec![1];
+    assert!(diff(&old, &new).is_err());
+}
+
+#[test]
+fn test_describe_array() {
+    let change = Change {
+        range: 0..64,
+        physical: 0x10000,
+        field: Some(Field {
+            kind: crate::StructKind::Superblock,
+            name: "label".to_string(),
+            offset: 0,
+            size: 64,
+            width: 1,
+        }),
+    };
+    let old = vec![0; 64];
+    let mut new = old.clone();
+    new[3] = 0x41;
+    new[60] = 0x42;
+
+    assert_eq!(
+        change.describe(&old, &new),
+        "superblock.label[3] 0 -> 65, superblock.label[60] 0 -> 66"
+    );
+}
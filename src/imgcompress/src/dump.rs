@@ -0,0 +1,663 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::mem::size_of;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::btrfs::parse_chunk;
+use crate::chunk_tree::{ChunkTreeCache, ChunkTreeKey};
+use crate::diff::{format_key, format_value};
+use crate::fields::Layout;
+use crate::parse;
+use crate::structs::*;
+use crate::tree;
+
+/// Arrays larger than this many bytes are summarized if they're all zeroes
+const MAX_WHOLE_ARRAY: usize = 32;
+
+/// Item payloads we can't decode only have this many bytes printed
+const MAX_RAW_PAYLOAD: usize = 32;
+
+/// Pretty print the superblock, sys_chunk_array, chunk mappings and every tree in `img`, a raw
+/// btrfs image.
+///
+/// Never fails b/c the whole point is to look at fuzzed images. Anything that can't be parsed is
+/// reported inline with an `ERROR:` prefix and skipped.
+pub fn dump(img: &[u8]) -> String {
+    let mut dumper = Dumper {
+        img,
+        out: String::new(),
+        cache: ChunkTreeCache::default(),
+        devid: 0,
+        node_size: 0,
+        visited: HashSet::new(),
+        roots: Vec::new(),
+    };
+    dumper.dump();
+
+    dumper.out
+}
+
+struct Dumper<'a> {
+    img: &'a [u8],
+    out: String,
+    cache: ChunkTreeCache,
+    /// Device id of `img`
+    devid: u64,
+    node_size: usize,
+    /// Logical address of every node printed so far. Fuzzed trees can have cycles.
+    visited: HashSet<u64>,
+    /// Objectid and root node of every `BtrfsRootItem` found so far
+    roots: Vec<(u64, u64)>,
+}
+
+/// Format a field like `format_value` does but with some special cases for readability
+fn format_field(name: &str, bytes: &[u8], width: usize) -> String {
+    if name == "label" || name == "magic" {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        return format!("{:?}", String::from_utf8_lossy(&bytes[..end]));
+    }
+
+    if bytes.len() > MAX_WHOLE_ARRAY && bytes.iter().all(|b| *b == 0) {
+        return format!("<{} zero bytes>", bytes.len());
+    }
+
+    format_value(bytes, width)
+}
+
+/// Format a payload we can't decode as hex
+fn format_raw(data: &[u8]) -> String {
+    if data.len() > MAX_RAW_PAYLOAD {
+        format!(
+            "{}... ({} bytes)",
+            format_value(&data[..MAX_RAW_PAYLOAD], 0),
+            data.len()
+        )
+    } else {
+        format_value(data, 0)
+    }
+}
+
+impl<'a> Dumper<'a> {
+    fn line<S: AsRef<str>>(&mut self, indent: usize, s: S) {
+        for _ in 0..indent {
+            self.out.push('\t');
+        }
+        self.out.push_str(s.as_ref());
+        self.out.push('\n');
+    }
+
+    fn error<S: AsRef<str>>(&mut self, indent: usize, s: S) {
+        self.line(indent, format!("ERROR: {}", s.as_ref()));
+    }
+
+    /// Print every field of the `T` at the start of `buf`. Fields that don't fit are skipped.
+    fn fields<T: Layout>(&mut self, indent: usize, buf: &[u8], skip: &[&str]) {
+        for f in T::layout() {
+            if skip.contains(&f.name.as_str()) || f.offset + f.size > buf.len() {
+                continue;
+            }
+
+            let value = format_field(
+                &f.name,
+                &buf[f.offset..(f.offset + f.size)],
+                f.width as usize,
+            );
+            self.line(indent, format!("{} {}", f.name, value));
+        }
+    }
+
+    fn dump(&mut self) {
+        if BTRFS_SUPERBLOCK_OFFSET + BTRFS_SUPERBLOCK_SIZE > self.img.len() {
+            self.error(0, "image too small to contain superblock");
+            return;
+        }
+        let img = self.img;
+        let superblock_buf =
+            &img[BTRFS_SUPERBLOCK_OFFSET..(BTRFS_SUPERBLOCK_OFFSET + BTRFS_SUPERBLOCK_SIZE)];
+        let superblock: &BtrfsSuperblock = parse::read(superblock_buf, 0).unwrap();
+
+        self.line(
+            0,
+            format!("superblock at physical {}", BTRFS_SUPERBLOCK_OFFSET),
+        );
+        if superblock.magic != BTRFS_SUPERBLOCK_MAGIC {
+            self.error(1, "superblock magic is wrong");
+        }
+        self.fields::<BtrfsSuperblock>(1, superblock_buf, &["sys_chunk_array"]);
+
+        self.devid = superblock.dev_item.devid.get();
+        self.dump_sys_chunk_array(superblock);
+
+        // The chunk tree has to be walked before the mappings can be printed b/c it holds most
+        // of them. Print the mappings first anyways b/c everything else depends on them.
+        let head = std::mem::take(&mut self.out);
+        let node_size = superblock.node_size.get() as usize;
+        if node_size < size_of::<BtrfsHeader>() || node_size > BTRFS_MAX_METADATA_BLOCKSIZE {
+            self.error(
+                0,
+                format!("node_size={} is invalid, not printing trees", node_size),
+            );
+        } else {
+            self.node_size = node_size;
+            self.line(0, format!("chunk tree root {}", superblock.chunk_root));
+            self.dump_node(superblock.chunk_root.get(), 0);
+        }
+        let trees = std::mem::replace(&mut self.out, head);
+        self.dump_mappings();
+        self.out.push_str(&trees);
+        if self.node_size == 0 {
+            return;
+        }
+
+        self.line(0, format!("root tree root {}", superblock.root));
+        self.dump_node(superblock.root.get(), 0);
+
+        // The log tree is maintained separately from the root tree
+        if superblock.log_root.get() != 0 {
+            self.line(0, format!("log root tree root {}", superblock.log_root));
+            self.dump_node(superblock.log_root.get(), 0);
+        }
+
+        // Trees found while printing other trees are appended to `self.roots`
+        let mut i = 0;
+        while i < self.roots.len() {
+            let (objectid, bytenr) = self.roots[i];
+            self.line(0, format!("tree {} root {}", objectid, bytenr));
+            self.dump_node(bytenr, 0);
+            i += 1;
+        }
+    }
+
+    fn dump_sys_chunk_array(&mut self, superblock: &BtrfsSuperblock) {
+        let mut array_size = superblock.sys_chunk_array_size.get() as usize;
+        self.line(0, format!("sys_chunk_array size {}", array_size));
+        if array_size > superblock.sys_chunk_array.len() {
+            self.error(1, "sys_chunk_array_size is larger than sys_chunk_array");
+            array_size = superblock.sys_chunk_array.len();
+        }
+
+        let array = &superblock.sys_chunk_array[..array_size];
+        let mut offset = 0;
+        let mut i = 0;
+        while offset < array_size {
+            let key = match parse::read::<BtrfsKey>(array, offset) {
+                Ok(k) => k,
+                Err(_) => {
+                    self.error(1, format!("short key read at offset={}", offset));
+                    return;
+                }
+            };
+            self.line(1, format!("item {} key {}", i, format_key(key)));
+            if key.ty != BTRFS_CHUNK_ITEM_KEY {
+                self.error(2, "not a chunk item, can't parse the rest of the array");
+                return;
+            }
+            offset += size_of::<BtrfsKey>();
+
+            match self.dump_chunk(2, key.offset.get(), &array[offset..]) {
+                Some(size) => offset += size,
+                None => return,
+            }
+            i += 1;
+        }
+    }
+
+    /// Print the chunk item at the start of `buf` and add it to the chunk mappings.
+    ///
+    /// Returns the size of the chunk item if it could be parsed.
+    fn dump_chunk(&mut self, indent: usize, logical: u64, buf: &[u8]) -> Option<usize> {
+        let (chunk, value, size) = match parse_chunk(buf) {
+            Ok(c) => c,
+            Err(e) => {
+                self.error(indent, format!("{:#}", e));
+                return None;
+            }
+        };
+
+        self.line(
+            indent,
+            format!(
+                "length {} owner {} stripe_len {} type 0x{:x} ({:?}) io_align {} io_width {} sector_size {} num_stripes {} sub_stripes {}",
+                chunk.length,
+                chunk.owner,
+                chunk.stripe_len,
+                chunk.ty,
+                value.profile,
+                chunk.io_align,
+                chunk.io_width,
+                chunk.sector_size,
+                chunk.num_stripes,
+                chunk.sub_stripes,
+            ),
+        );
+        for (i, stripe) in value.stripes.iter().enumerate() {
+            self.line(
+                indent + 1,
+                format!(
+                    "stripe {} devid {} offset {}",
+                    i, stripe.devid, stripe.offset
+                ),
+            );
+        }
+
+        let key = ChunkTreeKey {
+            start: logical,
+            size: chunk.length.get(),
+        };
+        if let Err(e) = self.cache.insert(key, value) {
+            self.error(indent, format!("{:#}", e));
+        }
+
+        Some(size)
+    }
+
+    fn dump_mappings(&mut self) {
+        self.line(0, "chunk mappings");
+
+        let mut mappings: Vec<_> = self.cache.iter().cloned().collect();
+        mappings.sort_by_key(|(k, _)| k.start);
+        for (k, v) in mappings {
+            self.line(
+                1,
+                format!(
+                    "logical {} size {} profile {:?} stripe_len {} sub_stripes {}",
+                    k.start, k.size, v.profile, v.stripe_len, v.sub_stripes
+                ),
+            );
+            for (i, stripe) in v.stripes.iter().enumerate() {
+                self.line(
+                    2,
+                    format!(
+                        "stripe {} devid {} offset {}",
+                        i, stripe.devid, stripe.offset
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Read the first copy of the node at `logical` that lives in `self.img`
+    fn read_node(&self, logical: u64) -> Result<&'a [u8]> {
+        let mirrors = self.cache.mirrors(logical);
+        if mirrors.is_empty() {
+            bail!("logical addr={} not mapped", logical);
+        }
+
+        let physical: usize = mirrors
+            .iter()
+            .find(|m| m.devid == self.devid)
+            .ok_or_else(|| {
+                anyhow!(
+                    "logical addr={} not mapped on devid={}",
+                    logical,
+                    self.devid
+                )
+            })?
+            .offset
+            .try_into()?;
+        match physical.checked_add(self.node_size) {
+            Some(end) if end <= self.img.len() => Ok(&self.img[physical..end]),
+            _ => bail!(
+                "node at physical addr={} extends past end of image",
+                physical
+            ),
+        }
+    }
+
+    /// Print the node at `logical` and everything under it
+    fn dump_node(&mut self, logical: u64, depth: usize) {
+        if depth >= BTRFS_MAX_LEVEL as usize {
+            self.error(1, format!("node {} is too deep in the tree", logical));
+            return;
+        }
+        if !self.visited.insert(logical) {
+            self.error(1, format!("node {} was already printed", logical));
+            return;
+        }
+        let node = match self.read_node(logical) {
+            Ok(n) => n,
+            Err(e) => {
+                self.error(1, format!("{:#}", e));
+                return;
+            }
+        };
+        // `self.node_size` is always large enough for a header
+        let header = tree::parse_btrfs_header(node).unwrap();
+
+        self.line(
+            1,
+            format!(
+                "{} {} level {} items {} generation {} owner {}",
+                if header.level == 0 { "leaf" } else { "node" },
+                logical,
+                header.level,
+                header.nritems,
+                header.generation,
+                header.owner,
+            ),
+        );
+        self.line(
+            1,
+            format!(
+                "bytenr {} flags 0x{:x} fsid {} chunk_tree_uuid {}",
+                header.bytenr,
+                header.flags,
+                format_value(&header.fsid, 0),
+                format_value(&header.chunk_tree_uuid, 0),
+            ),
+        );
+
+        let slot_size = if header.level == 0 {
+            size_of::<BtrfsItem>()
+        } else {
+            size_of::<BtrfsKeyPtr>()
+        };
+        let capacity = (node.len() - size_of::<BtrfsHeader>()) / slot_size;
+        let mut nritems = header.nritems.get() as usize;
+        if nritems > capacity {
+            self.error(
+                2,
+                format!(
+                    "nritems={} doesn't fit in node, only printing {}",
+                    nritems, capacity
+                ),
+            );
+            nritems = capacity;
+        }
+
+        if header.level == 0 {
+            for i in 0..nritems {
+                let item = tree::parse_slot::<BtrfsItem>(node, i).unwrap();
+                self.dump_item(node, i, item);
+            }
+        } else {
+            let mut children = Vec::with_capacity(nritems);
+            for i in 0..nritems {
+                let ptr = tree::parse_slot::<BtrfsKeyPtr>(node, i).unwrap();
+                self.line(
+                    2,
+                    format!(
+                        "key {} block {} gen {}",
+                        format_key(&ptr.key),
+                        ptr.blockptr,
+                        ptr.generation
+                    ),
+                );
+                children.push(ptr.blockptr.get());
+            }
+
+            for child in children {
+                self.dump_node(child, depth + 1);
+            }
+        }
+    }
+
+    fn dump_item(&mut self, node: &[u8], idx: usize, item: &BtrfsItem) {
+        self.line(
+            2,
+            format!(
+                "item {} key {} itemoff {} itemsize {}",
+                idx,
+                format_key(&item.key),
+                item.offset,
+                item.size
+            ),
+        );
+
+        let data = match tree::item_data(node, item) {
+            Ok(d) => d,
+            Err(e) => {
+                self.error(3, format!("{:#}", e));
+                return;
+            }
+        };
+
+        match item.key.ty {
+            BTRFS_INODE_ITEM_KEY => self.fields::<BtrfsInodeItem>(3, data, &[]),
+            BTRFS_INODE_REF_KEY => self.dump_inode_refs(data),
+            BTRFS_DIR_ITEM_KEY | BTRFS_DIR_INDEX_KEY | BTRFS_XATTR_ITEM_KEY => {
+                self.dump_dir_items(data)
+            }
+            BTRFS_ROOT_ITEM_KEY => {
+                self.fields::<BtrfsRootItem>(3, data, &[]);
+                match parse::read::<BtrfsRootItem>(data, 0) {
+                    Ok(root) => self
+                        .roots
+                        .push((item.key.objectid.get(), root.bytenr.get())),
+                    Err(_) => self.error(3, "root item is too small"),
+                }
+            }
+            BTRFS_CHUNK_ITEM_KEY => {
+                self.dump_chunk(3, item.key.offset.get(), data);
+            }
+            _ => {
+                if !data.is_empty() {
+                    self.line(3, format!("data {}", format_raw(data)));
+                }
+            }
+        }
+    }
+
+    /// A single item can hold more than one name b/c of hard links in the same directory
+    fn dump_inode_refs(&mut self, data: &[u8]) {
+        let mut offset = 0;
+        while offset < data.len() {
+            let inode_ref = match parse::read::<BtrfsInodeRef>(data, offset) {
+                Ok(r) => r,
+                Err(_) => {
+                    self.error(3, "short inode ref read");
+                    return;
+                }
+            };
+            let begin = offset + size_of::<BtrfsInodeRef>();
+            let name = match parse::read_array::<u8>(data, begin, inode_ref.name_len.get().into()) {
+                Ok(n) => n,
+                Err(_) => {
+                    self.error(
+                        3,
+                        format!("name_len={} is out of bounds", inode_ref.name_len),
+                    );
+                    return;
+                }
+            };
+
+            self.line(
+                3,
+                format!(
+                    "index {} name_len {} name {:?}",
+                    inode_ref.index,
+                    inode_ref.name_len,
+                    String::from_utf8_lossy(name)
+                ),
+            );
+            offset = begin + name.len();
+        }
+    }
+
+    /// A single item can hold more than one entry b/c of hash collisions
+    fn dump_dir_items(&mut self, data: &[u8]) {
+        let mut offset = 0;
+        while offset < data.len() {
+            let dir_item = match parse::read::<BtrfsDirItem>(data, offset) {
+                Ok(d) => d,
+                Err(_) => {
+                    self.error(3, "short dir item read");
+                    return;
+                }
+            };
+            let name_begin = offset + size_of::<BtrfsDirItem>();
+            let name_end = name_begin + dir_item.name_len.get() as usize;
+            let data_end = name_end + dir_item.data_len.get() as usize;
+            if data_end > data.len() {
+                self.error(
+                    3,
+                    format!(
+                        "name_len={} data_len={} is out of bounds",
+                        dir_item.name_len, dir_item.data_len
+                    ),
+                );
+                return;
+            }
+
+            self.line(
+                3,
+                format!(
+                    "location {} transid {} data_len {} name_len {} type {}",
+                    format_key(&dir_item.location),
+                    dir_item.transid,
+                    dir_item.data_len,
+                    dir_item.name_len,
+                    dir_item.ty
+                ),
+            );
+            self.line(
+                4,
+                format!(
+                    "name {:?}",
+                    String::from_utf8_lossy(&data[name_begin..name_end])
+                ),
+            );
+            if data_end > name_end {
+                self.line(4, format!("data {}", format_raw(&data[name_end..data_end])));
+            }
+            offset = data_end;
+        }
+    }
+}
+
+#[test]
+fn test_dump() {
+    use crate::parse::put;
+
+    const NODE_SIZE: usize = 4096;
+    const CHUNK_ROOT: usize = 0x20000;
+    const ROOT: usize = 0x21000;
+    const FS_ROOT: usize = 0x22000;
+
+    let mut img = vec![0; 0x23000];
+
+    // Identity map the whole image with a single chunk
+    let sb = BTRFS_SUPERBLOCK_OFFSET;
+    let mut superblock: BtrfsSuperblock = parse::zeroed();
+    superblock.magic = BTRFS_SUPERBLOCK_MAGIC;
+    superblock.node_size.set(NODE_SIZE as u32);
+    superblock.chunk_root.set(CHUNK_ROOT as u64);
+    superblock.root.set(ROOT as u64);
+    superblock.dev_item.devid.set(1);
+    superblock.label[..4].copy_from_slice(b"test");
+    superblock.root_backups[1].tree_root.set(1234);
+    let mut chunk: BtrfsChunk = parse::zeroed();
+    chunk.length.set(1 << 20);
+    chunk.stripe_len.set(BTRFS_STRIPE_LEN);
+    chunk.num_stripes.set(1);
+    chunk.stripe.devid.set(1);
+    let key = BtrfsKey {
+        objectid: 256.into(),
+        ty: BTRFS_CHUNK_ITEM_KEY,
+        offset: 0.into(),
+    };
+    put(&mut superblock.sys_chunk_array, 0, key);
+    put(
+        &mut superblock.sys_chunk_array,
+        size_of::<BtrfsKey>(),
+        chunk,
+    );
+    superblock
+        .sys_chunk_array_size
+        .set((size_of::<BtrfsKey>() + size_of::<BtrfsChunk>()) as u32);
+    put(&mut img, sb, superblock);
+
+    // Empty chunk tree
+    let mut header: BtrfsHeader = parse::zeroed();
+    header.bytenr.set(CHUNK_ROOT as u64);
+    put(&mut img, CHUNK_ROOT, header);
+
+    // Root tree with a single fs tree
+    header.bytenr.set(ROOT as u64);
+    header.nritems.set(1);
+    put(&mut img, ROOT, header);
+    let mut root_item: BtrfsRootItem = parse::zeroed();
+    root_item.bytenr.set(FS_ROOT as u64);
+    let item_offset = NODE_SIZE - size_of::<BtrfsHeader>() - size_of::<BtrfsRootItem>();
+    put(
+        &mut img,
+        ROOT + size_of::<BtrfsHeader>(),
+        BtrfsItem {
+            key: BtrfsKey {
+                objectid: 5.into(),
+                ty: BTRFS_ROOT_ITEM_KEY,
+                offset: 0.into(),
+            },
+            offset: (item_offset as u32).into(),
+            size: (size_of::<BtrfsRootItem>() as u32).into(),
+        },
+    );
+    put(
+        &mut img,
+        ROOT + size_of::<BtrfsHeader>() + item_offset,
+        root_item,
+    );
+
+    // Fuzzed fs tree that points at itself and at something unmapped
+    header.bytenr.set(FS_ROOT as u64);
+    header.level = 1;
+    header.nritems.set(2);
+    put(&mut img, FS_ROOT, header);
+    for (i, blockptr) in [FS_ROOT as u64, 1 << 40].iter().enumerate() {
+        put(
+            &mut img,
+            FS_ROOT + size_of::<BtrfsHeader>() + i * size_of::<BtrfsKeyPtr>(),
+            BtrfsKeyPtr {
+                key: BtrfsKey {
+                    objectid: 256.into(),
+                    ty: BTRFS_INODE_ITEM_KEY,
+                    offset: 0.into(),
+                },
+                blockptr: (*blockptr).into(),
+                generation: 0.into(),
+            },
+        );
+    }
+
+    let out = dump(&img);
+    let expected = [
+        "superblock at physical 65536",
+        "\tlabel \"test\"",
+        "\troot_backups[1].tree_root 1234",
+        "sys_chunk_array size 97",
+        "\titem 0 key (256 CHUNK_ITEM 0)",
+        "chunk mappings",
+        "\tlogical 0 size 1048576 profile Single stripe_len 65536 sub_stripes 0",
+        "chunk tree root 131072",
+        "\tleaf 131072 level 0 items 0 generation 0 owner 0",
+        "root tree root 135168",
+        "\t\titem 0 key (5 ROOT_ITEM 0) itemoff 3556 itemsize 439",
+        "\t\t\tbytenr 139264",
+        "tree 5 root 139264",
+        "\tnode 139264 level 1 items 2 generation 0 owner 0",
+        "\t\tkey (256 INODE_ITEM 0) block 1099511627776 gen 0",
+        "\tERROR: node 139264 was already printed",
+        "\tERROR: logical addr=1099511627776 not mapped",
+    ];
+    let mut rest = out.as_str();
+    for line in &expected {
+        let pos = rest
+            .find(line)
+            .unwrap_or_else(|| panic!("{:?} not found in order in:\n{}", line, out));
+        rest = &rest[pos..];
+    }
+
+    // Every item has to fit in the node
+    header.nritems.set(u32::MAX);
+    put(&mut img, FS_ROOT, header);
+    assert!(dump(&img).contains("ERROR: nritems=4294967295 doesn't fit in node, only printing"));
+
+    // Garbage doesn't stop anything
+    assert!(dump(&[0; 10]).contains("ERROR: image too small"));
+    let mut garbage = vec![0xff; 0x23000];
+    garbage[sb..(sb + 8)].copy_from_slice(&[0; 8]);
+    let out = dump(&garbage);
+    assert!(out.contains("ERROR: superblock magic is wrong"));
+    assert!(out.contains("ERROR: sys_chunk_array_size is larger than sys_chunk_array"));
+}
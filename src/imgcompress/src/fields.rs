@@ -0,0 +1,436 @@
+use std::convert::TryInto;
+use std::mem::{size_of, MaybeUninit};
+use std::ops::Range;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::parse::{Le16, Le32, Le64};
+use crate::structs::*;
+
+/// Kind of on-disk structure a `Field` belongs to
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructKind {
+    Superblock,
+    Header,
+    Item,
+    KeyPtr,
+    InodeItem,
+    InodeRef,
+    RootItem,
+    DirItem,
+    FileExtentItem,
+}
+
+impl StructKind {
+    /// Short name used when printing fields, eg `inode` in `inode.size`
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            StructKind::Superblock => "superblock",
+            StructKind::Header => "header",
+            StructKind::Item => "item",
+            StructKind::KeyPtr => "key_ptr",
+            StructKind::InodeItem => "inode",
+            StructKind::InodeRef => "inode_ref",
+            StructKind::RootItem => "root",
+            StructKind::DirItem => "dir_item",
+            StructKind::FileExtentItem => "file_extent",
+        }
+    }
+}
+
+/// A single field of an on-disk structure stored in `CompressedBtrfsImage::data`
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub kind: StructKind,
+    /// Name of the field. Fields of nested structs are dot separated, eg `key.objectid`.
+    pub name: String,
+    /// Offset in `CompressedBtrfsImage::data` the field begins at
+    pub offset: u64,
+    /// Size of the entire field
+    pub size: u64,
+    /// Width of a single value in the field. This is the same as `size` for integers. Arrays
+    /// have the width of their elements.
+    pub width: u8,
+}
+
+/// Every known on-disk structure field of a `CompressedBtrfsImage`. Holds one list of fields per
+/// extent in `CompressedBtrfsImage::metadata`, in the same order.
+pub type FieldMap = Vec<Vec<Field>>;
+
+/// Where a field lives inside of its struct
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldLayout {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+    pub width: u8,
+}
+
+/// Implemented by everything that can be described as a list of fields
+pub trait Layout {
+    fn layout() -> Vec<FieldLayout>;
+}
+
+/// A struct of kind `kind` at `offset` bytes into a metadata extent
+pub(crate) struct StructAt {
+    pub kind: StructKind,
+    pub offset: usize,
+    pub layout: Vec<FieldLayout>,
+}
+
+impl StructAt {
+    pub fn new<T: Layout>(kind: StructKind, offset: usize) -> Self {
+        Self {
+            kind,
+            offset,
+            layout: T::layout(),
+        }
+    }
+}
+
+/// Every field of `structs`, which are located inside the extent at `extent` in
+/// `CompressedBtrfsImage::data`. Fields that don't entirely fit inside the extent are dropped.
+pub(crate) fn fields_in(structs: &[StructAt], extent: Range<usize>) -> Result<Vec<Field>> {
+    let mut fields = Vec::new();
+    for s in structs {
+        for f in &s.layout {
+            let begin = s.offset + f.offset;
+            if begin + f.size > extent.len() {
+                continue;
+            }
+
+            fields.push(Field {
+                kind: s.kind,
+                name: f.name.clone(),
+                offset: (extent.start + begin).try_into()?,
+                size: f.size.try_into()?,
+                width: f.width,
+            });
+        }
+    }
+
+    Ok(fields)
+}
+
+macro_rules! impl_scalar_layout {
+    ($($ty:ty),*) => {
+        $(
+            impl Layout for $ty {
+                fn layout() -> Vec<FieldLayout> {
+                    vec![FieldLayout {
+                        name: String::new(),
+                        offset: 0,
+                        size: size_of::<$ty>(),
+                        width: size_of::<$ty>() as u8,
+                    }]
+                }
+            }
+        )*
+    };
+}
+
+impl_scalar_layout!(u8, Le16, Le32, Le64);
+
+impl<T: Layout, const N: usize> Layout for [T; N] {
+    fn layout() -> Vec<FieldLayout> {
+        let inner = T::layout();
+
+        // Arrays of integers are a single field
+        if inner.len() == 1 && inner[0].name.is_empty() {
+            return vec![FieldLayout {
+                name: String::new(),
+                offset: 0,
+                size: size_of::<Self>(),
+                width: inner[0].width,
+            }];
+        }
+
+        // Arrays of structs get one set of fields per element
+        let mut fields = Vec::new();
+        for i in 0..N {
+            for f in &inner {
+                fields.push(FieldLayout {
+                    name: format!("[{}].{}", i, f.name),
+                    offset: i * size_of::<T>() + f.offset,
+                    size: f.size,
+                    width: f.width,
+                });
+            }
+        }
+
+        fields
+    }
+}
+
+/// Helper to get at the type of a struct field through a raw pointer to it
+fn layout_of<T: Layout>(_: *const T) -> Vec<FieldLayout> {
+    T::layout()
+}
+
+macro_rules! impl_struct_layout {
+    ($ty:ty, [$($field:ident),* $(,)?]) => {
+        impl Layout for $ty {
+            fn layout() -> Vec<FieldLayout> {
+                let base = MaybeUninit::<$ty>::uninit();
+                let base_ptr = base.as_ptr();
+                let mut fields = Vec::new();
+
+                $(
+                    // Safe b/c `addr_of!` never reads or creates a reference to the field
+                    let field_ptr = unsafe { std::ptr::addr_of!((*base_ptr).$field) };
+                    let offset = field_ptr as usize - base_ptr as usize;
+                    for sub in layout_of(field_ptr) {
+                        let name = if sub.name.is_empty() {
+                            stringify!($field).to_string()
+                        } else if sub.name.starts_with('[') {
+                            format!("{}{}", stringify!($field), sub.name)
+                        } else {
+                            format!("{}.{}", stringify!($field), sub.name)
+                        };
+
+                        fields.push(FieldLayout {
+                            name,
+                            offset: offset + sub.offset,
+                            size: sub.size,
+                            width: sub.width,
+                        });
+                    }
+                )*
+
+                fields
+            }
+        }
+    };
+}
+
+impl_struct_layout!(
+    BtrfsDevItem,
+    [
+        devid,
+        total_bytes,
+        bytes_used,
+        io_align,
+        io_width,
+        sector_size,
+        ty,
+        generation,
+        start_offset,
+        dev_group,
+        seek_speed,
+        bandwidth,
+        uuid,
+        fsid,
+    ]
+);
+
+impl_struct_layout!(
+    BtrfsRootBackup,
+    [
+        tree_root,
+        tree_root_gen,
+        chunk_root,
+        chunk_root_gen,
+        extent_root,
+        extent_root_gen,
+        fs_root,
+        fs_root_gen,
+        dev_root,
+        dev_root_gen,
+        csum_root,
+        csum_root_gen,
+        total_bytes,
+        bytes_used,
+        num_devices,
+        unused_64,
+        tree_root_level,
+        chunk_root_level,
+        extent_root_level,
+        fs_root_level,
+        dev_root_level,
+        csum_root_level,
+        unused_8,
+    ]
+);
+
+impl_struct_layout!(
+    BtrfsSuperblock,
+    [
+        csum,
+        fsid,
+        bytenr,
+        flags,
+        magic,
+        generation,
+        root,
+        chunk_root,
+        log_root,
+        log_root_transid,
+        total_bytes,
+        bytes_used,
+        root_dir_objectid,
+        num_devices,
+        sector_size,
+        node_size,
+        leafsize,
+        stripesize,
+        sys_chunk_array_size,
+        chunk_root_generation,
+        compat_flags,
+        compat_ro_flags,
+        incompat_flags,
+        csum_type,
+        root_level,
+        chunk_root_level,
+        log_root_level,
+        dev_item,
+        label,
+        cache_generation,
+        uuid_tree_generation,
+        metadata_uuid,
+        _reserved,
+        sys_chunk_array,
+        root_backups,
+    ]
+);
+
+impl_struct_layout!(BtrfsTimespec, [sec, nsec]);
+
+impl_struct_layout!(
+    BtrfsInodeItem,
+    [
+        generation,
+        transid,
+        size,
+        nbytes,
+        block_group,
+        nlink,
+        uid,
+        gid,
+        mode,
+        rdev,
+        flags,
+        sequence,
+        reserved,
+        atime,
+        ctime,
+        mtime,
+        otime,
+    ]
+);
+
+impl_struct_layout!(
+    BtrfsRootItem,
+    [
+        inode,
+        generation,
+        root_dirid,
+        bytenr,
+        byte_limit,
+        bytes_used,
+        last_snapshot,
+        flags,
+        refs,
+        drop_progress,
+        drop_level,
+        level,
+        generation_v2,
+        uuid,
+        parent_uuid,
+        received_uuid,
+        ctransid,
+        otransid,
+        stransid,
+        rtransid,
+        ctime,
+        otime,
+        stime,
+        rtime,
+        reserved,
+    ]
+);
+
+impl_struct_layout!(BtrfsDirItem, [location, transid, data_len, name_len, ty]);
+impl_struct_layout!(BtrfsInodeRef, [index, name_len]);
+impl_struct_layout!(
+    BtrfsFileExtentItem,
+    [
+        generation,
+        ram_bytes,
+        compression,
+        encryption,
+        other_encoding,
+        ty,
+        disk_bytenr,
+        disk_num_bytes,
+        offset,
+        num_bytes,
+    ]
+);
+impl_struct_layout!(BtrfsKey, [objectid, ty, offset]);
+
+impl_struct_layout!(
+    BtrfsHeader,
+    [
+        csum,
+        fsid,
+        bytenr,
+        flags,
+        chunk_tree_uuid,
+        generation,
+        owner,
+        nritems,
+        level,
+    ]
+);
+
+impl_struct_layout!(BtrfsItem, [key, offset, size]);
+impl_struct_layout!(BtrfsKeyPtr, [key, blockptr, generation]);
+
+#[test]
+fn test_header_layout() {
+    let layout = BtrfsHeader::layout();
+    let find = |name: &str| layout.iter().find(|f| f.name == name).unwrap().clone();
+
+    assert_eq!(layout.len(), 9);
+    assert_eq!(
+        find("csum"),
+        FieldLayout {
+            name: "csum".to_string(),
+            offset: 0,
+            size: BTRFS_CSUM_SIZE,
+            width: 1,
+        }
+    );
+    assert_eq!(find("bytenr").offset, 48);
+    assert_eq!(find("bytenr").width, 8);
+    assert_eq!(find("nritems").offset, 96);
+    assert_eq!(find("level").offset, 100);
+    assert_eq!(find("level").size, 1);
+}
+
+#[test]
+fn test_nested_layout() {
+    let item = BtrfsItem::layout();
+    let names: Vec<&str> = item.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec!["key.objectid", "key.ty", "key.offset", "offset", "size"]
+    );
+    assert_eq!(item[2].offset, 9);
+    assert_eq!(item[4].offset, 21);
+
+    // Every byte of the superblock should be covered exactly once
+    let superblock = BtrfsSuperblock::layout();
+    let total: usize = superblock.iter().map(|f| f.size).sum();
+    assert_eq!(total, size_of::<BtrfsSuperblock>());
+    let backup = superblock
+        .iter()
+        .find(|f| f.name == "root_backups[3].csum_root_level")
+        .unwrap();
+    assert_eq!(
+        backup.offset,
+        size_of::<BtrfsSuperblock>() - 11,
+        "last root backup field is misplaced"
+    );
+}
@@ -1,11 +1,13 @@
 use std::convert::TryInto;
+use std::fmt;
 #[cfg(test)]
 use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
 #[cfg(test)]
 use std::process::Command;
+use std::str::FromStr;
 
-use anyhow::{bail, Result};
-use crc32c::crc32c_append;
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
 #[cfg(test)]
 use tempfile::NamedTempFile;
@@ -13,10 +15,16 @@ use zstd::stream::decode_all;
 
 mod btrfs;
 mod chunk_tree;
-mod structs;
-mod tree;
+mod csum;
+pub mod diff;
+pub mod dump;
+mod fields;
+pub mod parse;
+pub mod structs;
+pub mod tree;
 
 use btrfs::Btrfs;
+pub use fields::{Field, FieldMap, StructKind};
 use structs::*;
 
 /// Metadata for a metadata extent.
@@ -28,6 +36,29 @@ pub struct MetadataExtent {
     pub offset: u64,
     /// Length of metadata extent
     pub size: u64,
+    /// Logical address of the node if this extent begins with a `BtrfsHeader`
+    #[serde(default)]
+    pub logical: Option<u64>,
+    /// If true, this extent is file data rather than metadata
+    #[serde(default)]
+    pub is_data: bool,
+}
+
+impl MetadataExtent {
+    /// Whether this extent is the primary superblock or one of its mirrors
+    pub fn is_superblock(&self) -> bool {
+        self.size as usize == BTRFS_SUPERBLOCK_SIZE
+            && BTRFS_SUPERBLOCK_OFFSETS.contains(&(self.offset as usize))
+    }
+}
+
+/// A data checksum that has to be recomputed after fuzzing
+#[derive(Deserialize, Serialize)]
+struct DataCsum {
+    /// Physical address of the sector the csum covers
+    data: u64,
+    /// Physical address of every copy of the csum
+    csums: Vec<u64>,
 }
 
 #[derive(Deserialize, Serialize, Default)]
@@ -43,70 +74,258 @@ pub struct CompressedBtrfsImage {
     pub data: Vec<u8>,
     /// Size of each node in the btree. Used to calculate checksum in node headers.
     node_size: usize,
+    /// Checksum algorithm used by the image. Saved separately b/c `BtrfsSuperblock::csum_type`
+    /// could get fuzzed to something else.
+    #[serde(default)]
+    csum_type: u16,
+    /// Size of a data sector. Saved separately for the same reason as `csum_type`.
+    #[serde(default)]
+    sector_size: usize,
+    /// Data csums covering fuzzable data extents
+    #[serde(default)]
+    data_csums: Vec<DataCsum>,
+    /// Every known on-disk structure field in `data`. Structures are decoded out of the
+    /// unfuzzed image, so fuzzing `data` doesn't change the field map.
+    #[serde(default)]
+    pub fields: FieldMap,
 }
 
 impl CompressedBtrfsImage {
     /// Mark a range of data as metadata
+    ///
+    /// `logical` is the logical address of the node `metadata` is the start of, if any.
     pub(crate) fn mark_as_metadata(
         &mut self,
         physical: u64,
         metadata: &[u8],
         needs_csum_fixup: bool,
+        logical: Option<u64>,
     ) -> Result<()> {
         self.metadata.push(MetadataExtent {
             needs_csum_fixup,
             offset: physical,
             size: metadata.len().try_into()?,
+            logical,
+            is_data: false,
         });
         self.data.extend_from_slice(metadata);
 
         Ok(())
     }
+
+    /// Mark a range of file data as fuzzable
+    pub(crate) fn mark_as_data(&mut self, physical: u64, data: &[u8]) -> Result<()> {
+        self.metadata.push(MetadataExtent {
+            offset: physical,
+            size: data.len().try_into()?,
+            is_data: true,
+            ..Default::default()
+        });
+        self.data.extend_from_slice(data);
+
+        Ok(())
+    }
+
+    /// Iterate over every metadata extent along with the range in `data` it is stored in
+    pub fn extents(&self) -> impl Iterator<Item = (&MetadataExtent, Range<usize>)> {
+        let mut data_idx = 0;
+        self.metadata.iter().map(move |metadata| {
+            let begin = data_idx;
+            data_idx += metadata.size as usize;
+            (metadata, begin..data_idx)
+        })
+    }
+
+    /// Reconstruct `data` as it was before any fuzzing, ie straight out of `base`
+    pub fn baseline(&self) -> Result<Vec<u8>> {
+        let base: Vec<u8> = decode_all(self.base.as_slice())?;
+
+        let mut baseline = Vec::with_capacity(self.data.len());
+        for (metadata, _) in self.extents() {
+            let begin: usize = metadata.offset.try_into()?;
+            let end = begin + metadata.size as usize;
+            if end > base.len() {
+                bail!("Metadata extent at {} is outside the base image", begin);
+            }
+
+            baseline.extend_from_slice(&base[begin..end]);
+        }
+
+        Ok(baseline)
+    }
+
+    /// Locate every known on-disk structure field in `data`. `base` is the uncompressed
+    /// original image.
+    pub(crate) fn compute_field_map(&self, base: &[u8]) -> Result<FieldMap> {
+        self.extents()
+            .map(|(metadata, range)| {
+                let structs = btrfs::extent_structs(self, base, metadata)?;
+                fields::fields_in(&structs, range)
+            })
+            .collect()
+    }
+}
+
+/// Node header field that `decompress` can restore after fuzzing. The kernel rejects nodes with
+/// any of these out of place before looking any deeper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderField {
+    /// Set to the logical address the node was found at
+    Bytenr,
+    Fsid,
+    ChunkTreeUuid,
+    Generation,
+}
+
+impl FromStr for HeaderField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let field = match s {
+            "bytenr" => HeaderField::Bytenr,
+            "fsid" => HeaderField::Fsid,
+            "chunk-tree-uuid" => HeaderField::ChunkTreeUuid,
+            "generation" => HeaderField::Generation,
+            _ => bail!("Unknown header field={}", s),
+        };
+
+        Ok(field)
+    }
+}
+
+impl fmt::Display for HeaderField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HeaderField::Bytenr => "bytenr",
+            HeaderField::Fsid => "fsid",
+            HeaderField::ChunkTreeUuid => "chunk-tree-uuid",
+            HeaderField::Generation => "generation",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Knobs for `decompress_with`
+#[derive(Debug, Clone, Default)]
+pub struct DecompressOptions {
+    /// Node header fields to leave exactly as fuzzed. Every other `HeaderField` is restored.
+    pub skip_header_fixups: Vec<HeaderField>,
+}
+
+impl DecompressOptions {
+    fn fixes(&self, field: HeaderField) -> bool {
+        !self.skip_header_fixups.contains(&field)
+    }
+}
+
+/// Knobs for `compress_with`
+#[derive(Debug, Clone, Default)]
+pub struct CompressOptions {
+    /// Also mark the data extents referenced by files as fuzzable. `decompress` recomputes their
+    /// csums in the csum tree.
+    pub data: bool,
 }
 
 /// Compress a btrfs image
 pub fn compress(img: &[u8]) -> Result<CompressedBtrfsImage> {
-    let btrfs = Btrfs::new(img)?;
-    btrfs.compress()
+    compress_with(img, &CompressOptions::default())
+}
+
+/// Like `compress` but with knobs for what gets marked as fuzzable
+pub fn compress_with(img: &[u8], opts: &CompressOptions) -> Result<CompressedBtrfsImage> {
+    let mut btrfs = Btrfs::new(img)?;
+    btrfs.compress(opts)
 }
 
 /// Decompressed an `imgcompress::compress`d btrfs image.
 ///
-/// Also rewrites superblock magic and checksums to be valid.
+/// Also rewrites the magic and `bytenr` of every superblock copy, every node header field in
+/// `HeaderField`, and all metadata and data checksums to be valid.
 pub fn decompress(compressed: &CompressedBtrfsImage) -> Result<Vec<u8>> {
+    decompress_with(compressed, &DecompressOptions::default())
+}
+
+/// Like `decompress` but with knobs for which fixups happen
+pub fn decompress_with(
+    compressed: &CompressedBtrfsImage,
+    opts: &DecompressOptions,
+) -> Result<Vec<u8>> {
     // Decompress the base image
     let mut image: Vec<u8> = decode_all(compressed.base.as_slice())?;
 
-    // Now overwrite `image` with the metadata placed at their original offsets
-    let mut data_idx = 0;
+    // Node headers in the base image are what fuzzed headers get restored to. Grab them before
+    // they get overwritten.
+    let mut base_headers = Vec::new();
     for metadata in &compressed.metadata {
+        if let Some(logical) = metadata.logical {
+            let offset: usize = metadata.offset.try_into()?;
+            let header: BtrfsHeader = *parse::read(&image, offset).with_context(|| {
+                format!("Base image too short to contain node at offset={}", offset)
+            })?;
+            base_headers.push((offset, logical, header));
+        }
+    }
+
+    // Now overwrite `image` with the metadata placed at their original offsets
+    for (metadata, range) in compressed.extents() {
         let offset: usize = metadata.offset.try_into()?;
         let size: usize = metadata.size.try_into()?;
 
         let _: Vec<_> = image
             .splice(
                 offset..(offset + size),
-                compressed.data[data_idx..(data_idx + size)].iter().cloned(),
+                compressed.data[range].iter().cloned(),
             )
             .collect();
-        data_idx += size;
     }
 
-    // Fixup the fist superblock
-    if image.len() < (BTRFS_SUPERBLOCK_OFFSET + BTRFS_SUPERBLOCK_SIZE) {
-        bail!("Decompressed image too short to contain superblock");
-    } else {
-        let superblock_ptr = image[BTRFS_SUPERBLOCK_OFFSET..].as_mut_ptr() as *mut BtrfsSuperblock;
-        let superblock = unsafe { &mut *superblock_ptr };
-
-        // We only support CRC32 for now
-        if superblock.csum_type != BTRFS_CSUM_TYPE_CRC32 {
-            let ty: u16 = superblock.csum_type;
-            println!("Warning: wrong csum type in superblock, type={}", ty);
+    // Fixup every superblock copy. Mirrors are only used if their magic is right and `bytenr`
+    // matches the offset they live at.
+    for metadata in compressed.metadata.iter().filter(|m| m.is_superblock()) {
+        let offset: usize = metadata.offset.try_into()?;
+        let superblock: &mut BtrfsSuperblock =
+            parse::read_mut(&mut image, offset).with_context(|| {
+                format!(
+                    "Decompressed image too short to contain superblock at offset={}",
+                    offset
+                )
+            })?;
+        superblock.magic = BTRFS_SUPERBLOCK_MAGIC;
+        superblock.bytenr.set(metadata.offset);
+    }
+
+    // Fixup node headers so nodes aren't rejected the moment they're read
+    for (offset, logical, base) in base_headers {
+        let header: &mut BtrfsHeader = parse::read_mut(&mut image, offset)?;
+        if opts.fixes(HeaderField::Bytenr) {
+            header.bytenr.set(logical);
+        }
+        if opts.fixes(HeaderField::Fsid) {
+            header.fsid = base.fsid;
         }
+        if opts.fixes(HeaderField::ChunkTreeUuid) {
+            header.chunk_tree_uuid = base.chunk_tree_uuid;
+        }
+        if opts.fixes(HeaderField::Generation) {
+            header.generation = base.generation;
+        }
+    }
 
-        if superblock.magic != BTRFS_SUPERBLOCK_MAGIC {
-            superblock.magic = BTRFS_SUPERBLOCK_MAGIC;
+    // Recalculate data csums. This has to happen before the csums of the csum tree leaves are.
+    for data_csum in &compressed.data_csums {
+        let begin: usize = data_csum.data.try_into()?;
+        let sector = image
+            .get(begin..begin.saturating_add(compressed.sector_size))
+            .ok_or_else(|| anyhow!("Data sector at offset={} is out of bounds", begin))?;
+        let checksum = csum::checksum(compressed.csum_type, sector)?;
+
+        for offset in &data_csum.csums {
+            let begin: usize = (*offset).try_into()?;
+            image
+                .get_mut(begin..begin.saturating_add(checksum.len()))
+                .ok_or_else(|| anyhow!("Data csum at offset={} is out of bounds", begin))?
+                .copy_from_slice(&checksum);
         }
     }
 
@@ -118,10 +337,7 @@ pub fn decompress(compressed: &CompressedBtrfsImage) -> Result<Vec<u8>> {
 
         let offset: usize = metadata.offset.try_into()?;
 
-        let block_size = if offset == BTRFS_SUPERBLOCK_OFFSET
-            || offset == BTRFS_SUPERBLOCK_OFFSET2
-            || offset == BTRFS_SUPERBLOCK_OFFSET3
-        {
+        let block_size = if metadata.is_superblock() {
             BTRFS_SUPERBLOCK_SIZE
         } else {
             compressed.node_size
@@ -131,13 +347,14 @@ pub fn decompress(compressed: &CompressedBtrfsImage) -> Result<Vec<u8>> {
         // Calculate checksum for block
         let begin = offset + BTRFS_CSUM_SIZE;
         let end = offset + block_size;
-        let checksum: u32 = crc32c_append(BTRFS_CSUM_CRC32_SEED, &image[begin..end]);
+        let checksum = csum::checksum(compressed.csum_type, &image[begin..end])?;
 
         // Write checksum back into block
         //
-        // NB: a crc32c checksum is only 4 bytes long. We'll leave the other 28 bytes alone.
+        // NB: some checksums (eg crc32c) are shorter than `BTRFS_CSUM_SIZE`. We'll leave the
+        // remaining bytes alone.
         let _: Vec<_> = image
-            .splice(offset..(offset + 4), checksum.to_le_bytes().iter().cloned())
+            .splice(offset..(offset + checksum.len()), checksum.iter().cloned())
             .collect();
     }
 
@@ -146,6 +363,11 @@ pub fn decompress(compressed: &CompressedBtrfsImage) -> Result<Vec<u8>> {
 
 #[cfg(test)]
 fn generate_test_image() -> Vec<u8> {
+    generate_test_image_with_args(&[])
+}
+
+#[cfg(test)]
+fn generate_test_image_with_args(args: &[&str]) -> Vec<u8> {
     let mut orig = NamedTempFile::new().expect("Failed to create tempfile");
     // mkfs.btrfs needs at least 120 MB to create an image
     orig.as_file()
@@ -158,6 +380,7 @@ fn generate_test_image() -> Vec<u8> {
 
     // mkfs.brtrfs
     let output = Command::new("mkfs.btrfs")
+        .args(args)
         .arg(orig.path())
         .output()
         .expect("Failed to run mkfs.btrfs");
@@ -202,6 +425,70 @@ fn test_checksum_fixup() {
     assert!(orig_buffer == decompressed);
 }
 
+/// Test that checksums are fixed up for every checksum algorithm btrfs supports
+#[test]
+fn test_checksum_fixup_all_csum_types() {
+    let csums = [
+        ("crc32c", BTRFS_CSUM_TYPE_CRC32),
+        ("xxhash", BTRFS_CSUM_TYPE_XXHASH),
+        ("sha256", BTRFS_CSUM_TYPE_SHA256),
+        ("blake2", BTRFS_CSUM_TYPE_BLAKE2),
+    ];
+
+    for (name, ty) in &csums {
+        let orig_buffer = generate_test_image_with_args(&["--csum", name]);
+
+        // Corrupted checksum should be fixed up
+        let mut corrupted_buffer = orig_buffer.clone();
+        let random: Vec<u8> = vec![0xDE; BTRFS_CSUM_SIZE];
+        corrupted_buffer.splice(
+            BTRFS_SUPERBLOCK_OFFSET..(BTRFS_SUPERBLOCK_OFFSET + BTRFS_CSUM_SIZE),
+            random.iter().cloned(),
+        );
+        let compressed = compress(&corrupted_buffer).expect("Failed to compress image");
+        let decompressed = decompress(&compressed).expect("Failed to decompress image");
+        assert!(orig_buffer == decompressed, "csum={} not fixed up", name);
+
+        // Fuzzing the csum type in the superblock must not change the fixup algorithm
+        let mut compressed = compress(&orig_buffer).expect("Failed to compress image");
+        let superblock: &mut BtrfsSuperblock = parse::read_mut(&mut compressed.data, 0).unwrap();
+        superblock.csum_type.set(0xFF);
+
+        let decompressed = decompress(&compressed).expect("Failed to decompress image");
+        let begin = BTRFS_SUPERBLOCK_OFFSET + BTRFS_CSUM_SIZE;
+        let end = BTRFS_SUPERBLOCK_OFFSET + BTRFS_SUPERBLOCK_SIZE;
+        let expected = csum::checksum(*ty, &decompressed[begin..end]).unwrap();
+        assert!(
+            decompressed[BTRFS_SUPERBLOCK_OFFSET..].starts_with(&expected),
+            "csum={} fixed up with wrong algorithm",
+            name
+        );
+    }
+}
+
+/// Test that both copies of DUP metadata get annotated
+#[test]
+fn test_dup_metadata() {
+    let count_nodes = |compressed: &CompressedBtrfsImage| {
+        compressed
+            .metadata
+            .iter()
+            .filter(|m| m.needs_csum_fixup && !m.is_superblock())
+            .count()
+    };
+
+    let single = generate_test_image_with_args(&["-m", "single"]);
+    let single = compress(&single).expect("Failed to compress single image");
+    let orig_dup = generate_test_image_with_args(&["-m", "dup"]);
+    let dup = compress(&orig_dup).expect("Failed to compress dup image");
+
+    assert!(count_nodes(&single) > 0);
+    assert_eq!(count_nodes(&single) * 2, count_nodes(&dup));
+
+    let decompressed = decompress(&dup).expect("Failed to decompress dup image");
+    assert!(orig_dup == decompressed);
+}
+
 #[test]
 fn test_superblock_magic_fixup() {
     let orig_buffer = generate_test_image();
@@ -217,7 +504,7 @@ fn test_superblock_magic_fixup() {
 
         if offset == BTRFS_SUPERBLOCK_OFFSET {
             let superblock =
-                unsafe { &mut *(compressed.data[data_idx..].as_mut_ptr() as *mut BtrfsSuperblock) };
+                parse::read_mut::<BtrfsSuperblock>(&mut compressed.data, data_idx).unwrap();
             // Magic corruption
             superblock.magic[3] = b'Z';
             corrupted_super = true;
@@ -233,6 +520,99 @@ fn test_superblock_magic_fixup() {
     assert!(orig_buffer == decompressed);
 }
 
+/// Test that every superblock mirror gets its magic, `bytenr` and checksum fixed up
+#[test]
+fn test_superblock_mirror_fixup() {
+    let orig_buffer = generate_test_image();
+
+    let mut compressed = compress(&orig_buffer).expect("Failed to compress image");
+    let mirrors: Vec<(u64, Range<usize>)> = compressed
+        .extents()
+        .filter(|(m, _)| m.is_superblock() && m.offset != BTRFS_SUPERBLOCK_OFFSET as u64)
+        .map(|(m, range)| (m.offset, range))
+        .collect();
+    // 120M image only has room for the first mirror
+    assert_eq!(mirrors.len(), 1);
+
+    for (_, range) in &mirrors {
+        let superblock =
+            parse::read_mut::<BtrfsSuperblock>(&mut compressed.data, range.start).unwrap();
+        superblock.magic[0] = b'Z';
+        superblock.bytenr.set(0xDEADBEEF);
+        superblock.csum[0] ^= 0xFF;
+    }
+
+    let decompressed = decompress(&compressed).expect("Failed to decompress image");
+    for (offset, _) in &mirrors {
+        let offset = *offset as usize;
+        let superblock = parse::read::<BtrfsSuperblock>(&decompressed, offset).unwrap();
+        assert_eq!(superblock.magic, BTRFS_SUPERBLOCK_MAGIC);
+        assert_eq!(superblock.bytenr.get(), offset as u64);
+    }
+    assert!(orig_buffer == decompressed);
+}
+
+/// Test that fuzzed node headers are restored unless the fixup is skipped
+#[test]
+fn test_header_fixups() {
+    use std::mem::size_of;
+    use zstd::stream::encode_all;
+
+    const NODE_SIZE: usize = 4096;
+    const PHYSICAL: usize = 0x2000;
+    const LOGICAL: u64 = 0x100000;
+
+    let mut base = vec![0; PHYSICAL + NODE_SIZE];
+    let mut header: BtrfsHeader = parse::zeroed();
+    header.fsid = [0xAA; 16];
+    header.chunk_tree_uuid = [0xBB; 16];
+    header.bytenr.set(LOGICAL);
+    header.generation.set(7);
+    parse::put(&mut base, PHYSICAL, header);
+
+    let mut fuzzed = header;
+    fuzzed.fsid = [0; 16];
+    fuzzed.chunk_tree_uuid = [0; 16];
+    fuzzed.bytenr.set(1);
+    fuzzed.generation.set(8);
+    fuzzed.nritems.set(3);
+    let mut data = vec![0; size_of::<BtrfsHeader>()];
+    parse::put(&mut data, 0, fuzzed);
+
+    let compressed = CompressedBtrfsImage {
+        base: encode_all(base.as_slice(), 0).unwrap(),
+        metadata: vec![MetadataExtent {
+            needs_csum_fixup: true,
+            offset: PHYSICAL as u64,
+            size: data.len() as u64,
+            logical: Some(LOGICAL),
+            ..Default::default()
+        }],
+        data,
+        node_size: NODE_SIZE,
+        csum_type: BTRFS_CSUM_TYPE_CRC32,
+        ..Default::default()
+    };
+
+    let image = decompress(&compressed).unwrap();
+    let fixed = parse::read::<BtrfsHeader>(&image, PHYSICAL).unwrap();
+    assert_eq!(fixed.bytenr.get(), LOGICAL);
+    assert_eq!(fixed.fsid, header.fsid);
+    assert_eq!(fixed.chunk_tree_uuid, header.chunk_tree_uuid);
+    assert_eq!(fixed.generation.get(), 7);
+    // Everything else is left fuzzed
+    assert_eq!(fixed.nritems.get(), 3);
+
+    let opts = DecompressOptions {
+        skip_header_fixups: vec![HeaderField::Bytenr, HeaderField::Generation],
+    };
+    let image = decompress_with(&compressed, &opts).unwrap();
+    let fixed = parse::read::<BtrfsHeader>(&image, PHYSICAL).unwrap();
+    assert_eq!(fixed.bytenr.get(), 1);
+    assert_eq!(fixed.fsid, header.fsid);
+    assert_eq!(fixed.generation.get(), 8);
+}
+
 /// Test that checksums are recalculated on metadata changes. Note that this is pretty difficult to
 /// test accurately so we opt to just check that the checksum was changed.
 #[test]
@@ -289,3 +669,57 @@ fn test_checksum_fixup_on_metadata_corruption() {
     // Test that checksum changed
     assert!(csum_before.unwrap() != csum_after);
 }
+
+#[test]
+fn test_field_map() {
+    let orig_buffer = generate_test_image();
+    let compressed = compress(&orig_buffer).expect("Failed to compress image");
+    let fields = &compressed.fields;
+    assert_eq!(fields.len(), compressed.metadata.len());
+
+    let mut saw_header = false;
+    for ((_, range), extent_fields) in compressed.extents().zip(fields) {
+        for field in extent_fields {
+            let begin: usize = field.offset.try_into().unwrap();
+            let end = begin + field.size as usize;
+            assert!(begin >= range.start && end <= range.end);
+            assert_eq!(field.size % u64::from(field.width), 0);
+
+            if field.kind == StructKind::Header && field.name == "bytenr" {
+                saw_header = true;
+            }
+        }
+    }
+    assert!(saw_header);
+
+    // Magic in the primary superblock should be where the field map says it is
+    let magic = fields[0]
+        .iter()
+        .find(|f| f.kind == StructKind::Superblock && f.name == "magic")
+        .expect("Failed to find superblock magic");
+    let begin = magic.offset as usize;
+    let magic_bytes = &compressed.data[begin..(begin + magic.size as usize)];
+    assert_eq!(magic_bytes, &BTRFS_SUPERBLOCK_MAGIC);
+
+    // The field map survives serialization
+    let mut buf = Vec::new();
+    compressed
+        .serialize(&mut rmp_serde::Serializer::new(&mut buf))
+        .unwrap();
+    let deserialized: CompressedBtrfsImage = rmp_serde::from_read_ref(&buf).unwrap();
+    assert!(deserialized.fields == compressed.fields);
+}
+
+#[test]
+fn test_baseline() {
+    let orig_buffer = generate_test_image();
+    let mut compressed = compress(&orig_buffer).expect("Failed to compress image");
+    let orig_data = compressed.data.clone();
+    assert!(compressed.baseline().unwrap() == orig_data);
+
+    // Fuzzing `data` shouldn't change the baseline
+    for byte in compressed.data.iter_mut().step_by(7) {
+        *byte = !*byte;
+    }
+    assert!(compressed.baseline().unwrap() == orig_data);
+}
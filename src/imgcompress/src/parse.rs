@@ -0,0 +1,158 @@
+//! Safe, zero-copy access to on-disk structures
+//!
+//! Every on-disk struct is `#[repr(C, packed)]` and built out of bytes and little endian
+//! integers, so any run of bytes is a valid instance and there are no alignment requirements.
+//! That means parsing only needs a bounds check, which every function here does.
+
+use std::any::type_name;
+use std::fmt;
+use std::mem::{size_of, MaybeUninit};
+
+use anyhow::{bail, Result};
+
+/// Implemented by types that can be read from any bytes
+///
+/// # Safety
+///
+/// Implementors must have an alignment of 1 and every bit pattern must be a valid instance. In
+/// practice that means `#[repr(C, packed)]` structs made only of `OnDisk` fields.
+pub unsafe trait OnDisk: Copy {}
+
+macro_rules! impl_le {
+    ($($name:ident: $ty:ty),*) => {
+        $(
+            /// Little endian integer as stored on disk
+            #[repr(transparent)]
+            #[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+            pub struct $name([u8; size_of::<$ty>()]);
+
+            impl $name {
+                pub const fn new(val: $ty) -> Self {
+                    Self(val.to_le_bytes())
+                }
+
+                pub const fn get(self) -> $ty {
+                    <$ty>::from_le_bytes(self.0)
+                }
+
+                pub fn set(&mut self, val: $ty) {
+                    *self = Self::new(val);
+                }
+            }
+
+            impl From<$ty> for $name {
+                fn from(val: $ty) -> Self {
+                    Self::new(val)
+                }
+            }
+
+            impl fmt::Debug for $name {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    fmt::Debug::fmt(&self.get(), f)
+                }
+            }
+
+            impl fmt::Display for $name {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    fmt::Display::fmt(&self.get(), f)
+                }
+            }
+
+            impl fmt::LowerHex for $name {
+                fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    fmt::LowerHex::fmt(&self.get(), f)
+                }
+            }
+
+            unsafe impl OnDisk for $name {}
+        )*
+    };
+}
+
+impl_le!(Le16: u16, Le32: u32, Le64: u64);
+
+unsafe impl OnDisk for u8 {}
+unsafe impl<T: OnDisk, const N: usize> OnDisk for [T; N] {}
+
+/// Checks that `count` `T`s starting at `offset` fit in `len` bytes
+fn check_bounds<T>(len: usize, offset: usize, count: usize) -> Result<()> {
+    let end = size_of::<T>()
+        .checked_mul(count)
+        .and_then(|size| size.checked_add(offset));
+    match end {
+        Some(end) if end <= len => Ok(()),
+        _ => bail!(
+            "{} x {} at offset={} is out of bounds (len={})",
+            count,
+            type_name::<T>(),
+            offset,
+            len
+        ),
+    }
+}
+
+/// Read the `T` at `offset` in `buf`
+pub fn read<T: OnDisk>(buf: &[u8], offset: usize) -> Result<&T> {
+    check_bounds::<T>(buf.len(), offset, 1)?;
+
+    // Safe b/c it's in bounds and `T: OnDisk` is valid for any bytes at any alignment
+    Ok(unsafe { &*(buf.as_ptr().add(offset) as *const T) })
+}
+
+/// Mutable version of `read`
+pub fn read_mut<T: OnDisk>(buf: &mut [u8], offset: usize) -> Result<&mut T> {
+    check_bounds::<T>(buf.len(), offset, 1)?;
+
+    // Safe for the same reasons as `read`
+    Ok(unsafe { &mut *(buf.as_mut_ptr().add(offset) as *mut T) })
+}
+
+/// Read `count` consecutive `T`s starting at `offset` in `buf`
+pub fn read_array<T: OnDisk>(buf: &[u8], offset: usize, count: usize) -> Result<&[T]> {
+    check_bounds::<T>(buf.len(), offset, count)?;
+
+    // Safe for the same reasons as `read`
+    Ok(unsafe { std::slice::from_raw_parts(buf.as_ptr().add(offset) as *const T, count) })
+}
+
+/// A `T` with every byte set to 0
+pub fn zeroed<T: OnDisk>() -> T {
+    // Safe b/c `T: OnDisk` is valid for any bytes
+    unsafe { MaybeUninit::zeroed().assume_init() }
+}
+
+/// Overwrite the `T` at `offset` in `buf`. Panics if it doesn't fit.
+#[cfg(test)]
+pub(crate) fn put<T: OnDisk>(buf: &mut [u8], offset: usize, val: T) {
+    *read_mut(buf, offset).unwrap() = val;
+}
+
+#[test]
+fn test_le() {
+    let mut val = Le32::new(0x1234_5678);
+    assert_eq!(val.0, [0x78, 0x56, 0x34, 0x12]);
+    assert_eq!(val.get(), 0x1234_5678);
+    val.set(7);
+    assert_eq!(format!("{} {:?} {:x}", val, val, Le64::new(255)), "7 7 ff");
+}
+
+#[test]
+fn test_read() {
+    let buf = [1, 0, 2, 0, 3, 0];
+    assert_eq!(read::<Le16>(&buf, 2).unwrap().get(), 2);
+    assert!(read::<Le16>(&buf, 5)
+        .unwrap_err()
+        .to_string()
+        .contains("out of bounds"));
+    assert!(read::<Le16>(&buf, usize::MAX).is_err());
+
+    let array = read_array::<Le16>(&buf, 1, 2).unwrap();
+    assert_eq!(array[0].get(), 0x200);
+    assert_eq!(array[1].get(), 0x300);
+    assert!(read_array::<Le16>(&buf, 0, 4).is_err());
+    assert!(read_array::<Le16>(&buf, 0, usize::MAX).is_err());
+
+    let mut buf = buf;
+    read_mut::<Le16>(&mut buf, 4).unwrap().set(0xabcd);
+    assert_eq!(buf[4..], [0xcd, 0xab]);
+}
@@ -1,3 +1,5 @@
+use crate::parse::{Le16, Le32, Le64, OnDisk};
+
 pub const BTRFS_CSUM_SIZE: usize = 32;
 const BTRFS_LABEL_SIZE: usize = 256;
 const BTRFS_FSID_SIZE: usize = 16;
@@ -7,40 +9,152 @@ const BTRFS_SYSTEM_CHUNK_ARRAY_SIZE: usize = 2048;
 pub const BTRFS_SUPERBLOCK_OFFSET: usize = 0x10_000;
 pub const BTRFS_SUPERBLOCK_OFFSET2: usize = 0x4_000_000;
 pub const BTRFS_SUPERBLOCK_OFFSET3: usize = 0x4_000_000_000;
+/// Offsets of the primary superblock and its mirrors
+pub const BTRFS_SUPERBLOCK_OFFSETS: [usize; 3] = [
+    BTRFS_SUPERBLOCK_OFFSET,
+    BTRFS_SUPERBLOCK_OFFSET2,
+    BTRFS_SUPERBLOCK_OFFSET3,
+];
 pub const BTRFS_SUPERBLOCK_MAGIC: [u8; 8] = *b"_BHRfS_M";
 pub const BTRFS_SUPERBLOCK_SIZE: usize = 4096;
 pub const BTRFS_CSUM_TYPE_CRC32: u16 = 0;
+pub const BTRFS_CSUM_TYPE_XXHASH: u16 = 1;
+pub const BTRFS_CSUM_TYPE_SHA256: u16 = 2;
+pub const BTRFS_CSUM_TYPE_BLAKE2: u16 = 3;
 /// All the docs and code suggest it's `u32::MAX` but after many hours of debugging it turns out
 /// only 0 works. Something is definitely fishy here. At least we have tests that test checksum
 /// integrity.
 pub const BTRFS_CSUM_CRC32_SEED: u32 = 0;
 
-pub const BTRFS_CHUNK_ITEM_KEY: u8 = 228;
+/// Largest possible node size
+pub const BTRFS_MAX_METADATA_BLOCKSIZE: usize = 65536;
+/// Trees can't be deeper than this. See `BTRFS_MAX_LEVEL` in fs/btrfs/ctree.h.
+pub const BTRFS_MAX_LEVEL: u8 = 8;
+
+pub const BTRFS_CSUM_TREE_OBJECTID: u64 = 7;
+/// Objectid of every `BTRFS_EXTENT_CSUM_KEY` item, ie -10
+pub const BTRFS_EXTENT_CSUM_OBJECTID: u64 = -10i64 as u64;
+
+pub const BTRFS_FILE_EXTENT_INLINE: u8 = 0;
+pub const BTRFS_FILE_EXTENT_REG: u8 = 1;
+pub const BTRFS_FILE_EXTENT_PREALLOC: u8 = 2;
+
+pub const BTRFS_INODE_ITEM_KEY: u8 = 1;
+pub const BTRFS_INODE_REF_KEY: u8 = 12;
+pub const BTRFS_INODE_EXTREF_KEY: u8 = 13;
+pub const BTRFS_XATTR_ITEM_KEY: u8 = 24;
+pub const BTRFS_ORPHAN_ITEM_KEY: u8 = 48;
+pub const BTRFS_DIR_LOG_ITEM_KEY: u8 = 60;
+pub const BTRFS_DIR_LOG_INDEX_KEY: u8 = 72;
+pub const BTRFS_DIR_ITEM_KEY: u8 = 84;
+pub const BTRFS_DIR_INDEX_KEY: u8 = 96;
+pub const BTRFS_EXTENT_DATA_KEY: u8 = 108;
+pub const BTRFS_EXTENT_CSUM_KEY: u8 = 128;
 pub const BTRFS_ROOT_ITEM_KEY: u8 = 132;
+pub const BTRFS_ROOT_BACKREF_KEY: u8 = 144;
+pub const BTRFS_ROOT_REF_KEY: u8 = 156;
+pub const BTRFS_EXTENT_ITEM_KEY: u8 = 168;
+pub const BTRFS_METADATA_ITEM_KEY: u8 = 169;
+pub const BTRFS_TREE_BLOCK_REF_KEY: u8 = 176;
+pub const BTRFS_EXTENT_DATA_REF_KEY: u8 = 178;
+pub const BTRFS_SHARED_BLOCK_REF_KEY: u8 = 182;
+pub const BTRFS_SHARED_DATA_REF_KEY: u8 = 184;
+pub const BTRFS_BLOCK_GROUP_ITEM_KEY: u8 = 192;
+pub const BTRFS_FREE_SPACE_INFO_KEY: u8 = 198;
+pub const BTRFS_FREE_SPACE_EXTENT_KEY: u8 = 199;
+pub const BTRFS_FREE_SPACE_BITMAP_KEY: u8 = 200;
+pub const BTRFS_DEV_EXTENT_KEY: u8 = 204;
+pub const BTRFS_DEV_ITEM_KEY: u8 = 216;
+pub const BTRFS_CHUNK_ITEM_KEY: u8 = 228;
+pub const BTRFS_QGROUP_STATUS_KEY: u8 = 240;
+pub const BTRFS_QGROUP_INFO_KEY: u8 = 242;
+pub const BTRFS_QGROUP_LIMIT_KEY: u8 = 244;
+pub const BTRFS_QGROUP_RELATION_KEY: u8 = 246;
+pub const BTRFS_PERSISTENT_ITEM_KEY: u8 = 249;
+pub const BTRFS_DEV_REPLACE_KEY: u8 = 250;
+pub const BTRFS_UUID_KEY_SUBVOL: u8 = 251;
+pub const BTRFS_UUID_KEY_RECEIVED_SUBVOL: u8 = 252;
+pub const BTRFS_STRING_ITEM_KEY: u8 = 253;
+
+/// Name of a key type the way btrfs-progs prints it, eg `INODE_ITEM`
+pub fn key_type_name(ty: u8) -> Option<&'static str> {
+    let name = match ty {
+        BTRFS_INODE_ITEM_KEY => "INODE_ITEM",
+        BTRFS_INODE_REF_KEY => "INODE_REF",
+        BTRFS_INODE_EXTREF_KEY => "INODE_EXTREF",
+        BTRFS_XATTR_ITEM_KEY => "XATTR_ITEM",
+        BTRFS_ORPHAN_ITEM_KEY => "ORPHAN_ITEM",
+        BTRFS_DIR_LOG_ITEM_KEY => "DIR_LOG_ITEM",
+        BTRFS_DIR_LOG_INDEX_KEY => "DIR_LOG_INDEX",
+        BTRFS_DIR_ITEM_KEY => "DIR_ITEM",
+        BTRFS_DIR_INDEX_KEY => "DIR_INDEX",
+        BTRFS_EXTENT_DATA_KEY => "EXTENT_DATA",
+        BTRFS_EXTENT_CSUM_KEY => "EXTENT_CSUM",
+        BTRFS_ROOT_ITEM_KEY => "ROOT_ITEM",
+        BTRFS_ROOT_BACKREF_KEY => "ROOT_BACKREF",
+        BTRFS_ROOT_REF_KEY => "ROOT_REF",
+        BTRFS_EXTENT_ITEM_KEY => "EXTENT_ITEM",
+        BTRFS_METADATA_ITEM_KEY => "METADATA_ITEM",
+        BTRFS_TREE_BLOCK_REF_KEY => "TREE_BLOCK_REF",
+        BTRFS_EXTENT_DATA_REF_KEY => "EXTENT_DATA_REF",
+        BTRFS_SHARED_BLOCK_REF_KEY => "SHARED_BLOCK_REF",
+        BTRFS_SHARED_DATA_REF_KEY => "SHARED_DATA_REF",
+        BTRFS_BLOCK_GROUP_ITEM_KEY => "BLOCK_GROUP_ITEM",
+        BTRFS_FREE_SPACE_INFO_KEY => "FREE_SPACE_INFO",
+        BTRFS_FREE_SPACE_EXTENT_KEY => "FREE_SPACE_EXTENT",
+        BTRFS_FREE_SPACE_BITMAP_KEY => "FREE_SPACE_BITMAP",
+        BTRFS_DEV_EXTENT_KEY => "DEV_EXTENT",
+        BTRFS_DEV_ITEM_KEY => "DEV_ITEM",
+        BTRFS_CHUNK_ITEM_KEY => "CHUNK_ITEM",
+        BTRFS_QGROUP_STATUS_KEY => "QGROUP_STATUS",
+        BTRFS_QGROUP_INFO_KEY => "QGROUP_INFO",
+        BTRFS_QGROUP_LIMIT_KEY => "QGROUP_LIMIT",
+        BTRFS_QGROUP_RELATION_KEY => "QGROUP_RELATION",
+        BTRFS_PERSISTENT_ITEM_KEY => "PERSISTENT_ITEM",
+        BTRFS_DEV_REPLACE_KEY => "DEV_REPLACE",
+        BTRFS_UUID_KEY_SUBVOL => "UUID_KEY_SUBVOL",
+        BTRFS_UUID_KEY_RECEIVED_SUBVOL => "UUID_KEY_RECEIVED_SUBVOL",
+        BTRFS_STRING_ITEM_KEY => "STRING_ITEM",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+pub const BTRFS_STRIPE_LEN: u64 = 64 << 10;
+
+pub const BTRFS_BLOCK_GROUP_RAID0: u64 = 1 << 3;
+pub const BTRFS_BLOCK_GROUP_RAID1: u64 = 1 << 4;
+pub const BTRFS_BLOCK_GROUP_DUP: u64 = 1 << 5;
+pub const BTRFS_BLOCK_GROUP_RAID10: u64 = 1 << 6;
+pub const BTRFS_BLOCK_GROUP_RAID5: u64 = 1 << 7;
+pub const BTRFS_BLOCK_GROUP_RAID6: u64 = 1 << 8;
+pub const BTRFS_BLOCK_GROUP_RAID1C3: u64 = 1 << 9;
+pub const BTRFS_BLOCK_GROUP_RAID1C4: u64 = 1 << 10;
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsDevItem {
     /// the internal btrfs device id
-    pub devid: u64,
+    pub devid: Le64,
     /// size of the device
-    pub total_bytes: u64,
+    pub total_bytes: Le64,
     /// bytes used
-    pub bytes_used: u64,
+    pub bytes_used: Le64,
     /// optimal io alignment for this device
-    pub io_align: u32,
+    pub io_align: Le32,
     /// optimal io width for this device
-    pub io_width: u32,
+    pub io_width: Le32,
     /// minimal io size for this device
-    pub sector_size: u32,
+    pub sector_size: Le32,
     /// type and info about this device
-    pub ty: u64,
+    pub ty: Le64,
     /// expected generation for this device
-    pub generation: u64,
+    pub generation: Le64,
     /// starting byte of this partition on the device, to allow for stripe alignment in the future
-    pub start_offset: u64,
+    pub start_offset: Le64,
     /// grouping information for allocation decisions
-    pub dev_group: u32,
+    pub dev_group: Le32,
     /// seek speed 0-100 where 100 is fastest
     pub seek_speed: u8,
     /// bandwidth 0-100 where 100 is fastest
@@ -54,23 +168,23 @@ pub struct BtrfsDevItem {
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsRootBackup {
-    pub tree_root: u64,
-    pub tree_root_gen: u64,
-    pub chunk_root: u64,
-    pub chunk_root_gen: u64,
-    pub extent_root: u64,
-    pub extent_root_gen: u64,
-    pub fs_root: u64,
-    pub fs_root_gen: u64,
-    pub dev_root: u64,
-    pub dev_root_gen: u64,
-    pub csum_root: u64,
-    pub csum_root_gen: u64,
-    pub total_bytes: u64,
-    pub bytes_used: u64,
-    pub num_devices: u64,
+    pub tree_root: Le64,
+    pub tree_root_gen: Le64,
+    pub chunk_root: Le64,
+    pub chunk_root_gen: Le64,
+    pub extent_root: Le64,
+    pub extent_root_gen: Le64,
+    pub fs_root: Le64,
+    pub fs_root_gen: Le64,
+    pub dev_root: Le64,
+    pub dev_root_gen: Le64,
+    pub csum_root: Le64,
+    pub csum_root_gen: Le64,
+    pub total_bytes: Le64,
+    pub bytes_used: Le64,
+    pub num_devices: Le64,
     /// future
-    pub unused_64: [u64; 4],
+    pub unused_64: [Le64; 4],
     pub tree_root_level: u8,
     pub chunk_root_level: u8,
     pub extent_root_level: u8,
@@ -87,42 +201,42 @@ pub struct BtrfsSuperblock {
     pub csum: [u8; BTRFS_CSUM_SIZE],
     pub fsid: [u8; BTRFS_FSID_SIZE],
     /// Physical address of this block
-    pub bytenr: u64,
-    pub flags: u64,
+    pub bytenr: Le64,
+    pub flags: Le64,
     pub magic: [u8; 0x8],
-    pub generation: u64,
+    pub generation: Le64,
     /// Logical address of the root tree root
-    pub root: u64,
+    pub root: Le64,
     /// Logical address of the chunk tree root
-    pub chunk_root: u64,
+    pub chunk_root: Le64,
     /// Logical address of the log tree root
-    pub log_root: u64,
-    pub log_root_transid: u64,
-    pub total_bytes: u64,
-    pub bytes_used: u64,
-    pub root_dir_objectid: u64,
-    pub num_devices: u64,
-    pub sector_size: u32,
-    pub node_size: u32,
+    pub log_root: Le64,
+    pub log_root_transid: Le64,
+    pub total_bytes: Le64,
+    pub bytes_used: Le64,
+    pub root_dir_objectid: Le64,
+    pub num_devices: Le64,
+    pub sector_size: Le32,
+    pub node_size: Le32,
     /// Unused and must be equal to `nodesize`
-    pub leafsize: u32,
-    pub stripesize: u32,
-    pub sys_chunk_array_size: u32,
-    pub chunk_root_generation: u64,
-    pub compat_flags: u64,
-    pub compat_ro_flags: u64,
-    pub incompat_flags: u64,
-    pub csum_type: u16,
+    pub leafsize: Le32,
+    pub stripesize: Le32,
+    pub sys_chunk_array_size: Le32,
+    pub chunk_root_generation: Le64,
+    pub compat_flags: Le64,
+    pub compat_ro_flags: Le64,
+    pub incompat_flags: Le64,
+    pub csum_type: Le16,
     pub root_level: u8,
     pub chunk_root_level: u8,
     pub log_root_level: u8,
     pub dev_item: BtrfsDevItem,
     pub label: [u8; BTRFS_LABEL_SIZE],
-    pub cache_generation: u64,
-    pub uuid_tree_generation: u64,
+    pub cache_generation: Le64,
+    pub uuid_tree_generation: Le64,
     pub metadata_uuid: [u8; BTRFS_FSID_SIZE],
     /// Future expansion
-    pub _reserved: [u64; 28],
+    pub _reserved: [Le64; 28],
     pub sys_chunk_array: [u8; BTRFS_SYSTEM_CHUNK_ARRAY_SIZE],
     pub root_backups: [BtrfsRootBackup; 4],
 }
@@ -130,8 +244,8 @@ pub struct BtrfsSuperblock {
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsStripe {
-    pub devid: u64,
-    pub offset: u64,
+    pub devid: Le64,
+    pub offset: Le64,
     pub dev_uuid: [u8; BTRFS_UUID_SIZE],
 }
 
@@ -139,21 +253,21 @@ pub struct BtrfsStripe {
 #[derive(Copy, Clone)]
 pub struct BtrfsChunk {
     /// size of this chunk in bytes
-    pub length: u64,
+    pub length: Le64,
     /// objectid of the root referencing this chunk
-    pub owner: u64,
-    pub stripe_len: u64,
-    pub ty: u64,
+    pub owner: Le64,
+    pub stripe_len: Le64,
+    pub ty: Le64,
     /// optimal io alignment for this chunk
-    pub io_align: u32,
+    pub io_align: Le32,
     /// optimal io width for this chunk
-    pub io_width: u32,
+    pub io_width: Le32,
     /// minimal io size for this chunk
-    pub sector_size: u32,
+    pub sector_size: Le32,
     /// 2^16 stripes is quite a lot, a second limit is the size of a single item in the btree
-    pub num_stripes: u16,
+    pub num_stripes: Le16,
     /// sub stripes only matter for raid10
-    pub sub_stripes: u16,
+    pub sub_stripes: Le16,
     pub stripe: BtrfsStripe,
     // additional stripes go here
 }
@@ -161,29 +275,29 @@ pub struct BtrfsChunk {
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsTimespec {
-    pub sec: u64,
-    pub nsec: u32,
+    pub sec: Le64,
+    pub nsec: Le32,
 }
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsInodeItem {
     /// nfs style generation number
-    pub generation: u64,
+    pub generation: Le64,
     /// transid that last touched this inode
-    pub transid: u64,
-    pub size: u64,
-    pub nbytes: u64,
-    pub block_group: u64,
-    pub nlink: u32,
-    pub uid: u32,
-    pub gid: u32,
-    pub mode: u32,
-    pub rdev: u64,
-    pub flags: u64,
+    pub transid: Le64,
+    pub size: Le64,
+    pub nbytes: Le64,
+    pub block_group: Le64,
+    pub nlink: Le32,
+    pub uid: Le32,
+    pub gid: Le32,
+    pub mode: Le32,
+    pub rdev: Le64,
+    pub flags: Le64,
     /// modification sequence number for NFS
-    pub sequence: u64,
-    pub reserved: [u64; 4],
+    pub sequence: Le64,
+    pub reserved: [Le64; 4],
     pub atime: BtrfsTimespec,
     pub ctime: BtrfsTimespec,
     pub mtime: BtrfsTimespec,
@@ -194,59 +308,77 @@ pub struct BtrfsInodeItem {
 #[derive(Copy, Clone)]
 pub struct BtrfsRootItem {
     pub inode: BtrfsInodeItem,
-    pub generation: u64,
-    pub root_dirid: u64,
-    pub bytenr: u64,
-    pub byte_limit: u64,
-    pub bytes_used: u64,
-    pub last_snapshot: u64,
-    pub flags: u64,
-    pub refs: u32,
+    pub generation: Le64,
+    pub root_dirid: Le64,
+    pub bytenr: Le64,
+    pub byte_limit: Le64,
+    pub bytes_used: Le64,
+    pub last_snapshot: Le64,
+    pub flags: Le64,
+    pub refs: Le32,
     pub drop_progress: BtrfsKey,
     pub drop_level: u8,
     pub level: u8,
-    pub generation_v2: u64,
+    pub generation_v2: Le64,
     pub uuid: [u8; BTRFS_UUID_SIZE],
     pub parent_uuid: [u8; BTRFS_UUID_SIZE],
     pub received_uuid: [u8; BTRFS_UUID_SIZE],
     /// updated when an inode changes
-    pub ctransid: u64,
+    pub ctransid: Le64,
     /// trans when created
-    pub otransid: u64,
+    pub otransid: Le64,
     /// trans when sent. non-zero for received subvol
-    pub stransid: u64,
+    pub stransid: Le64,
     /// trans when received. non-zero for received subvol
-    pub rtransid: u64,
+    pub rtransid: Le64,
     pub ctime: BtrfsTimespec,
     pub otime: BtrfsTimespec,
     pub stime: BtrfsTimespec,
     pub rtime: BtrfsTimespec,
-    pub reserved: [u64; 8],
+    pub reserved: [Le64; 8],
 }
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsDirItem {
     pub location: BtrfsKey,
-    pub transid: u64,
-    pub data_len: u16,
-    pub name_len: u16,
+    pub transid: Le64,
+    pub data_len: Le16,
+    pub name_len: Le16,
     pub ty: u8,
 }
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsInodeRef {
-    pub index: u64,
-    pub name_len: u16,
+    pub index: Le64,
+    pub name_len: Le16,
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BtrfsFileExtentItem {
+    pub generation: Le64,
+    /// Size of the extent once decompressed
+    pub ram_bytes: Le64,
+    pub compression: u8,
+    pub encryption: u8,
+    pub other_encoding: Le16,
+    pub ty: u8,
+    /// Inline extents store their data starting here instead of the fields below
+    pub disk_bytenr: Le64,
+    pub disk_num_bytes: Le64,
+    /// Offset into the extent the file's data starts at
+    pub offset: Le64,
+    pub num_bytes: Le64,
 }
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct BtrfsKey {
-    pub objectid: u64,
+    pub objectid: Le64,
     pub ty: u8,
-    pub offset: u64,
+    pub offset: Le64,
 }
 
 #[repr(C, packed)]
@@ -255,12 +387,12 @@ pub struct BtrfsHeader {
     pub csum: [u8; BTRFS_CSUM_SIZE],
     pub fsid: [u8; BTRFS_FSID_SIZE],
     /// Which block this node is supposed to live in
-    pub bytenr: u64,
-    pub flags: u64,
+    pub bytenr: Le64,
+    pub flags: Le64,
     pub chunk_tree_uuid: [u8; BTRFS_UUID_SIZE],
-    pub generation: u64,
-    pub owner: u64,
-    pub nritems: u32,
+    pub generation: Le64,
+    pub owner: Le64,
+    pub nritems: Le32,
     pub level: u8,
 }
 
@@ -270,8 +402,8 @@ pub struct BtrfsHeader {
 /// tell us where to find the item in the leaf.
 pub struct BtrfsItem {
     pub key: BtrfsKey,
-    pub offset: u32,
-    pub size: u32,
+    pub offset: Le32,
+    pub size: Le32,
 }
 
 #[repr(C, packed)]
@@ -286,8 +418,8 @@ pub struct BtrfsLeaf {
 /// All non-leaf blocks are nodes and they hold only keys are pointers to other blocks
 pub struct BtrfsKeyPtr {
     pub key: BtrfsKey,
-    pub blockptr: u64,
-    pub generation: u64,
+    pub blockptr: Le64,
+    pub generation: Le64,
 }
 
 #[repr(C, packed)]
@@ -296,3 +428,30 @@ pub struct BtrfsNode {
     pub header: BtrfsHeader,
     // `BtrfsKeyPtr`s begin here
 }
+
+/// Safe b/c every struct above is packed and only made of `OnDisk` fields
+macro_rules! impl_on_disk {
+    ($($ty:ty),*) => {
+        $(unsafe impl OnDisk for $ty {})*
+    };
+}
+
+impl_on_disk!(
+    BtrfsDevItem,
+    BtrfsRootBackup,
+    BtrfsSuperblock,
+    BtrfsStripe,
+    BtrfsChunk,
+    BtrfsTimespec,
+    BtrfsInodeItem,
+    BtrfsRootItem,
+    BtrfsDirItem,
+    BtrfsInodeRef,
+    BtrfsFileExtentItem,
+    BtrfsKey,
+    BtrfsHeader,
+    BtrfsItem,
+    BtrfsLeaf,
+    BtrfsKeyPtr,
+    BtrfsNode
+);
@@ -1,41 +1,54 @@
-use anyhow::{bail, Result};
+use std::mem::size_of;
 
+use anyhow::{Context, Result};
+
+use crate::parse;
 use crate::structs::*;
 
 /// Parse BtrfsHeader from a tree node (internal or leaf)
-pub fn parse_btrfs_header<'a>(buf: &'a [u8]) -> Result<&'a BtrfsHeader> {
-    let header_size = std::mem::size_of::<BtrfsHeader>();
-    if buf.len() < header_size {
-        bail!("Failed to parse BtrfsHeader b/c buf too small");
-    }
+pub fn parse_btrfs_header(buf: &[u8]) -> Result<&BtrfsHeader> {
+    parse::read(buf, 0).with_context(|| "Failed to parse BtrfsHeader b/c buf too small".to_string())
+}
 
-    Ok(unsafe { &*(buf.as_ptr() as *const BtrfsHeader) })
+/// Parse the `idx`th `T` (`BtrfsItem` or `BtrfsKeyPtr`) following the header in `node`
+///
+/// Returns `None` if the slot doesn't fit in `node`.
+pub fn parse_slot<T: parse::OnDisk>(node: &[u8], idx: usize) -> Option<&T> {
+    let begin = size_of::<BtrfsHeader>().checked_add(idx.checked_mul(size_of::<T>())?)?;
+    parse::read(node, begin).ok()
 }
 
 /// Parse an internal tree node
 ///
 /// Precondition is that `buf` is not a leaf node.
-pub fn parse_btrfs_node<'a>(buf: &'a [u8]) -> Result<Vec<&'a BtrfsKeyPtr>> {
+pub fn parse_btrfs_node(buf: &[u8]) -> Result<&[BtrfsKeyPtr]> {
     let header = parse_btrfs_header(buf)?;
-    let mut offset = std::mem::size_of::<BtrfsHeader>();
-    let mut key_ptrs = Vec::new();
-    for _ in 0..header.nritems {
-        key_ptrs.push(unsafe { &*(buf.as_ptr().add(offset) as *const BtrfsKeyPtr) });
-        offset += std::mem::size_of::<BtrfsKeyPtr>();
-    }
-
-    Ok(key_ptrs)
+    parse::read_array(buf, size_of::<BtrfsHeader>(), header.nritems.get() as usize)
+        .with_context(|| format!("nritems={} doesn't fit in node", header.nritems))
 }
 
 /// Parse leaf tree node
-pub fn parse_btrfs_leaf<'a>(buf: &'a [u8]) -> Result<Vec<&'a BtrfsItem>> {
+pub fn parse_btrfs_leaf(buf: &[u8]) -> Result<&[BtrfsItem]> {
     let header = parse_btrfs_header(buf)?;
-    let mut offset = std::mem::size_of::<BtrfsHeader>();
-    let mut items = Vec::new();
-    for _ in 0..header.nritems {
-        items.push(unsafe { &*(buf.as_ptr().add(offset) as *const BtrfsItem) });
-        offset += std::mem::size_of::<BtrfsItem>();
-    }
-
-    Ok(items)
+    parse::read_array(buf, size_of::<BtrfsHeader>(), header.nritems.get() as usize)
+        .with_context(|| format!("nritems={} doesn't fit in leaf", header.nritems))
+}
+
+/// Parse the payload of `item`, which lives in `leaf`, as a `T`
+pub fn parse_item<'a, T: parse::OnDisk>(leaf: &'a [u8], item: &BtrfsItem) -> Result<&'a T> {
+    let data = item_data(leaf, item)?;
+    parse::read(data, 0)
+        .with_context(|| format!("Item payload too small for key type={}", item.key.ty))
+}
+
+/// The payload of `item`, which lives in `leaf`
+pub fn item_data<'a>(leaf: &'a [u8], item: &BtrfsItem) -> Result<&'a [u8]> {
+    // `item.offset` is relative to the end of the header
+    let begin = size_of::<BtrfsHeader>() + item.offset.get() as usize;
+    parse::read_array(leaf, begin, item.size.get() as usize).with_context(|| {
+        format!(
+            "Item payload offset={} size={} is out of bounds",
+            item.offset, item.size
+        )
+    })
 }
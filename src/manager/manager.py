@@ -12,6 +12,14 @@ import pexpect
 MASTER_NAME = "master"
 KERNEL_PANIC_STR = "Kernel panic"
 
+# Per test case timeout. The runner enforces this itself so that a test case
+# stuck in the kernel doesn't wedge the fuzzer.
+TIMEOUT_MS = 20000
+
+# Values the kernel compared against, learned by the runner and spliced into
+# fields by the custom mutator. Each VM keeps its own.
+DICTIONARY_PATH = "/tmp/btrfs-fuzz-dictionary"
+
 
 def get_secondary_name(idx):
     return f"secondary_{idx}"
@@ -30,6 +38,7 @@ def get_cmd_env_vars():
     # ineffective
     e.append("AFL_CUSTOM_MUTATOR_LIBRARY=/btrfs-fuzz/libmutator.so")
     e.append("AFL_CUSTOM_MUTATOR_ONLY=1")
+    e.append(f"BTRFS_FUZZ_DICTIONARY={DICTIONARY_PATH}")
 
     # The custom mutator doesn't append or delete bytes. Trimming also messes
     # with deserializing input so, don't trim.
@@ -60,6 +69,7 @@ def get_cmd_args(master=False, secondary=None):
     c.append("-m 500")
     c.append("-i /state/input")
     c.append("-o /state/output")
+    c.append(f"-t {TIMEOUT_MS}")
 
     # See bottom of
     # https://github.com/AFLplusplus/AFLplusplus/blob/stable/docs/power_schedules.md
@@ -76,6 +86,29 @@ def get_cmd_args(master=False, secondary=None):
     c.append("--")
     c.append("/btrfs-fuzz/runner")
 
+    # Save crash signatures so crashes can be bucketed later
+    c.append("--signature-dir /state/signatures")
+    c.append("--btrfs-symbols /btrfs-fuzz/btrfs-symbols")
+
+    # Give every btrfs basic block its own slot in the coverage map
+    c.append("--vmlinux /btrfs-fuzz/vmlinux")
+
+    # Same timeout as AFL so hangs are reported as such
+    c.append(f"--timeout {TIMEOUT_MS}")
+
+    # Learn comparison operands for the custom mutator
+    c.append(f"--cmp-dictionary {DICTIONARY_PATH}")
+
+    # Keep runner stats next to AFL's fuzzer_stats. AFL names the output
+    # directory of a sole instance "default".
+    if master:
+        name = MASTER_NAME
+    elif secondary is not None:
+        name = get_secondary_name(secondary)
+    else:
+        name = "default"
+    c.append(f"--stats-file /state/output/{name}/runner_stats")
+
     return c
 
 
@@ -1,18 +1,44 @@
 use std::boxed::Box;
+use std::env;
+use std::path::PathBuf;
 use std::ptr;
 use std::slice;
 
 use anyhow::Result;
 use fuzzmutator::mutator::MutatorEngine;
-use rmp_serde::{decode::from_read_ref, Serializer};
-use serde::Serialize;
-
-use imgcompress::CompressedBtrfsImage;
+use rand::Rng;
+use testcase::{Dictionary, Testcase};
+
+mod mount_options;
+mod program;
+mod structured;
+
+/// 1 in `BYTE_MUTATION_CHANCE` mutations are done at the byte level instead of being
+/// structure-aware
+const BYTE_MUTATION_CHANCE: u32 = 4;
+/// 1 in `PROGRAM_MUTATION_CHANCE` mutations change the test case's program instead of its image
+const PROGRAM_MUTATION_CHANCE: u32 = 4;
+/// 1 in `MOUNT_OPTIONS_MUTATION_CHANCE` mutations change how the test case's image is mounted
+const MOUNT_OPTIONS_MUTATION_CHANCE: u32 = 16;
+/// 1 in `DICTIONARY_MUTATION_CHANCE` structure-aware mutations splice in a value the kernel was
+/// seen comparing against
+const DICTIONARY_MUTATION_CHANCE: u32 = 4;
+/// The dictionary is reloaded every `DICTIONARY_RELOAD_INTERVAL` mutations to pick up what the
+/// runner learned
+const DICTIONARY_RELOAD_INTERVAL: u64 = 10_000;
+/// Environment variable holding the path the runner saves its dictionary to
+/// (`runner --cmp-dictionary`)
+const DICTIONARY_ENV: &str = "BTRFS_FUZZ_DICTIONARY";
 
 struct Mutator {
     engine: MutatorEngine,
     /// We'll return pointers to data in this buffer from `afl_custom_fuzz`
     fuzz_buf: Vec<u8>,
+    dictionary_path: Option<PathBuf>,
+    /// Values out of the dictionary at `dictionary_path`
+    dictionary: Vec<u64>,
+    /// Number of mutations done so far
+    nr_mutations: u64,
 }
 
 impl Mutator {
@@ -20,8 +46,29 @@ impl Mutator {
         Ok(Self {
             engine: MutatorEngine::new()?,
             fuzz_buf: Vec::new(),
+            dictionary_path: env::var_os(DICTIONARY_ENV).map(PathBuf::from),
+            dictionary: Vec::new(),
+            nr_mutations: 0,
         })
     }
+
+    /// Reload the dictionary every so often. Keeps using the old one if the reload fails.
+    fn maybe_reload_dictionary(&mut self) {
+        let path = match &self.dictionary_path {
+            Some(p) => p,
+            None => return,
+        };
+
+        self.nr_mutations += 1;
+        if self.nr_mutations % DICTIONARY_RELOAD_INTERVAL != 1 {
+            return;
+        }
+
+        match Dictionary::load(path) {
+            Ok(d) => self.dictionary = d.values(),
+            Err(e) => eprintln!("Failed to reload dictionary: {}", e),
+        }
+    }
 }
 
 /// Initialize this custom mutator
@@ -68,6 +115,7 @@ pub extern "C" fn afl_custom_init(
 ///     produce data larger than max_size.
 /// @return Size of the mutated output.
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn afl_custom_fuzz(
     data: *mut libc::c_void,
     buf: *mut u8,
@@ -81,7 +129,7 @@ pub extern "C" fn afl_custom_fuzz(
 
     // Deserialize input
     let serialized: &[u8] = unsafe { slice::from_raw_parts(buf, buf_size) };
-    let mut deserialized: CompressedBtrfsImage = match from_read_ref(&serialized) {
+    let mut deserialized = match Testcase::decode(serialized) {
         Ok(d) => d,
         Err(e) => {
             eprintln!("Failed to deserialize fuzzer input: {}", e);
@@ -90,14 +138,30 @@ pub extern "C" fn afl_custom_fuzz(
         }
     };
 
-    // Mutate payload (but don't touch the metadata)
-    mutator.engine.mutate(&mut deserialized.data);
-    // The engine shouldn't append any data but it's probably worthwhile to check again
-    assert!(deserialized.data.len() + deserialized.metadata.len() <= max_size);
+    mutator.maybe_reload_dictionary();
+
+    let rng = mutator.engine.borrow_rng();
+    if rng.gen_range(0, MOUNT_OPTIONS_MUTATION_CHANCE) == 0 {
+        mount_options::mutate(&mut deserialized.mount_options, rng);
+    } else if rng.gen_range(0, PROGRAM_MUTATION_CHANCE) == 0 {
+        program::mutate(&mut deserialized.program, rng);
+    } else {
+        // Mutate image payload (but don't touch the metadata). Prefer structure-aware mutations
+        // but fall back to byte level mutations every so often, or if there's nothing structured
+        // to mutate.
+        let image = &mut deserialized.image;
+        let byte_level = rng.gen_range(0, BYTE_MUTATION_CHANCE) == 0;
+        let spliced = !byte_level
+            && rng.gen_range(0, DICTIONARY_MUTATION_CHANCE) == 0
+            && structured::splice(image, &mutator.dictionary, rng);
+        if !spliced && (byte_level || !structured::mutate(image, rng)) {
+            mutator.engine.mutate(&mut image.data);
+        }
+    }
 
     // Serialize data again
     mutator.fuzz_buf.clear(); // Does not affect capacity
-    match deserialized.serialize(&mut Serializer::new(&mut mutator.fuzz_buf)) {
+    match deserialized.encode(&mut mutator.fuzz_buf) {
         Ok(_) => (),
         Err(e) => {
             eprintln!("Failed to serialize fuzzer input: {}", e);
@@ -105,7 +169,16 @@ pub extern "C" fn afl_custom_fuzz(
             return 0;
         }
     };
-    assert!(mutator.fuzz_buf.len() <= max_size);
+
+    // Mutations can grow the serialized test case, eg by adding operations to the program or by
+    // turning small integers into ones that take more bytes to encode. Drop the mutation and hand
+    // back the input rather than give afl more than it asked for.
+    if mutator.fuzz_buf.len() > max_size {
+        mutator.fuzz_buf.clear();
+        mutator
+            .fuzz_buf
+            .extend_from_slice(&serialized[..serialized.len().min(max_size)]);
+    }
 
     // Yes, it's ok to hand out ref to the Vec we own. The API is designed this way
     unsafe { out_buf.write(mutator.fuzz_buf.as_mut_ptr()) };
@@ -119,7 +192,7 @@ pub extern "C" fn afl_custom_fuzz(
 #[no_mangle]
 pub extern "C" fn afl_custom_deinit(data: *mut libc::c_void) {
     // Reconstruct box and immediately drop to free resources
-    unsafe { Box::from_raw(data as *mut Mutator) };
+    drop(unsafe { Box::from_raw(data as *mut Mutator) });
 }
 
 /// Not confident that the 3rd party mutator works. Let's just make sure it seems sane.
@@ -0,0 +1,35 @@
+use rand::Rng;
+
+use testcase::{MountOptions, MOUNT_FLAGS, MOUNT_OPTIONS};
+
+/// Flip a single mount option or flag
+pub fn mutate<R: Rng>(mount_options: &mut MountOptions, rng: &mut R) {
+    let idx = rng.gen_range(0, MOUNT_OPTIONS.len() + MOUNT_FLAGS.len());
+    if idx < MOUNT_OPTIONS.len() {
+        mount_options.options ^= 1 << idx;
+    } else {
+        mount_options.flags ^= 1 << (idx - MOUNT_OPTIONS.len());
+    }
+}
+
+#[test]
+fn test_mount_options_mutate() {
+    let mut rng = rand::thread_rng();
+    let mut mount_options = MountOptions::default();
+
+    let mut seen = MountOptions::default();
+    for _ in 0..10_000 {
+        let prev = mount_options;
+        mutate(&mut mount_options, &mut rng);
+        assert_ne!(mount_options, prev);
+        assert!(mount_options.options < 1 << MOUNT_OPTIONS.len());
+        assert!(mount_options.flags < 1 << MOUNT_FLAGS.len());
+
+        seen.options |= mount_options.options;
+        seen.flags |= mount_options.flags;
+    }
+
+    // Everything gets turned on eventually
+    assert_eq!(seen.option_names().len(), MOUNT_OPTIONS.len());
+    assert_eq!(seen.flag_names().len(), MOUNT_FLAGS.len());
+}
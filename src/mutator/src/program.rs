@@ -0,0 +1,191 @@
+use rand::Rng;
+
+use testcase::{Op, Program, NR_FILES};
+
+/// Programs aren't grown past this many ops
+const MAX_PROGRAM_LEN: usize = 64;
+
+/// Interesting offsets and lengths for file ops
+const SIZES: &[u64] = &[
+    0,
+    1,
+    511,
+    512,
+    4095,
+    4096,
+    4097,
+    65536,
+    128 << 10,
+    1 << 20,
+    i64::MAX as u64,
+    u64::MAX,
+];
+
+/// Number of variants in `Op`
+const NR_OP_KINDS: u32 = 23;
+
+fn file<R: Rng>(rng: &mut R) -> u64 {
+    rng.gen_range(0, NR_FILES)
+}
+
+fn size<R: Rng>(rng: &mut R) -> u64 {
+    if rng.gen() {
+        SIZES[rng.gen_range(0, SIZES.len())]
+    } else {
+        rng.gen_range(0, 1 << 20)
+    }
+}
+
+/// Generate a random op with random arguments
+fn random_op<R: Rng>(rng: &mut R) -> Op {
+    match rng.gen_range(0, NR_OP_KINDS) {
+        0 => Op::Mkdir,
+        1 => Op::Write {
+            file: file(rng),
+            offset: size(rng),
+            len: size(rng),
+        },
+        2 => Op::Read { file: file(rng) },
+        3 => Op::Fsync { file: file(rng) },
+        4 => Op::Truncate {
+            file: file(rng),
+            len: size(rng),
+        },
+        5 => Op::Fallocate {
+            file: file(rng),
+            offset: size(rng),
+            len: size(rng),
+        },
+        6 => Op::PunchHole {
+            file: file(rng),
+            offset: size(rng),
+            len: size(rng),
+        },
+        7 => Op::Rename {
+            from: file(rng),
+            to: file(rng),
+        },
+        8 => Op::Link {
+            from: file(rng),
+            to: file(rng),
+        },
+        9 => Op::Unlink { file: file(rng) },
+        10 => Op::Setxattr { file: file(rng) },
+        11 => Op::Listxattr { file: file(rng) },
+        12 => Op::Removexattr { file: file(rng) },
+        13 => Op::Compress { file: file(rng) },
+        14 => Op::Reflink {
+            from: file(rng),
+            to: file(rng),
+        },
+        15 => Op::Readdir,
+        16 => Op::Subvol,
+        17 => Op::Snapshot {
+            readonly: rng.gen(),
+        },
+        18 => Op::Send,
+        19 => Op::Sync,
+        20 => Op::Scrub,
+        21 => Op::Balance,
+        _ => Op::ReadAll,
+    }
+}
+
+/// Change a single argument of `op`. Ops without arguments are replaced with a random op.
+fn mutate_op<R: Rng>(op: &mut Op, rng: &mut R) {
+    match op {
+        Op::Write {
+            file: f,
+            offset,
+            len,
+        }
+        | Op::Fallocate {
+            file: f,
+            offset,
+            len,
+        }
+        | Op::PunchHole {
+            file: f,
+            offset,
+            len,
+        } => match rng.gen_range(0, 3) {
+            0 => *f = file(rng),
+            1 => *offset = size(rng),
+            _ => *len = size(rng),
+        },
+        Op::Truncate { file: f, len } => {
+            if rng.gen() {
+                *f = file(rng);
+            } else {
+                *len = size(rng);
+            }
+        }
+        Op::Read { file: f }
+        | Op::Fsync { file: f }
+        | Op::Unlink { file: f }
+        | Op::Setxattr { file: f }
+        | Op::Listxattr { file: f }
+        | Op::Removexattr { file: f }
+        | Op::Compress { file: f } => *f = file(rng),
+        Op::Rename { from, to } | Op::Link { from, to } | Op::Reflink { from, to } => {
+            if rng.gen() {
+                *from = file(rng);
+            } else {
+                *to = file(rng);
+            }
+        }
+        Op::Snapshot { readonly } => *readonly = !*readonly,
+        _ => *op = random_op(rng),
+    }
+}
+
+/// Apply a single mutation to `program`: insert, remove, swap or change an op
+pub fn mutate<R: Rng>(program: &mut Program, rng: &mut R) {
+    let ops = &mut program.ops;
+
+    match rng.gen_range(0, 4) {
+        0 if ops.len() < MAX_PROGRAM_LEN => {
+            let idx = rng.gen_range(0, ops.len() + 1);
+            ops.insert(idx, random_op(rng));
+        }
+        1 if !ops.is_empty() => {
+            let idx = rng.gen_range(0, ops.len());
+            ops.remove(idx);
+        }
+        2 if ops.len() >= 2 => {
+            let a = rng.gen_range(0, ops.len());
+            let b = rng.gen_range(0, ops.len());
+            ops.swap(a, b);
+        }
+        _ if !ops.is_empty() => {
+            let idx = rng.gen_range(0, ops.len());
+            mutate_op(&mut ops[idx], rng);
+        }
+        _ => ops.push(random_op(rng)),
+    }
+}
+
+#[test]
+fn test_program_mutate() {
+    let mut rng = rand::thread_rng();
+    let orig = Program::default();
+    let mut program = orig.clone();
+
+    let mut changed = false;
+    for _ in 0..10_000 {
+        mutate(&mut program, &mut rng);
+        assert!(program.ops.len() <= MAX_PROGRAM_LEN.max(orig.ops.len()));
+        assert!(program.ops.iter().all(|op| match op {
+            Op::Write { file, .. } | Op::Unlink { file } => *file < NR_FILES,
+            Op::Rename { from, to } => *from < NR_FILES && *to < NR_FILES,
+            _ => true,
+        }));
+        changed |= program != orig;
+    }
+    assert!(changed);
+
+    // Empty programs get an op
+    let mut empty = Program { ops: Vec::new() };
+    mutate(&mut empty, &mut rng);
+    assert_eq!(empty.ops.len(), 1);
+}
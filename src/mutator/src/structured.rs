@@ -0,0 +1,580 @@
+use std::mem::size_of;
+use std::ops::Range;
+
+use rand::Rng;
+
+use imgcompress::parse::{self, OnDisk};
+use imgcompress::structs::*;
+use imgcompress::{CompressedBtrfsImage, StructKind};
+
+/// Interesting values to set integer fields to. Narrower fields use the truncated values.
+const BOUNDARY_VALUES: &[u64] = &[
+    0,
+    1,
+    2,
+    0x7F,
+    0x80,
+    0xFF,
+    0x100,
+    0x1000,
+    0x4000,
+    0x7FFF,
+    0x8000,
+    0xFFFF,
+    0x10000,
+    0x7FFF_FFFF,
+    0x8000_0000,
+    0xFFFF_FFFF,
+    0x1_0000_0000,
+    i64::MAX as u64,
+    1 << 63,
+    u64::MAX - 1,
+    u64::MAX,
+];
+
+/// Node header fields the runner restores on decompress unless told otherwise. Mutating them
+/// would be wasted work.
+const RESTORED_HEADER_FIELDS: [&str; 4] = ["bytenr", "fsid", "chunk_tree_uuid", "generation"];
+
+/// Key types we'll swap item keys to
+const KEY_TYPES: &[u8] = &[
+    BTRFS_INODE_ITEM_KEY,
+    BTRFS_INODE_REF_KEY,
+    BTRFS_INODE_EXTREF_KEY,
+    BTRFS_XATTR_ITEM_KEY,
+    BTRFS_ORPHAN_ITEM_KEY,
+    BTRFS_DIR_LOG_ITEM_KEY,
+    BTRFS_DIR_LOG_INDEX_KEY,
+    BTRFS_DIR_ITEM_KEY,
+    BTRFS_DIR_INDEX_KEY,
+    BTRFS_EXTENT_DATA_KEY,
+    BTRFS_EXTENT_CSUM_KEY,
+    BTRFS_ROOT_ITEM_KEY,
+    BTRFS_ROOT_BACKREF_KEY,
+    BTRFS_ROOT_REF_KEY,
+    BTRFS_EXTENT_ITEM_KEY,
+    BTRFS_METADATA_ITEM_KEY,
+    BTRFS_TREE_BLOCK_REF_KEY,
+    BTRFS_EXTENT_DATA_REF_KEY,
+    BTRFS_SHARED_BLOCK_REF_KEY,
+    BTRFS_SHARED_DATA_REF_KEY,
+    BTRFS_BLOCK_GROUP_ITEM_KEY,
+    BTRFS_FREE_SPACE_INFO_KEY,
+    BTRFS_FREE_SPACE_EXTENT_KEY,
+    BTRFS_FREE_SPACE_BITMAP_KEY,
+    BTRFS_DEV_EXTENT_KEY,
+    BTRFS_DEV_ITEM_KEY,
+    BTRFS_CHUNK_ITEM_KEY,
+    BTRFS_QGROUP_STATUS_KEY,
+    BTRFS_QGROUP_INFO_KEY,
+    BTRFS_QGROUP_LIMIT_KEY,
+    BTRFS_QGROUP_RELATION_KEY,
+    BTRFS_PERSISTENT_ITEM_KEY,
+    BTRFS_DEV_REPLACE_KEY,
+    BTRFS_UUID_KEY_SUBVOL,
+    BTRFS_UUID_KEY_RECEIVED_SUBVOL,
+    BTRFS_STRING_ITEM_KEY,
+];
+
+/// File type bits in `BtrfsInodeItem::mode`. See inode(7).
+const S_IFMT: u32 = 0o170000;
+const S_IFMT_TYPES: &[u32] = &[
+    0o140000, 0o120000, 0o100000, 0o060000, 0o040000, 0o020000, 0o010000,
+];
+
+/// A tree node annotated in `CompressedBtrfsImage::data`
+struct Node {
+    /// Logical address as recorded in the node header
+    bytenr: u64,
+    /// Physical address of the node in the decompressed image
+    physical: u64,
+    level: u8,
+    nritems: u32,
+    /// Range in `data` holding the header and the item (or key ptr) array
+    header: Range<usize>,
+    /// Range in `data` holding leaf payloads along with the physical address the range starts at
+    payload: Option<(Range<usize>, u64)>,
+}
+
+impl Node {
+    /// Number of items (or key ptrs) that actually fit in the annotated header range
+    fn nr_entries(&self, entry_size: usize) -> usize {
+        let fits = (self.header.len() - size_of::<BtrfsHeader>()) / entry_size;
+        fits.min(self.nritems as usize)
+    }
+
+    /// Offset in `data` of the `idx`th item or key ptr
+    fn entry_offset(&self, idx: usize, entry_size: usize) -> usize {
+        self.header.start + size_of::<BtrfsHeader>() + idx * entry_size
+    }
+
+    /// Offset in `data` of a leaf item's payload, if the whole payload was annotated
+    fn payload_offset(&self, item: &BtrfsItem, len: usize) -> Option<usize> {
+        let (range, start) = self.payload.as_ref()?;
+        let physical =
+            self.physical + size_of::<BtrfsHeader>() as u64 + u64::from(item.offset.get());
+        if physical < *start {
+            return None;
+        }
+
+        let offset = range.start + (physical - *start) as usize;
+        if offset + len > range.end {
+            return None;
+        }
+
+        Some(offset)
+    }
+}
+
+fn read<T: OnDisk>(data: &[u8], offset: usize) -> Option<T> {
+    parse::read(data, offset).ok().copied()
+}
+
+#[cfg(test)]
+fn write<T: OnDisk>(data: &mut [u8], offset: usize, val: T) {
+    *parse::read_mut(data, offset).unwrap() = val;
+}
+
+/// Let `f` change the `T` at `offset` in place
+fn modify<T: OnDisk, F: FnOnce(&mut T)>(data: &mut [u8], offset: usize, f: F) -> bool {
+    match parse::read_mut::<T>(data, offset) {
+        Ok(val) => {
+            f(val);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn boundary<R: Rng>(rng: &mut R) -> u64 {
+    BOUNDARY_VALUES[rng.gen_range(0, BOUNDARY_VALUES.len())]
+}
+
+fn key_type<R: Rng>(rng: &mut R) -> u8 {
+    KEY_TYPES[rng.gen_range(0, KEY_TYPES.len())]
+}
+
+/// Find every tree node annotated in `image`
+fn find_nodes(image: &CompressedBtrfsImage) -> Vec<Node> {
+    let mut nodes: Vec<Node> = Vec::new();
+
+    for (metadata, range) in image.extents() {
+        if metadata.is_data {
+            continue;
+        }
+
+        if metadata.needs_csum_fixup {
+            if metadata.is_superblock() {
+                continue;
+            }
+
+            let header: BtrfsHeader = match read(&image.data, range.start) {
+                Some(h) if range.len() >= size_of::<BtrfsHeader>() => h,
+                _ => continue,
+            };
+
+            nodes.push(Node {
+                bytenr: header.bytenr.get(),
+                physical: metadata.offset,
+                level: header.level,
+                nritems: header.nritems.get(),
+                header: range,
+                payload: None,
+            });
+        } else {
+            // A payload belongs to the closest node header in front of it
+            let owner = nodes
+                .iter_mut()
+                .filter(|n| {
+                    n.physical < metadata.offset
+                        && metadata.offset - n.physical < BTRFS_MAX_METADATA_BLOCKSIZE as u64
+                })
+                .max_by_key(|n| n.physical);
+            if let Some(node) = owner {
+                node.payload = Some((range, metadata.offset));
+            }
+        }
+    }
+
+    nodes
+}
+
+/// Apply a single structure-aware mutation to `image`.
+///
+/// A tree node is picked at random and one semantically meaningful field in it (or one of its
+/// items) is changed. Returns false if `image` has no nodes to mutate, in which case the caller
+/// should fall back to some other mutation.
+pub fn mutate<R: Rng>(image: &mut CompressedBtrfsImage, rng: &mut R) -> bool {
+    let nodes = find_nodes(image);
+    if nodes.is_empty() {
+        return false;
+    }
+
+    let node = &nodes[rng.gen_range(0, nodes.len())];
+
+    // Header mutations are mostly caught by the tree checker so don't do them too often
+    if rng.gen_range(0, 8) == 0 {
+        return mutate_header(image, node, rng);
+    }
+
+    if node.level == 0 {
+        mutate_leaf(image, node, rng)
+    } else {
+        mutate_internal(image, node, &nodes, rng)
+    }
+}
+
+/// Leaves the fields in `RESTORED_HEADER_FIELDS` alone
+fn mutate_header<R: Rng>(image: &mut CompressedBtrfsImage, node: &Node, rng: &mut R) -> bool {
+    let choice = rng.gen_range(0, 3);
+    modify(
+        &mut image.data,
+        node.header.start,
+        |h: &mut BtrfsHeader| match choice {
+            0 => h.nritems.set(boundary(rng) as u32),
+            1 => h.level = rng.gen_range(0, BTRFS_MAX_LEVEL + 1),
+            _ => h.owner.set(boundary(rng)),
+        },
+    )
+}
+
+fn mutate_key<R: Rng>(key: &mut BtrfsKey, rng: &mut R) {
+    match rng.gen_range(0, 3) {
+        0 => key.ty = key_type(rng),
+        1 => key.objectid.set(boundary(rng)),
+        _ => key.offset.set(boundary(rng)),
+    }
+}
+
+fn mutate_leaf<R: Rng>(image: &mut CompressedBtrfsImage, node: &Node, rng: &mut R) -> bool {
+    let item_size = size_of::<BtrfsItem>();
+    let nr = node.nr_entries(item_size);
+    if nr == 0 {
+        return mutate_header(image, node, rng);
+    }
+
+    let item_offset = node.entry_offset(rng.gen_range(0, nr), item_size);
+    let item: BtrfsItem = match read(&image.data, item_offset) {
+        Some(i) => i,
+        None => return false,
+    };
+
+    match rng.gen_range(0, 4) {
+        0 => modify(&mut image.data, item_offset, |i: &mut BtrfsItem| {
+            mutate_key(&mut i.key, rng)
+        }),
+        1 => modify(&mut image.data, item_offset, |i: &mut BtrfsItem| {
+            if rng.gen() {
+                i.size.set(boundary(rng) as u32);
+            } else {
+                i.offset.set(boundary(rng) as u32);
+            }
+        }),
+        _ => {
+            if mutate_payload(image, node, &item, rng) {
+                true
+            } else {
+                // No payload mutations for this item type, so just change the key
+                modify(&mut image.data, item_offset, |i: &mut BtrfsItem| {
+                    mutate_key(&mut i.key, rng)
+                })
+            }
+        }
+    }
+}
+
+fn mutate_inode_item<R: Rng>(inode: &mut BtrfsInodeItem, rng: &mut R) {
+    match rng.gen_range(0, 6) {
+        0 => {
+            let ty = S_IFMT_TYPES[rng.gen_range(0, S_IFMT_TYPES.len())];
+            inode.mode.set((inode.mode.get() & !S_IFMT) | ty);
+        }
+        1 => inode
+            .mode
+            .set(inode.mode.get() ^ (1 << rng.gen_range(0, 12))),
+        2 => inode.size.set(boundary(rng)),
+        3 => inode.nbytes.set(boundary(rng)),
+        4 => inode.nlink.set(boundary(rng) as u32),
+        _ => inode
+            .flags
+            .set(inode.flags.get() ^ (1 << rng.gen_range(0, 64))),
+    }
+}
+
+fn mutate_payload<R: Rng>(
+    image: &mut CompressedBtrfsImage,
+    node: &Node,
+    item: &BtrfsItem,
+    rng: &mut R,
+) -> bool {
+    let data = &mut image.data;
+
+    match item.key.ty {
+        BTRFS_INODE_ITEM_KEY => {
+            let offset = match node.payload_offset(item, size_of::<BtrfsInodeItem>()) {
+                Some(o) => o,
+                None => return false,
+            };
+            modify(data, offset, |inode: &mut BtrfsInodeItem| {
+                mutate_inode_item(inode, rng)
+            })
+        }
+        BTRFS_ROOT_ITEM_KEY => {
+            let offset = match node.payload_offset(item, size_of::<BtrfsRootItem>()) {
+                Some(o) => o,
+                None => return false,
+            };
+            modify(data, offset, |root: &mut BtrfsRootItem| {
+                match rng.gen_range(0, 6) {
+                    0 => root.bytenr.set(boundary(rng)),
+                    1 => root.level = rng.gen_range(0, BTRFS_MAX_LEVEL + 1),
+                    2 => root.refs.set(boundary(rng) as u32),
+                    3 => root.generation.set(boundary(rng)),
+                    4 => root.drop_level = rng.gen_range(0, BTRFS_MAX_LEVEL + 1),
+                    _ => mutate_inode_item(&mut root.inode, rng),
+                }
+            })
+        }
+        BTRFS_DIR_ITEM_KEY | BTRFS_DIR_INDEX_KEY | BTRFS_XATTR_ITEM_KEY => {
+            let offset = match node.payload_offset(item, size_of::<BtrfsDirItem>()) {
+                Some(o) => o,
+                None => return false,
+            };
+            modify(data, offset, |dir: &mut BtrfsDirItem| {
+                match rng.gen_range(0, 5) {
+                    0 => mutate_key(&mut dir.location, rng),
+                    1 => dir.name_len.set(boundary(rng) as u16),
+                    2 => dir.data_len.set(boundary(rng) as u16),
+                    3 => dir.ty = rng.gen_range(0, 10),
+                    _ => dir.transid.set(boundary(rng)),
+                }
+            })
+        }
+        BTRFS_CHUNK_ITEM_KEY => {
+            let offset = match node.payload_offset(item, size_of::<BtrfsChunk>()) {
+                Some(o) => o,
+                None => return false,
+            };
+            modify(data, offset, |chunk: &mut BtrfsChunk| {
+                match rng.gen_range(0, 6) {
+                    0 => chunk.length.set(boundary(rng)),
+                    1 => chunk.stripe_len.set(boundary(rng)),
+                    2 => chunk.num_stripes.set(boundary(rng) as u16),
+                    3 => chunk.sub_stripes.set(boundary(rng) as u16),
+                    4 => chunk.ty.set(chunk.ty.get() ^ (1 << rng.gen_range(0, 11))),
+                    _ => {
+                        if rng.gen() {
+                            chunk.stripe.devid.set(boundary(rng));
+                        } else {
+                            chunk.stripe.offset.set(boundary(rng));
+                        }
+                    }
+                }
+            })
+        }
+        _ => false,
+    }
+}
+
+fn mutate_internal<R: Rng>(
+    image: &mut CompressedBtrfsImage,
+    node: &Node,
+    nodes: &[Node],
+    rng: &mut R,
+) -> bool {
+    let ptr_size = size_of::<BtrfsKeyPtr>();
+    let nr = node.nr_entries(ptr_size);
+    if nr == 0 {
+        return mutate_header(image, node, rng);
+    }
+
+    let ptr_offset = node.entry_offset(rng.gen_range(0, nr), ptr_size);
+    let other = nodes[rng.gen_range(0, nodes.len())].bytenr;
+    modify(&mut image.data, ptr_offset, |p: &mut BtrfsKeyPtr| {
+        match rng.gen_range(0, 4) {
+            // Point at some other node in the image so we get loops, level mismatches, etc.
+            0 => p.blockptr.set(other),
+            1 => p.blockptr.set(boundary(rng)),
+            2 => p.generation.set(boundary(rng)),
+            _ => mutate_key(&mut p.key, rng),
+        }
+    })
+}
+
+/// Overwrite a random field with a value out of `dictionary`. Narrower fields get the truncated
+/// value and arrays get a single element overwritten.
+///
+/// Returns false if `image` has no fields to splice into.
+pub fn splice<R: Rng>(image: &mut CompressedBtrfsImage, dictionary: &[u64], rng: &mut R) -> bool {
+    if dictionary.is_empty() {
+        return false;
+    }
+
+    let fields: Vec<(u64, u64, usize)> = image
+        .fields
+        .iter()
+        .flatten()
+        .filter(|f| matches!(f.width, 1 | 2 | 4 | 8) && f.size >= u64::from(f.width))
+        .filter(|f| {
+            f.kind != StructKind::Header || !RESTORED_HEADER_FIELDS.contains(&f.name.as_str())
+        })
+        .map(|f| (f.offset, f.size, f.width as usize))
+        .collect();
+    if fields.is_empty() {
+        return false;
+    }
+
+    let (offset, size, width) = fields[rng.gen_range(0, fields.len())];
+    let elem = rng.gen_range(0, size / width as u64);
+    let begin = (offset + elem * width as u64) as usize;
+    let val = dictionary[rng.gen_range(0, dictionary.len())];
+
+    match image.data.get_mut(begin..begin + width) {
+        Some(field) => {
+            field.copy_from_slice(&val.to_le_bytes()[..width]);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+fn test_image() -> CompressedBtrfsImage {
+    use imgcompress::MetadataExtent;
+
+    const NODE_PHYSICAL: u64 = 1 << 20;
+    const NODE_SIZE: usize = 4096;
+
+    let mut image = CompressedBtrfsImage::default();
+
+    // Leaf with a single inode item
+    let mut node = vec![0; NODE_SIZE];
+    let header_size = size_of::<BtrfsHeader>();
+    let item_size = size_of::<BtrfsItem>();
+    let inode_size = size_of::<BtrfsInodeItem>();
+    let payload_offset = NODE_SIZE - header_size - inode_size;
+    write(
+        &mut node,
+        0,
+        BtrfsHeader {
+            csum: [0; BTRFS_CSUM_SIZE],
+            fsid: [0; 16],
+            bytenr: NODE_PHYSICAL.into(),
+            flags: 0.into(),
+            chunk_tree_uuid: [0; 16],
+            generation: 1.into(),
+            owner: 5.into(),
+            nritems: 1.into(),
+            level: 0,
+        },
+    );
+    write(
+        &mut node,
+        header_size,
+        BtrfsItem {
+            key: BtrfsKey {
+                objectid: 256.into(),
+                ty: BTRFS_INODE_ITEM_KEY,
+                offset: 0.into(),
+            },
+            offset: (payload_offset as u32).into(),
+            size: (inode_size as u32).into(),
+        },
+    );
+
+    let metadata_size = header_size + item_size;
+    image.metadata.push(MetadataExtent {
+        needs_csum_fixup: true,
+        offset: NODE_PHYSICAL,
+        size: metadata_size as u64,
+        ..Default::default()
+    });
+    image.data.extend_from_slice(&node[..metadata_size]);
+    image.metadata.push(MetadataExtent {
+        needs_csum_fixup: false,
+        offset: NODE_PHYSICAL + (header_size + payload_offset) as u64,
+        size: inode_size as u64,
+        ..Default::default()
+    });
+    image
+        .data
+        .extend_from_slice(&node[(header_size + payload_offset)..]);
+
+    image
+}
+
+#[test]
+fn test_find_nodes() {
+    let image = test_image();
+    let nodes = find_nodes(&image);
+
+    assert_eq!(nodes.len(), 1);
+    assert_eq!(nodes[0].nr_entries(size_of::<BtrfsItem>()), 1);
+
+    let item: BtrfsItem = read(
+        &image.data,
+        nodes[0].entry_offset(0, size_of::<BtrfsItem>()),
+    )
+    .expect("Failed to read item");
+    let payload = nodes[0]
+        .payload_offset(&item, size_of::<BtrfsInodeItem>())
+        .expect("Failed to locate payload");
+    assert_eq!(payload, nodes[0].header.end);
+}
+
+#[test]
+fn test_structured_mutate() {
+    let mut rng = rand::thread_rng();
+    let orig = test_image();
+
+    for _ in 0..1000 {
+        let mut image = test_image();
+        assert!(mutate(&mut image, &mut rng));
+        assert_eq!(image.data.len(), orig.data.len());
+    }
+
+    // Nothing to mutate in an empty image
+    let mut empty = CompressedBtrfsImage::default();
+    assert!(!mutate(&mut empty, &mut rng));
+}
+
+#[test]
+fn test_splice() {
+    use imgcompress::Field;
+
+    let mut rng = rand::thread_rng();
+    let mut image = test_image();
+    let dictionary = [0x1122_3344_5566_7788];
+
+    // No fields to splice into
+    image.fields = vec![Vec::new(); image.metadata.len()];
+    assert!(!splice(&mut image, &dictionary, &mut rng));
+
+    // Header fields restored on decompress aren't spliced into
+    image.fields[0].push(Field {
+        kind: StructKind::Header,
+        name: "generation".to_string(),
+        offset: 0x20,
+        size: 8,
+        width: 8,
+    });
+    assert!(!splice(&mut image, &dictionary, &mut rng));
+    image.fields[0].clear();
+
+    image.fields[0].push(Field {
+        kind: StructKind::Header,
+        name: "field".to_string(),
+        offset: 0x10,
+        size: 4,
+        width: 2,
+    });
+    assert!(!splice(&mut image, &[], &mut rng));
+    for _ in 0..100 {
+        assert!(splice(&mut image, &dictionary, &mut rng));
+    }
+
+    // Only the 2 byte wide elements of the field get touched
+    assert_eq!(image.data[0x10..0x14], [0x88, 0x77, 0x88, 0x77]);
+    assert_eq!(image.data[0x0f], 0);
+    assert_eq!(image.data[0x14], 0);
+}
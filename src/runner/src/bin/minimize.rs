@@ -0,0 +1,91 @@
+//! Minimize a crashing or hanging test case
+//!
+//! Reverts fuzzed metadata back to the pristine image one metadata extent, and then one field, at
+//! a time. Reverts are only kept if the crash signature still reproduces.
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use imgcompress::diff::explain;
+use structopt::StructOpt;
+use testcase::Testcase;
+
+use runner::minimize::minimize;
+use runner::repro::{is_raw_image, ReproOpts, Reproducer};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "minimize",
+    about = "Minimize a crashing or hanging btrfs-fuzz test case"
+)]
+struct Opt {
+    #[structopt(flatten)]
+    repro: ReproOpts,
+    /// Where to write the minimized test case
+    #[structopt(short, long, parse(from_os_str))]
+    output: PathBuf,
+    /// Crashing or hanging test case
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let opts = Opt::from_args();
+
+    let buf = fs::read(&opts.input)
+        .with_context(|| format!("Failed to read {}", opts.input.display()))?;
+    if is_raw_image(&buf) {
+        bail!("Raw images have no baseline to minimize against, pass a test case instead");
+    }
+    let mut testcase = Testcase::decode(&buf)?;
+    let baseline = testcase.image.baseline()?;
+    let id = testcase::id(&buf);
+
+    let mut reproducer = Reproducer::new(&opts.repro)?;
+    let image = reproducer.decompress(&testcase.image)?;
+    let key = match reproducer.run(&image, &testcase.mount_options, &testcase.program, &id)? {
+        (Some(sig), _) => sig.key,
+        (None, _) => bail!("{} does not crash or hang", opts.input.display()),
+    };
+    println!("Minimizing against signature {}", key);
+
+    let program = testcase.program.clone();
+    let mount_options = testcase.mount_options;
+    let mut runs = 0;
+    let changes = minimize(&mut testcase.image, &baseline, |image| {
+        runs += 1;
+
+        // Reverting can make an image undecompressable. That doesn't reproduce anything.
+        let image = match reproducer.decompress(image) {
+            Ok(i) => i,
+            Err(_) => return Ok(false),
+        };
+        let reproduced = match reproducer.run(&image, &mount_options, &program, &id)? {
+            (Some(sig), _) => sig.key == key,
+            (None, _) => false,
+        };
+
+        if opts.repro.debug {
+            println!("Run {}: reproduced={}", runs, reproduced);
+        }
+
+        Ok(reproduced)
+    })?;
+
+    let mut out = Vec::new();
+    testcase.encode(&mut out)?;
+    fs::write(&opts.output, out)
+        .with_context(|| format!("Failed to write {}", opts.output.display()))?;
+
+    println!(
+        "{} metadata changes left after {} runs:",
+        changes.len(),
+        runs
+    );
+    for line in explain(&testcase.image, &baseline, &testcase.image.data) {
+        println!("\t{}", line);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! Reproduce test cases outside of the fuzzer
+//!
+//! Runs each input through the same mount and workload path as the runner and reports whether
+//! the kernel crashed or hung. Exit status is one of `EXIT_NO_CRASH`, `EXIT_CRASH` or
+//! `EXIT_ERROR`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+#[cfg(test)]
+use serde_json::json;
+use structopt::StructOpt;
+use testcase::{Signature, Verdict};
+
+use runner::repro::{ReproOpts, Reproducer};
+
+/// No input crashed the kernel
+const EXIT_NO_CRASH: i32 = 0;
+/// At least one input crashed or hung the kernel
+const EXIT_CRASH: i32 = 1;
+/// Something went wrong before an input could be run
+const EXIT_ERROR: i32 = 2;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "repro",
+    about = "Reproduce btrfs-fuzz test cases",
+    after_help = "EXIT STATUS:
+    0    No input crashed the kernel
+    1    At least one input crashed or hung the kernel
+    2    Setup error, or an input could not be run and none crashed"
+)]
+struct Opt {
+    #[structopt(flatten)]
+    repro: ReproOpts,
+    /// Write a JSON summary of every run to this file
+    #[structopt(long, parse(from_os_str))]
+    summary: Option<PathBuf>,
+    /// Test case, raw btrfs image, or a directory of either (eg `output/crashes`)
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+}
+
+enum Outcome {
+    Crash(Signature),
+    /// Killed after running for longer than `--timeout`
+    Hang(Signature),
+    NoCrash,
+    /// The input could not be run
+    Error(String),
+}
+
+struct RunResult {
+    input: PathBuf,
+    outcome: Outcome,
+    /// Everything the kernel logged during the run
+    kmsg: Vec<String>,
+}
+
+/// Every input to run. Directories are expanded to the files directly inside them.
+fn collect_inputs(input: &Path) -> Result<Vec<PathBuf>> {
+    if !input.is_dir() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+
+    let mut inputs = Vec::new();
+    for entry in
+        fs::read_dir(input).with_context(|| format!("Failed to read {}", input.display()))?
+    {
+        let path = entry?.path();
+        // AFL drops a README.txt in the crash directory
+        if path.is_file() && path.file_name() != Some("README.txt".as_ref()) {
+            inputs.push(path);
+        }
+    }
+    inputs.sort();
+
+    Ok(inputs)
+}
+
+/// JSON summary of every run, written to `--summary`
+#[derive(Serialize)]
+struct Summary<'a> {
+    crashes: usize,
+    hangs: usize,
+    no_crashes: usize,
+    errors: usize,
+    runs: Vec<RunSummary<'a>>,
+}
+
+#[derive(Serialize)]
+struct RunSummary<'a> {
+    /// Lossy b/c JSON strings have to be valid unicode
+    input: String,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    kmsg: &'a [String],
+}
+
+impl<'a> Summary<'a> {
+    fn new(results: &'a [RunResult]) -> Self {
+        let count = |f: fn(&Outcome) -> bool| results.iter().filter(|r| f(&r.outcome)).count();
+
+        let runs = results
+            .iter()
+            .map(|result| {
+                let (status, signature, title, error) = match &result.outcome {
+                    Outcome::Crash(sig) => (
+                        "crash",
+                        Some(sig.key.as_str()),
+                        Some(sig.title.as_str()),
+                        None,
+                    ),
+                    Outcome::Hang(sig) => (
+                        "hang",
+                        Some(sig.key.as_str()),
+                        Some(sig.title.as_str()),
+                        None,
+                    ),
+                    Outcome::NoCrash => ("no-crash", None, None, None),
+                    Outcome::Error(e) => ("error", None, None, Some(e.as_str())),
+                };
+
+                RunSummary {
+                    input: result.input.to_string_lossy().into_owned(),
+                    status,
+                    signature,
+                    title,
+                    error,
+                    kmsg: &result.kmsg,
+                }
+            })
+            .collect();
+
+        Self {
+            crashes: count(|o| matches!(o, Outcome::Crash(_))),
+            hangs: count(|o| matches!(o, Outcome::Hang(_))),
+            no_crashes: count(|o| matches!(o, Outcome::NoCrash)),
+            errors: count(|o| matches!(o, Outcome::Error(_))),
+            runs,
+        }
+    }
+}
+
+fn _main(opts: Opt) -> Result<i32> {
+    let inputs = collect_inputs(&opts.input)?;
+    if inputs.is_empty() {
+        bail!("No inputs in {}", opts.input.display());
+    }
+
+    let mut repro = Reproducer::new(&opts.repro)?;
+
+    let mut results = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let (outcome, kmsg) = match repro.run_file(&input) {
+            Ok((Some(sig), kmsg)) if sig.verdict == Verdict::Hang => (Outcome::Hang(sig), kmsg),
+            Ok((Some(sig), kmsg)) => (Outcome::Crash(sig), kmsg),
+            Ok((None, kmsg)) => (Outcome::NoCrash, kmsg),
+            Err(e) => (Outcome::Error(format!("{:#}", e)), Vec::new()),
+        };
+
+        match &outcome {
+            Outcome::Crash(sig) => println!("{}: CRASH {}", input.display(), sig.key),
+            Outcome::Hang(sig) => println!("{}: HANG {}", input.display(), sig.key),
+            Outcome::NoCrash => println!("{}: no crash", input.display()),
+            Outcome::Error(e) => println!("{}: ERROR {}", input.display(), e),
+        }
+        if opts.repro.debug {
+            for message in &kmsg {
+                println!("\t{}", message);
+            }
+        }
+
+        results.push(RunResult {
+            input,
+            outcome,
+            kmsg,
+        });
+    }
+
+    if let Some(path) = &opts.summary {
+        let summary = serde_json::to_string_pretty(&Summary::new(&results))?;
+        fs::write(path, summary)
+            .with_context(|| format!("Failed to write summary {}", path.display()))?;
+    }
+
+    if results
+        .iter()
+        .any(|r| matches!(r.outcome, Outcome::Crash(_) | Outcome::Hang(_)))
+    {
+        Ok(EXIT_CRASH)
+    } else if results
+        .iter()
+        .any(|r| matches!(r.outcome, Outcome::Error(_)))
+    {
+        Ok(EXIT_ERROR)
+    } else {
+        Ok(EXIT_NO_CRASH)
+    }
+}
+
+fn main() {
+    // Bad arguments are a setup error too
+    let opts = match Opt::from_iter_safe(std::env::args_os()) {
+        Ok(o) => o,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            exit(EXIT_ERROR);
+        }
+        Err(e) => e.exit(),
+    };
+
+    match _main(opts) {
+        Ok(rc) => exit(rc),
+        Err(e) => {
+            eprintln!("Setup error: {:#}", e);
+            exit(EXIT_ERROR);
+        }
+    }
+}
+
+#[test]
+fn test_summary_json() {
+    let results = vec![
+        RunResult {
+            input: PathBuf::from("crashes/id:000000"),
+            outcome: Outcome::Crash(Signature {
+                key: "bug:btrfs_get_16".to_string(),
+                title: "kernel BUG at fs/btrfs/ctree.c:1234!".to_string(),
+                verdict: Verdict::Crash,
+            }),
+            kmsg: vec!["kernel BUG at fs/btrfs/ctree.c:1234!".to_string()],
+        },
+        RunResult {
+            input: PathBuf::from("hangs/id:000000"),
+            outcome: Outcome::Hang(Signature {
+                key: "hang".to_string(),
+                title: "test case timed out".to_string(),
+                verdict: Verdict::Hang,
+            }),
+            kmsg: Vec::new(),
+        },
+        RunResult {
+            input: PathBuf::from("crashes/id:000001"),
+            outcome: Outcome::Error("Failed to deserialize \"x\"\n".to_string()),
+            kmsg: Vec::new(),
+        },
+    ];
+
+    let summary = serde_json::to_value(Summary::new(&results)).unwrap();
+    assert_eq!(
+        summary,
+        json!({
+            "crashes": 1,
+            "hangs": 1,
+            "no_crashes": 0,
+            "errors": 1,
+            "runs": [
+                {
+                    "input": "crashes/id:000000",
+                    "status": "crash",
+                    "signature": "bug:btrfs_get_16",
+                    "title": "kernel BUG at fs/btrfs/ctree.c:1234!",
+                    "kmsg": ["kernel BUG at fs/btrfs/ctree.c:1234!"]
+                },
+                {
+                    "input": "hangs/id:000000",
+                    "status": "hang",
+                    "signature": "hang",
+                    "title": "test case timed out",
+                    "kmsg": []
+                },
+                {
+                    "input": "crashes/id:000001",
+                    "status": "error",
+                    "error": "Failed to deserialize \"x\"\n",
+                    "kmsg": []
+                }
+            ]
+        })
+    );
+}
@@ -0,0 +1,303 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use testcase::{Signature, Verdict};
+
+use crate::kmsg::{self, CrashClass, Report};
+
+/// Max number of btrfs frames that go into a crash signature
+const SIGNATURE_FRAMES: usize = 3;
+
+/// Functions defined in fs/btrfs
+pub struct BtrfsSymbols {
+    /// `None` if we don't have a symbol list, in which case we guess based on function names
+    symbols: Option<HashSet<String>>,
+}
+
+impl BtrfsSymbols {
+    /// Load symbols from a file with one function name per line.
+    ///
+    /// Generate one with something like:
+    ///
+    /// ```text
+    /// nm --line-numbers --defined-only vmlinux | awk '$4 ~ /fs\/btrfs\// { print $3 }'
+    /// ```
+    pub fn load<P: AsRef<Path>>(path: Option<P>) -> Result<Self> {
+        let symbols = match path {
+            Some(p) => {
+                let p = p.as_ref();
+                let content = fs::read_to_string(p)
+                    .with_context(|| format!("Failed to read btrfs symbols {}", p.display()))?;
+                Some(content.lines().map(|l| l.trim().to_string()).collect())
+            }
+            None => None,
+        };
+
+        Ok(Self { symbols })
+    }
+
+    fn contains(&self, func: &str) -> bool {
+        match &self.symbols {
+            Some(s) => s.contains(func),
+            None => func.starts_with("btrfs_") || func.starts_with("__btrfs_"),
+        }
+    }
+}
+
+/// Build the signature for `report`: the report class plus the top frames inside btrfs
+pub fn signature(report: &Report, symbols: &BtrfsSymbols, verdict: Verdict) -> Signature {
+    let mut frames: Vec<&str> = Vec::new();
+    for func in report.function.iter().chain(report.frames.iter()) {
+        if frames.len() == SIGNATURE_FRAMES {
+            break;
+        }
+
+        if symbols.contains(func) && !frames.contains(&func.as_str()) {
+            frames.push(func);
+        }
+    }
+
+    let title = report.title();
+    let key = if frames.is_empty() {
+        // Nothing in btrfs to go on. The title is the next most stable thing.
+        format!("{}:{}", report.class, title)
+    } else {
+        format!("{}:{}", report.class, frames.join(":"))
+    };
+
+    Signature {
+        key,
+        title,
+        verdict,
+    }
+}
+
+/// Pick the report that decides the verdict of a run out of `reports`. Crashes win over
+/// interesting reports.
+fn judge<'a>(
+    reports: &'a [Report],
+    crash_classes: &[CrashClass],
+    interesting_classes: &[CrashClass],
+) -> Option<(&'a Report, Verdict)> {
+    let crash = reports
+        .iter()
+        .find(|r| crash_classes.contains(&r.class) && !interesting_classes.contains(&r.class));
+    if let Some(r) = crash {
+        return Some((r, Verdict::Crash));
+    }
+
+    reports
+        .iter()
+        .find(|r| interesting_classes.contains(&r.class))
+        .map(|r| (r, Verdict::Interesting))
+}
+
+/// Kernel report that decided the verdict of a test case
+pub struct Finding {
+    pub class: CrashClass,
+    pub signature: Signature,
+}
+
+/// Decides if a run failed based on what the kernel logged during it
+pub struct CrashReporter {
+    kmsg: i32,
+    crash_classes: Vec<CrashClass>,
+    /// Classes that make a run interesting but don't fail it. Takes precedence over
+    /// `crash_classes`.
+    interesting_classes: Vec<CrashClass>,
+    /// Where to save the signature of every failing or interesting test case
+    signature_dir: Option<PathBuf>,
+    symbols: BtrfsSymbols,
+    debug: bool,
+}
+
+impl CrashReporter {
+    pub fn new(
+        crash_classes: Vec<CrashClass>,
+        interesting_classes: Vec<CrashClass>,
+        signature_dir: Option<PathBuf>,
+        symbols: BtrfsSymbols,
+        debug: bool,
+    ) -> Result<Self> {
+        if let Some(dir) = &signature_dir {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+
+        Ok(Self {
+            kmsg: kmsg::open_kmsg()?,
+            crash_classes,
+            interesting_classes,
+            signature_dir,
+            symbols,
+            debug,
+        })
+    }
+
+    /// Consume everything the kernel logged since the last check and return what decided the
+    /// verdict of the test case with id `id`, if it did not pass cleanly.
+    ///
+    /// The signature is also saved if configured.
+    pub fn check(&self, id: &str) -> Result<Option<Finding>> {
+        self.check_messages(&kmsg::read_messages(self.kmsg)?, id)
+    }
+
+    /// Same as `check()` but for kmsg `messages` the caller already consumed
+    pub fn check_messages<S: AsRef<str>>(
+        &self,
+        messages: &[S],
+        id: &str,
+    ) -> Result<Option<Finding>> {
+        let reports = kmsg::parse_reports(messages);
+        if self.debug {
+            for report in &reports {
+                println!("Kernel report ({}): {}", report.class, report.title());
+            }
+        }
+
+        let (report, verdict) =
+            match judge(&reports, &self.crash_classes, &self.interesting_classes) {
+                Some(j) => j,
+                None => return Ok(None),
+            };
+
+        let finding = Finding {
+            class: report.class,
+            signature: signature(report, &self.symbols, verdict),
+        };
+        self.save(&finding.signature, id)?;
+
+        Ok(Some(finding))
+    }
+
+    /// Consume everything the kernel logged since the test case with id `id` timed out and
+    /// save a hang signature for it
+    pub fn check_hang(&self, id: &str) -> Result<Signature> {
+        self.check_hang_messages(&kmsg::read_messages(self.kmsg)?, id)
+    }
+
+    /// Same as `check_hang()` but for kmsg `messages` the caller already consumed
+    pub fn check_hang_messages<S: AsRef<str>>(
+        &self,
+        messages: &[S],
+        id: &str,
+    ) -> Result<Signature> {
+        let reports = kmsg::parse_reports(messages);
+
+        // The blocked task dump is the most useful but take anything if sysrq didn't work
+        let report = reports
+            .iter()
+            .find(|r| r.class == CrashClass::Hang)
+            .or_else(|| reports.first());
+        let sig = match report {
+            Some(r) => signature(r, &self.symbols, Verdict::Hang),
+            None => Signature {
+                key: CrashClass::Hang.to_string(),
+                title: "test case timed out".to_string(),
+                verdict: Verdict::Hang,
+            },
+        };
+        self.save(&sig, id)?;
+
+        Ok(sig)
+    }
+
+    fn save(&self, sig: &Signature, id: &str) -> Result<()> {
+        if self.debug {
+            println!("Crash signature ({}): {}", sig.verdict, sig.key);
+        }
+        if let Some(dir) = &self.signature_dir {
+            sig.write(dir, id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Consume everything the kernel logged since the last check
+    pub fn read_messages(&self) -> Result<Vec<String>> {
+        kmsg::read_messages(self.kmsg)
+    }
+}
+
+#[test]
+fn test_signature() {
+    let report = Report {
+        class: CrashClass::Kasan,
+        headline: "BUG: KASAN: slab-out-of-bounds in btrfs_get_16+0x1b/0x60".to_string(),
+        frames: vec![
+            "dump_stack_lvl".to_string(),
+            "kasan_report".to_string(),
+            "btrfs_get_16".to_string(),
+            "check_leaf".to_string(),
+            "btrfs_check_leaf_full".to_string(),
+            "btrfs_validate_metadata_buffer".to_string(),
+            "end_bio_extent_readpage".to_string(),
+        ],
+        function: Some("btrfs_get_16".to_string()),
+    };
+
+    // Without a symbol list we only know about `btrfs_` prefixed functions
+    let guess = BtrfsSymbols { symbols: None };
+    assert_eq!(
+        signature(&report, &guess, Verdict::Crash).key,
+        "kasan:btrfs_get_16:btrfs_check_leaf_full:btrfs_validate_metadata_buffer"
+    );
+
+    let symbols = BtrfsSymbols {
+        symbols: Some(
+            [
+                "btrfs_get_16",
+                "check_leaf",
+                "btrfs_check_leaf_full",
+                "end_bio_extent_readpage",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        ),
+    };
+    let sig = signature(&report, &symbols, Verdict::Crash);
+    assert_eq!(
+        sig.key,
+        "kasan:btrfs_get_16:check_leaf:btrfs_check_leaf_full"
+    );
+    assert_eq!(sig.title, "BUG: KASAN: slab-out-of-bounds in btrfs_get_16");
+
+    // Fall back to the title if btrfs isn't in the trace
+    let report = Report {
+        class: CrashClass::HungTask,
+        headline: "INFO: task runner:91 blocked for more than 120 seconds.".to_string(),
+        frames: vec!["__schedule".to_string()],
+        function: None,
+    };
+    assert_eq!(
+        signature(&report, &symbols, Verdict::Crash).key,
+        "hung-task:INFO: task hung"
+    );
+}
+
+#[test]
+fn test_judge() {
+    let reports = kmsg::parse_reports(&[
+        "WARNING: CPU: 0 PID: 1 at fs/btrfs/inode.c:100 btrfs_evict_inode+0x10/0x20",
+        "BUG: KASAN: use-after-free in btrfs_get_16+0x1b/0x60",
+    ]);
+    let crash_classes = [CrashClass::Warning, CrashClass::Kasan];
+
+    let (report, verdict) = judge(&reports, &crash_classes, &[]).unwrap();
+    assert_eq!(report.class, CrashClass::Warning);
+    assert_eq!(verdict, Verdict::Crash);
+
+    // Interesting classes don't fail the run but a crash elsewhere still does
+    let (report, verdict) = judge(&reports, &crash_classes, &[CrashClass::Warning]).unwrap();
+    assert_eq!(report.class, CrashClass::Kasan);
+    assert_eq!(verdict, Verdict::Crash);
+
+    let (report, verdict) = judge(&reports[..1], &crash_classes, &[CrashClass::Warning]).unwrap();
+    assert_eq!(report.class, CrashClass::Warning);
+    assert_eq!(verdict, Verdict::Interesting);
+
+    assert!(judge(&reports, &[CrashClass::Bug], &[]).is_none());
+}
@@ -6,10 +6,15 @@ use std::str::FromStr;
 
 use anyhow::{bail, Result};
 use libc::{c_void, calloc, free, shmat, shmdt};
+use nix::sys::signal::Signal;
 use nix::{unistd::read, unistd::write};
+use runner::kmsg::CrashClass;
 
 use crate::constants::*;
 
+/// `wait(2)` status flag set when a child dumped core
+const WCOREFLAG: i32 = 0x80;
+
 enum SharedMemPtr {
     /// Allocated using `shmat`, must be deallocated with `shmdt`
     Shm(*mut c_void),
@@ -19,7 +24,34 @@ enum SharedMemPtr {
 
 pub enum RunStatus {
     Success,
-    Failure,
+    /// Kernel logged a report that's worth keeping the input for but isn't a failure. Carries
+    /// the signature key of the report.
+    Interesting(String),
+    /// Class of the kernel report that failed the run. `None` if the child was killed without
+    /// the kernel saying why.
+    Failure(Option<CrashClass>),
+    /// Test case did not finish before the deadline
+    Hang,
+}
+
+impl RunStatus {
+    /// Signal AFL is told killed a failed run. AFL names saved crashes after the signal so each
+    /// kind of failure gets its own.
+    fn failure_signal(class: Option<CrashClass>) -> Signal {
+        match class {
+            Some(CrashClass::Ubsan) => Signal::SIGFPE,
+            Some(CrashClass::Warning)
+            | Some(CrashClass::Lockdep)
+            | Some(CrashClass::BtrfsCritical)
+            | Some(CrashClass::TransactionAbort) => Signal::SIGABRT,
+            Some(CrashClass::HungTask) | Some(CrashClass::Hang) => Signal::SIGALRM,
+            Some(CrashClass::Bug)
+            | Some(CrashClass::Kasan)
+            | Some(CrashClass::Gpf)
+            | Some(CrashClass::Panic)
+            | None => Signal::SIGSEGV,
+        }
+    }
 }
 
 /// This struct implements a fake AFL++ forkserver that does not actually fork children. Instead,
@@ -34,10 +66,14 @@ pub struct Forkserver {
     /// to reproduce a test).
     disabled: bool,
     shared_mem: SharedMemPtr,
+    /// Size of the coverage map in `shared_mem`
+    map_size: u32,
 }
 
 impl Forkserver {
-    pub fn new() -> Result<Self> {
+    /// `map_size` is the size of the coverage map we want. AFL++ sizes its map to fit when it
+    /// starts up.
+    pub fn new(map_size: u32) -> Result<Self> {
         let mut disabled = env::var_os("AFL_NO_FORKSRV").is_some();
 
         // https://github.com/AFLplusplus/AFLplusplus/blob/fac108476c1cb5/include/config.h#L305
@@ -55,7 +91,7 @@ impl Forkserver {
                 println!("Running outside of AFL");
                 disabled = true;
 
-                let ptr = unsafe { calloc(AFL_MAP_SIZE.try_into()?, 1) };
+                let ptr = unsafe { calloc(map_size.try_into()?, 1) };
                 if ptr.is_null() {
                     bail!("Failed to calloc() edge buffer");
                 }
@@ -69,7 +105,7 @@ impl Forkserver {
             // Must be exactly 4 bytes
             let val: u32 = AFL_FS_OPT_ENABLED
                 | AFL_FS_OPT_MAPSIZE
-                | Self::forkserver_opt_set_mapsize(AFL_MAP_SIZE);
+                | Self::forkserver_opt_set_mapsize(map_size);
 
             if write(AFL_FORKSERVER_WRITE_FD, &val.to_ne_bytes())? != 4 {
                 bail!("Forkserver failed to phone home");
@@ -79,6 +115,7 @@ impl Forkserver {
         Ok(Self {
             disabled,
             shared_mem,
+            map_size,
         })
     }
 
@@ -94,7 +131,7 @@ impl Forkserver {
             SharedMemPtr::Anon(p) => p,
         };
 
-        unsafe { slice::from_raw_parts_mut(ptr as *mut u8, AFL_MAP_SIZE.try_into().unwrap()) }
+        unsafe { slice::from_raw_parts_mut(ptr as *mut u8, self.map_size.try_into().unwrap()) }
     }
 
     /// Initiate a new test run with AFL
@@ -125,16 +162,23 @@ impl Forkserver {
         if self.disabled {
             match status {
                 RunStatus::Success => (),
-                RunStatus::Failure => eprintln!(">===== FAILURE REPORTED =====<"),
+                RunStatus::Interesting(_) => eprintln!(">===== INTERESTING REPORTED =====<"),
+                RunStatus::Failure(_) => eprintln!(">===== FAILURE REPORTED =====<"),
+                RunStatus::Hang => eprintln!(">===== HANG REPORTED =====<"),
             };
 
             return Ok(());
         }
 
         let val: i32 = match status {
-            RunStatus::Success => 0,
-            // 139 is SIGSEGV terminated exit code as encoded in `wait(2)`s `wstatus`
-            RunStatus::Failure => 139,
+            RunStatus::Success | RunStatus::Interesting(_) => 0,
+            // Signal terminated with a core dump as encoded in `wait(2)`s `wstatus`, eg 139 for
+            // SIGSEGV
+            RunStatus::Failure(class) => WCOREFLAG | RunStatus::failure_signal(class) as i32,
+            // AFL files the run under hangs as long as its own timer fired before we report,
+            // which is the case if the runner's timeout is at least as long as afl-fuzz's. 9 is
+            // what AFL would have seen had it SIGKILL'd the child itself.
+            RunStatus::Hang => 9,
         };
 
         if write(AFL_FORKSERVER_WRITE_FD, &val.to_ne_bytes())? != 4 {
@@ -1,3 +1,4 @@
+use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
 use std::mem::size_of;
@@ -9,17 +10,43 @@ use std::sync::atomic::Ordering;
 
 use anyhow::{anyhow, bail, Context, Result};
 use nix::fcntl::{fcntl, FcntlArg, FdFlag};
-use nix::{ioctl_read, ioctl_write_int_bad, request_code_none};
+use nix::{ioctl_read, ioctl_write_int_bad, ioctl_write_ptr, request_code_none};
 
-const COVER_SIZE: usize = 16 << 10;
+/// Default number of words in the kcov buffer. The first word is the number of entries that
+/// follow.
+pub const DEFAULT_COVER_SIZE: usize = 16 << 10;
 
 /// See include/uapi/linux/kcov.h
 const KCOV_IOCTL_MAGIC: u8 = b'c';
 const KCOV_INIT_TRACE_IOCTL_SEQ: u8 = 1;
 const KCOV_ENABLE_IOCTL_SEQ: u8 = 100;
-#[allow(dead_code)]
 const KCOV_DISABLE_IOCTL_SEQ: u8 = 101;
+const KCOV_REMOTE_ENABLE_IOCTL_SEQ: u8 = 102;
 const KCOV_TRACE_PC: u64 = 0;
+const KCOV_TRACE_CMP: u64 = 1;
+
+/// Set in `Comparison::ty` if one of the operands is a compile time constant
+const KCOV_CMP_CONST: u64 = 1;
+/// Words per record in `KcovMode::TraceCmp` mode: type, two operands and the PC
+const KCOV_CMP_RECORD_WORDS: usize = 4;
+
+/// Set in the counter by `Kcov::finish()` in `KcovMode::UniquePc` mode if PCs were dropped
+const KCOV_UNIQUE_TRUNCATED: usize = 1 << (usize::BITS - 1);
+
+/// Remote handles with this subsystem are inherited by every task the enabling task forks
+const KCOV_SUBSYSTEM_COMMON: u64 = 0;
+/// Bits of a remote handle that identify the instance within a subsystem
+const KCOV_INSTANCE_MASK: u64 = 0xffff_ffff;
+
+/// See `struct kcov_remote_arg` in include/uapi/linux/kcov.h. We never pass subsystem specific
+/// handles so the trailing `handles` array is left off.
+#[repr(C)]
+pub struct KcovRemoteArg {
+    trace_mode: u32,
+    area_size: u32,
+    num_handles: u64,
+    common_handle: u64,
+}
 
 ioctl_read!(
     kcov_init_trace,
@@ -36,8 +63,66 @@ ioctl_write_int_bad!(
     kcov_disable,
     request_code_none!(KCOV_IOCTL_MAGIC, KCOV_DISABLE_IOCTL_SEQ)
 );
+ioctl_write_ptr!(
+    kcov_remote_enable,
+    KCOV_IOCTL_MAGIC,
+    KCOV_REMOTE_ENABLE_IOCTL_SEQ,
+    KcovRemoteArg
+);
+
+/// Common remote handle for the `run`th test case. Every run gets its own so background work
+/// left over from a previous test case is never counted as this one's.
+pub fn common_handle(run: u64) -> u64 {
+    KCOV_SUBSYSTEM_COMMON | (run % KCOV_INSTANCE_MASK + 1)
+}
+
+/// What kcov collects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KcovMode {
+    /// Every PC that's executed
+    TracePc,
+    /// Operands of every comparison that's executed
+    TraceCmp,
+    /// Every PC that's executed, once. The buffer is drained as the test case runs so long
+    /// traces don't overflow it as long as there are breaks to drain in. Loses the order PCs
+    /// were executed in.
+    UniquePc,
+}
+
+/// A single comparison collected in `KcovMode::TraceCmp` mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comparison {
+    /// `KCOV_CMP_*` flags. Size of the operands is encoded in bits 1 and 2.
+    pub ty: u64,
+    pub arg1: u64,
+    pub arg2: u64,
+}
+
+impl Comparison {
+    /// Operands worth learning. Only the constant is interesting if there is one b/c the other
+    /// side is most likely whatever we fed the kernel.
+    pub fn operands(&self) -> Vec<u64> {
+        // Operands are zero extended so trim anything the compiler didn't compare
+        let size = 1u32 << ((self.ty >> 1) & 0b11);
+        let mask = u64::MAX >> (64 - 8 * size);
+
+        if self.ty & KCOV_CMP_CONST != 0 {
+            // The constant always comes first
+            vec![self.arg1 & mask]
+        } else {
+            vec![self.arg1 & mask, self.arg2 & mask]
+        }
+    }
+}
 
 pub struct Kcov {
+    mode: KcovMode,
+    /// Number of words in the buffer
+    size: usize,
+    /// PCs drained so far in `KcovMode::UniquePc` mode
+    unique: BTreeSet<u64>,
+    /// Whether the buffer filled up before a drain in `KcovMode::UniquePc` mode
+    truncated: bool,
     fd: i32,
     ptr: *mut libc::c_void,
     /// Must hold onto the kcov control file b/c `as_raw_fd()` does not transfer ownership
@@ -45,7 +130,12 @@ pub struct Kcov {
 }
 
 impl Kcov {
-    pub fn new() -> Result<Self> {
+    /// `size` is the number of words in the buffer, including the counter
+    pub fn new(mode: KcovMode, size: usize) -> Result<Self> {
+        if size < 1 + KCOV_CMP_RECORD_WORDS {
+            bail!("kcov buffer size={} is too small", size);
+        }
+
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -60,7 +150,7 @@ impl Kcov {
         fcntl(fd, FcntlArg::F_SETFD(flags))?;
 
         if unsafe {
-            kcov_init_trace(fd, COVER_SIZE as *mut u64)
+            kcov_init_trace(fd, size as *mut u64)
                 .with_context(|| "Failed to KCOV_INIT_TRACE".to_string())?
         } != 0
         {
@@ -70,7 +160,7 @@ impl Kcov {
         let ptr = unsafe {
             libc::mmap(
                 ptr::null_mut(),
-                COVER_SIZE * size_of::<usize>(),
+                size * size_of::<usize>(),
                 libc::PROT_READ | libc::PROT_WRITE,
                 libc::MAP_SHARED,
                 fd,
@@ -83,22 +173,39 @@ impl Kcov {
         }
 
         Ok(Self {
+            mode,
+            size,
+            unique: BTreeSet::new(),
+            truncated: false,
             fd,
             ptr,
             _file: file,
         })
     }
 
+    fn trace_mode(&self) -> u64 {
+        match self.mode {
+            KcovMode::TracePc | KcovMode::UniquePc => KCOV_TRACE_PC,
+            KcovMode::TraceCmp => KCOV_TRACE_CMP,
+        }
+    }
+
+    pub fn mode(&self) -> KcovMode {
+        self.mode
+    }
+
     pub fn enable(&mut self) -> Result<()> {
         // Reset the counter
         self.coverage()[0].store(0, Ordering::Relaxed);
+        self.unique.clear();
+        self.truncated = false;
 
         if unsafe {
-            kcov_enable(self.fd, KCOV_TRACE_PC.try_into().unwrap())
-                .with_context(|| "Failed to enable kcov PC tracing".to_string())?
+            kcov_enable(self.fd, self.trace_mode().try_into().unwrap())
+                .with_context(|| format!("Failed to enable kcov {:?}", self.mode))?
         } != 0
         {
-            bail!("Failed to enable kcov PC tracing");
+            bail!("Failed to enable kcov {:?}", self.mode);
         }
 
         // Reset counter again in case we traced anything as the ioctl returned
@@ -107,12 +214,36 @@ impl Kcov {
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// Collect coverage from kernel code annotated with `kcov_remote_start_common()` running on
+    /// behalf of `common_handle`, eg btrfs workers and kthreads. The handle is inherited by
+    /// every task forked after this. The calling thread's own coverage isn't collected.
+    ///
+    /// Must be `disable()`d before the coverage is read.
+    pub fn enable_remote(&mut self, common_handle: u64) -> Result<()> {
+        self.coverage()[0].store(0, Ordering::Relaxed);
+
+        let arg = KcovRemoteArg {
+            trace_mode: self.trace_mode().try_into().unwrap(),
+            area_size: self.size.try_into().unwrap(),
+            num_handles: 0,
+            common_handle,
+        };
+        if unsafe {
+            kcov_remote_enable(self.fd, &arg)
+                .with_context(|| format!("Failed to enable remote kcov {:?}", self.mode))?
+        } != 0
+        {
+            bail!("Failed to enable remote kcov {:?}", self.mode);
+        }
+
+        Ok(())
+    }
+
     pub fn disable(&mut self) -> Result<usize> {
         let len = self.coverage()[0].load(Ordering::Relaxed);
 
         if unsafe {
-            kcov_disable(self.fd, 0 as i32)
+            kcov_disable(self.fd, 0)
                 .with_context(|| "Failed to disable kcov tracing".to_string())?
         } != 0
         {
@@ -125,7 +256,97 @@ impl Kcov {
     pub fn coverage(&self) -> &[AtomicUsize] {
         // We can transmute from `usize` to `AtomicUsize` b/c they have the same in-memory
         // representations (as promised by the docs)
-        unsafe { slice::from_raw_parts(self.ptr as *const AtomicUsize, COVER_SIZE) }
+        unsafe { slice::from_raw_parts(self.ptr as *const AtomicUsize, self.size) }
+    }
+
+    /// Most entries the buffer holds. An entry is a PC or, in `KcovMode::TraceCmp` mode, a
+    /// comparison.
+    pub fn capacity(&self) -> usize {
+        match self.mode {
+            KcovMode::TracePc | KcovMode::UniquePc => self.size - 1,
+            KcovMode::TraceCmp => (self.size - 1) / KCOV_CMP_RECORD_WORDS,
+        }
+    }
+
+    /// Number of entries in the buffer
+    pub fn len(&self) -> usize {
+        let len = self.coverage()[0].load(Ordering::Relaxed) & !KCOV_UNIQUE_TRUNCATED;
+        len.min(self.capacity())
+    }
+
+    /// Whether the buffer filled up and entries were dropped. kcov stops recording when the
+    /// buffer is full so anything executed after that point is missing.
+    pub fn saturated(&self) -> bool {
+        let counter = self.coverage()[0].load(Ordering::Relaxed);
+        counter & KCOV_UNIQUE_TRUNCATED != 0 || counter >= self.capacity()
+    }
+
+    /// Collected PCs in the order they were executed. Sorted and unique in
+    /// `KcovMode::UniquePc` mode.
+    pub fn pcs(&self) -> Vec<u64> {
+        self.coverage()[1..=self.len()]
+            .iter()
+            .map(|pc| pc.load(Ordering::Relaxed) as u64)
+            .collect()
+    }
+
+    /// Move the PCs collected so far out of the buffer to make room for more. Only does
+    /// anything in `KcovMode::UniquePc` mode.
+    ///
+    /// Must be called from the traced thread while it's in userspace. kcov only traces the
+    /// thread that enabled it in task context so nothing is written to the buffer meanwhile.
+    pub fn drain(&mut self) {
+        if self.mode != KcovMode::UniquePc {
+            return;
+        }
+
+        if self.saturated() {
+            self.truncated = true;
+        }
+
+        let pcs = self.pcs();
+        self.unique.extend(pcs);
+        self.coverage()[0].store(0, Ordering::Relaxed);
+    }
+
+    /// Write the drained PCs back into the buffer for whoever reads the coverage. Only does
+    /// anything in `KcovMode::UniquePc` mode.
+    ///
+    /// Must be called from the traced thread once it's done.
+    pub fn finish(&mut self) {
+        if self.mode != KcovMode::UniquePc {
+            return;
+        }
+
+        self.drain();
+
+        let buf = self.coverage();
+        let mut len = 0;
+        for (slot, pc) in buf[1..].iter().zip(&self.unique) {
+            slot.store(*pc as usize, Ordering::Relaxed);
+            len += 1;
+        }
+
+        if self.truncated || len < self.unique.len() {
+            len |= KCOV_UNIQUE_TRUNCATED;
+        }
+        buf[0].store(len, Ordering::Relaxed);
+    }
+
+    /// Comparisons collected in `KcovMode::TraceCmp` mode
+    pub fn comparisons(&self) -> Vec<Comparison> {
+        let buf = self.coverage();
+
+        (0..self.len())
+            .map(|i| {
+                let record = &buf[1 + i * KCOV_CMP_RECORD_WORDS..];
+                Comparison {
+                    ty: record[0].load(Ordering::Relaxed) as u64,
+                    arg1: record[1].load(Ordering::Relaxed) as u64,
+                    arg2: record[2].load(Ordering::Relaxed) as u64,
+                }
+            })
+            .collect()
     }
 }
 
@@ -133,7 +354,7 @@ impl Drop for Kcov {
     /// Panic if we fail to free resources. Current thinking is it's better to fail
     /// early here and cause afl to report a crash rather than slowly leak memory.
     fn drop(&mut self) {
-        if unsafe { libc::munmap(self.ptr, COVER_SIZE * size_of::<usize>()) } != 0 {
+        if unsafe { libc::munmap(self.ptr, self.size * size_of::<usize>()) } != 0 {
             panic!("Failed to munmap shared kcov buffer");
         }
 
@@ -142,3 +363,31 @@ impl Drop for Kcov {
         }
     }
 }
+
+#[test]
+fn test_comparison_operands() {
+    // 8 byte compare against a constant
+    let cmp = Comparison {
+        ty: KCOV_CMP_CONST | (3 << 1),
+        arg1: 0x5f42485266535f4d,
+        arg2: 0x1234,
+    };
+    assert_eq!(cmp.operands(), vec![0x5f42485266535f4d]);
+
+    // 1 byte compare between two variables
+    let cmp = Comparison {
+        ty: 0,
+        arg1: 0x184,
+        arg2: 0xff0c,
+    };
+    assert_eq!(cmp.operands(), vec![0x84, 0x0c]);
+}
+
+#[test]
+fn test_common_handle() {
+    assert_eq!(common_handle(0), 1);
+    assert_eq!(common_handle(41), 42);
+    assert_eq!(common_handle(KCOV_INSTANCE_MASK - 1), KCOV_INSTANCE_MASK);
+    // Instance 0 is never handed out
+    assert_eq!(common_handle(KCOV_INSTANCE_MASK), 1);
+}
@@ -0,0 +1,562 @@
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use libc::c_void;
+use nix::errno::{errno, Errno};
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{lseek, Whence};
+
+/// Crash classes that fail a run unless configured otherwise
+pub const DEFAULT_CRASH_CLASSES: &str = "bug,warning,kasan,ubsan,gpf,lockdep,hung-task,hang,panic";
+
+/// Frames that are part of the reporting machinery rather than the code that misbehaved
+const REPORTING_FRAME_PREFIXES: &[&str] = &[
+    "dump_stack",
+    "__dump_stack",
+    "show_stack",
+    "panic",
+    "__warn",
+    "warn_slowpath",
+    "report_bug",
+    "handle_bug",
+    "exc_",
+    "asm_exc_",
+    "die",
+    "oops_",
+    "ubsan_",
+    "__ubsan_",
+    "kasan_",
+    "__kasan_",
+    "print_",
+    "lockdep_",
+    "lock_acquire",
+    "__lock_acquire",
+    "validate_chain",
+    "check_",
+    "btrfs_handle_fs_error",
+    "__btrfs_abort_transaction",
+    // Hung tasks are always stuck in the scheduler
+    "__schedule",
+    "schedule",
+    "io_schedule",
+    "__mutex_lock",
+    "mutex_lock",
+    "rwsem_down",
+    "down_",
+    "wait_for_",
+];
+
+/// Kind of kernel report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashClass {
+    /// `BUG:` and `kernel BUG at`
+    Bug,
+    /// `WARNING:` with a CPU and location, ie WARN_ON() and friends
+    Warning,
+    Kasan,
+    Ubsan,
+    /// General protection fault
+    Gpf,
+    /// Lockdep and RCU lockdep splats
+    Lockdep,
+    HungTask,
+    /// Blocked task stacks dumped with sysrq-w after a test case timed out
+    Hang,
+    /// `BTRFS critical` messages, eg from the tree checker
+    BtrfsCritical,
+    /// `BTRFS: Transaction aborted`
+    TransactionAbort,
+    Panic,
+}
+
+impl FromStr for CrashClass {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let class = match s {
+            "bug" => CrashClass::Bug,
+            "warning" => CrashClass::Warning,
+            "kasan" => CrashClass::Kasan,
+            "ubsan" => CrashClass::Ubsan,
+            "gpf" => CrashClass::Gpf,
+            "lockdep" => CrashClass::Lockdep,
+            "hung-task" => CrashClass::HungTask,
+            "hang" => CrashClass::Hang,
+            "btrfs-critical" => CrashClass::BtrfsCritical,
+            "transaction-abort" => CrashClass::TransactionAbort,
+            "panic" => CrashClass::Panic,
+            _ => bail!("Unknown crash class={}", s),
+        };
+
+        Ok(class)
+    }
+}
+
+impl fmt::Display for CrashClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CrashClass::Bug => "bug",
+            CrashClass::Warning => "warning",
+            CrashClass::Kasan => "kasan",
+            CrashClass::Ubsan => "ubsan",
+            CrashClass::Gpf => "gpf",
+            CrashClass::Lockdep => "lockdep",
+            CrashClass::HungTask => "hung-task",
+            CrashClass::Hang => "hang",
+            CrashClass::BtrfsCritical => "btrfs-critical",
+            CrashClass::TransactionAbort => "transaction-abort",
+            CrashClass::Panic => "panic",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// A single kernel report (oops, warning, sanitizer splat, etc.) found in kmsg
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub class: CrashClass,
+    /// First line of the report
+    pub headline: String,
+    /// Functions in the call trace, innermost first. Unreliable (`?`) frames are skipped.
+    pub frames: Vec<String>,
+    /// Function the report was triggered in, if we could figure it out
+    pub function: Option<String>,
+}
+
+impl Report {
+    fn new(class: CrashClass, headline: &str) -> Self {
+        Self {
+            class,
+            headline: headline.to_string(),
+            frames: Vec::new(),
+            function: None,
+        }
+    }
+
+    /// Short, stable description of the report. Addresses and offsets are left out so the same
+    /// bug produces the same title every time.
+    pub fn title(&self) -> String {
+        let headline = strip_offsets(&self.headline);
+        let function = match &self.function {
+            Some(f) => f,
+            // Hung task headlines have the task name and pid in them
+            None if self.class == CrashClass::HungTask => return "INFO: task hung".to_string(),
+            None => return headline,
+        };
+
+        match self.class {
+            CrashClass::Warning => format!("WARNING in {}", function),
+            CrashClass::Gpf => format!("general protection fault in {}", function),
+            CrashClass::Bug if headline.contains("unable to handle") => {
+                let end = headline.find(" for address").unwrap_or(headline.len());
+                format!("{} in {}", &headline[..end], function)
+            }
+            CrashClass::HungTask => format!("INFO: task hung in {}", function),
+            CrashClass::Hang => format!("hang in {}", function),
+            CrashClass::TransactionAbort => format!("{} in {}", headline, function),
+            _ => headline,
+        }
+    }
+
+    /// Fill in `function` from the call trace if nothing better came along
+    fn finish(&mut self) {
+        if self.function.is_none() {
+            self.function = self
+                .frames
+                .iter()
+                .find(|f| !REPORTING_FRAME_PREFIXES.iter().any(|p| f.starts_with(p)))
+                .cloned();
+        }
+    }
+}
+
+/// Strip compiler generated suffixes (eg `.isra.0`, `.constprop.0`, `.cold`) off of a function
+/// so partially inlined and cloned helpers map back to the function in the source
+fn normalize_function(func: &str) -> &str {
+    func.split('.').next().unwrap_or(func)
+}
+
+/// Strip `+0x12/0x34` offsets and clone suffixes from symbols in `line`
+fn strip_offsets(line: &str) -> String {
+    line.split(' ')
+        .map(|word| match parse_frame(word) {
+            Some(func) => func,
+            None => word,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse the function out of a `func+0x12/0x34` frame
+fn parse_frame(line: &str) -> Option<&str> {
+    let symbol = line.trim().split(' ').next()?;
+    let idx = symbol.find("+0x")?;
+    if !symbol[idx..].contains("/0x") {
+        return None;
+    }
+
+    Some(normalize_function(&symbol[..idx]))
+}
+
+/// Figure out which kind of report (if any) `line` starts
+fn classify(line: &str) -> Option<CrashClass> {
+    let class = if line.starts_with("BUG: KASAN:") {
+        CrashClass::Kasan
+    } else if line.starts_with("BUG:") || line.starts_with("kernel BUG at") {
+        CrashClass::Bug
+    } else if line.starts_with("WARNING: CPU:") {
+        CrashClass::Warning
+    } else if line.starts_with("WARNING:") {
+        CrashClass::Lockdep
+    } else if line.starts_with("UBSAN:") {
+        CrashClass::Ubsan
+    } else if line.contains("general protection fault") {
+        CrashClass::Gpf
+    } else if line.starts_with("INFO: task ") && line.contains("blocked for more than") {
+        CrashClass::HungTask
+    } else if line.contains("Show Blocked State") {
+        CrashClass::Hang
+    } else if line.starts_with("BTRFS critical") {
+        CrashClass::BtrfsCritical
+    } else if line.starts_with("BTRFS: Transaction aborted") {
+        CrashClass::TransactionAbort
+    } else if line.starts_with("Kernel panic") {
+        CrashClass::Panic
+    } else {
+        return None;
+    };
+
+    Some(class)
+}
+
+/// Split kmsg messages into kernel reports
+pub fn parse_reports<S: AsRef<str>>(messages: &[S]) -> Vec<Report> {
+    let mut reports: Vec<Report> = Vec::new();
+    let mut current: Option<Report> = None;
+    let mut in_trace = false;
+
+    for message in messages {
+        let line = message.as_ref().trim_end();
+        let trimmed = line.trim_start();
+
+        if let Some(class) = classify(trimmed) {
+            // Transaction aborts are reported through a WARN(). Keep it all in one report.
+            let merge = matches!(
+                &current,
+                Some(r) if r.class == CrashClass::TransactionAbort
+                    && class == CrashClass::Warning
+                    && r.frames.is_empty()
+            );
+            if merge {
+                if let Some(r) = current.as_mut() {
+                    r.function = trimmed.rsplit(' ').find_map(parse_frame).map(String::from);
+                }
+                in_trace = false;
+                continue;
+            }
+
+            if let Some(mut r) = current.take() {
+                r.finish();
+                reports.push(r);
+            }
+
+            let mut report = Report::new(class, trimmed);
+            report.function = match class {
+                // eg `WARNING: CPU: 0 PID: 1 at fs/btrfs/disk-io.c:10 func+0x1/0x2 [btrfs]`
+                CrashClass::Warning => trimmed.rsplit(' ').find_map(parse_frame),
+                // eg `BUG: KASAN: use-after-free in func+0x1/0x2`
+                CrashClass::Kasan => trimmed.rsplit(" in ").next().and_then(parse_frame),
+                _ => None,
+            }
+            .map(String::from);
+
+            current = Some(report);
+            in_trace = false;
+            continue;
+        }
+
+        let report = match current.as_mut() {
+            Some(r) => r,
+            None => continue,
+        };
+
+        if trimmed.starts_with("---[ end") {
+            in_trace = false;
+            if let Some(mut r) = current.take() {
+                r.finish();
+                reports.push(r);
+            }
+        } else if let Some(rip) = trimmed.strip_prefix("RIP: ") {
+            // eg `RIP: 0010:func+0x1/0x2`
+            let rip = rip.split_once(':').map_or(rip, |(_, r)| r);
+            if let Some(func) = parse_frame(rip) {
+                report.function = Some(func.to_string());
+            }
+        } else if trimmed == "Call Trace:" {
+            in_trace = true;
+        } else if in_trace {
+            if trimmed.starts_with('<') && trimmed.ends_with('>') {
+                // `<TASK>`, `</IRQ>`, etc.
+                continue;
+            }
+
+            if trimmed.starts_with("? ") {
+                continue;
+            }
+
+            match parse_frame(trimmed) {
+                Some(func) => report.frames.push(func.to_string()),
+                None => in_trace = false,
+            }
+        }
+    }
+
+    if let Some(mut r) = current {
+        r.finish();
+        reports.push(r);
+    }
+
+    reports
+}
+
+/// Opens kmsg fd and seeks to end.
+///
+/// Note we avoid using the higher level std::fs interfaces b/c /dev/kmsg is a bit special in that
+/// each read(2) returns exactly 1 entry in the kernel's printk buffer. So we don't want any high
+/// level APIs issuing multiple reads. The fd must also be opened in non-blocking mode otherwise
+/// reads will block until a new entry is available.
+pub fn open_kmsg() -> Result<i32> {
+    let fd = open(
+        "/dev/kmsg",
+        OFlag::O_RDONLY | OFlag::O_NONBLOCK,
+        Mode::empty(),
+    )?;
+    lseek(fd, 0, Whence::SeekEnd)?;
+    Ok(fd)
+}
+
+/// Parse the message out of a kmsg record
+///
+/// Records look like `<prefix>;<message>` followed by optional continuation lines that start
+/// with a space. See Documentation/ABI/testing/dev-kmsg in the kernel tree.
+fn parse_record(record: &str) -> Option<&str> {
+    let (_, message) = record.split_once(';')?;
+    Some(message.lines().next().unwrap_or(""))
+}
+
+/// Read every pending message out of kmsg
+pub fn read_messages(fd: i32) -> Result<Vec<String>> {
+    let mut buf: Vec<u8> = vec![0; 8192];
+    let mut messages = Vec::new();
+
+    // NB: make sure we consume all the entries in kmsg otherwise the next test might see entries
+    // from the previous run
+    loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        match n.cmp(&0) {
+            cmp::Ordering::Equal => break,
+            cmp::Ordering::Less => {
+                let errno = Errno::from_i32(errno());
+                match errno {
+                    // No more entries in kmsg
+                    Errno::EAGAIN => break,
+                    // We were too slow and the ring buffer wrapped. Next read continues from
+                    // the oldest available record.
+                    Errno::EPIPE => continue,
+                    _ => return Err(anyhow!("Failed to read from /dev/kmsg: {}", errno)),
+                }
+            }
+            cmp::Ordering::Greater => {
+                let record = String::from_utf8_lossy(&buf[..n as usize]);
+                if let Some(message) = parse_record(&record) {
+                    messages.push(message.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(messages)
+}
+
+#[test]
+fn test_parse_record() {
+    assert_eq!(
+        parse_record("4,1234,5678,-;BTRFS: Transaction aborted (error -5)\n SUBSYSTEM=btrfs\n"),
+        Some("BTRFS: Transaction aborted (error -5)")
+    );
+    assert_eq!(parse_record("6,1,2,-;"), Some(""));
+    assert_eq!(parse_record("garbage"), None);
+}
+
+#[test]
+fn test_parse_warning_and_abort() {
+    let messages = [
+        "BTRFS info (device loop0): disk space caching is enabled",
+        "------------[ cut here ]------------",
+        "BTRFS: Transaction aborted (error -117)",
+        "WARNING: CPU: 0 PID: 89 at fs/btrfs/extent-tree.c:3060 __btrfs_free_extent+0x5fd/0x9a0",
+        "Modules linked in:",
+        "RIP: 0010:__btrfs_free_extent+0x5fd/0x9a0",
+        "Call Trace:",
+        " <TASK>",
+        " __btrfs_run_delayed_refs.constprop.0+0x2d1/0x1170",
+        " ? lock_release+0x1a0/0x400",
+        " btrfs_run_delayed_refs+0x78/0x1c0",
+        " </TASK>",
+        "---[ end trace 0000000000000000 ]---",
+        "------------[ cut here ]------------",
+        "WARNING: CPU: 1 PID: 90 at fs/btrfs/inode.c:100 btrfs_evict_inode+0x10/0x20 [btrfs]",
+        "Call Trace:",
+        " evict+0xcf/0x1d0",
+        "---[ end trace 0000000000000000 ]---",
+    ];
+    let reports = parse_reports(&messages);
+    assert_eq!(reports.len(), 2);
+
+    assert_eq!(reports[0].class, CrashClass::TransactionAbort);
+    assert_eq!(reports[0].function.as_deref(), Some("__btrfs_free_extent"));
+    assert_eq!(
+        reports[0].frames,
+        vec!["__btrfs_run_delayed_refs", "btrfs_run_delayed_refs"]
+    );
+    assert_eq!(
+        reports[0].title(),
+        "BTRFS: Transaction aborted (error -117) in __btrfs_free_extent"
+    );
+
+    assert_eq!(reports[1].class, CrashClass::Warning);
+    assert_eq!(reports[1].title(), "WARNING in btrfs_evict_inode");
+    assert_eq!(reports[1].frames, vec!["evict"]);
+}
+
+#[test]
+fn test_parse_sanitizers() {
+    let messages = [
+        "==================================================================",
+        "BUG: KASAN: slab-out-of-bounds in btrfs_get_16+0x1b/0x60",
+        "Read of size 2 at addr ffff888104a6b0fe by task runner/91",
+        "Call Trace:",
+        " dump_stack_lvl+0x4d/0x66",
+        " print_report+0x17f/0x47b",
+        " kasan_report+0xa9/0x120",
+        " btrfs_get_16+0x1b/0x60",
+        " check_leaf+0x4a1/0x1f00",
+        "==================================================================",
+        "UBSAN: shift-out-of-bounds in fs/btrfs/ctree.c:1234:5",
+        "shift exponent 64 is too large for 64-bit type 'long long unsigned int'",
+        "Call Trace:",
+        " dump_stack_lvl+0x4d/0x66",
+        " __ubsan_handle_shift_out_of_bounds.cold+0x61/0xef",
+        " btrfs_bin_search.isra.0+0x1c0/0x200",
+    ];
+    let reports = parse_reports(&messages);
+    assert_eq!(reports.len(), 2);
+
+    assert_eq!(reports[0].class, CrashClass::Kasan);
+    assert_eq!(reports[0].function.as_deref(), Some("btrfs_get_16"));
+    assert_eq!(
+        reports[0].title(),
+        "BUG: KASAN: slab-out-of-bounds in btrfs_get_16"
+    );
+
+    assert_eq!(reports[1].class, CrashClass::Ubsan);
+    assert_eq!(reports[1].function.as_deref(), Some("btrfs_bin_search"));
+    assert_eq!(
+        reports[1].title(),
+        "UBSAN: shift-out-of-bounds in fs/btrfs/ctree.c:1234:5"
+    );
+}
+
+#[test]
+fn test_parse_misc_reports() {
+    let messages = [
+        "general protection fault, probably for non-canonical address 0xdffffc0000000002: 0000",
+        "RIP: 0010:btrfs_search_slot+0x125/0x1090",
+        "BUG: unable to handle page fault for address: ffffffffffffffe8",
+        "RIP: 0010:read_extent_buffer+0x2c/0x90",
+        "INFO: task runner:91 blocked for more than 120 seconds.",
+        "BTRFS critical (device loop0): corrupt leaf: root=1 block=30408704 slot=0",
+        "WARNING: possible circular locking dependency detected",
+        "Kernel panic - not syncing: Fatal exception",
+    ];
+    let reports = parse_reports(&messages);
+    let classes: Vec<CrashClass> = reports.iter().map(|r| r.class).collect();
+    assert_eq!(
+        classes,
+        vec![
+            CrashClass::Gpf,
+            CrashClass::Bug,
+            CrashClass::HungTask,
+            CrashClass::BtrfsCritical,
+            CrashClass::Lockdep,
+            CrashClass::Panic,
+        ]
+    );
+    assert_eq!(
+        reports[0].title(),
+        "general protection fault in btrfs_search_slot"
+    );
+    assert_eq!(
+        reports[1].title(),
+        "BUG: unable to handle page fault in read_extent_buffer"
+    );
+}
+
+#[test]
+fn test_parse_blocked_tasks() {
+    let messages = [
+        "sysrq: Show Blocked State",
+        "task:runner          state:D stack:0     pid:91    ppid:90     flags:0x00004000",
+        "Call Trace:",
+        " <TASK>",
+        " __schedule+0x2f1/0x8b0",
+        " schedule+0x5a/0xc0",
+        " wait_current_trans+0xd4/0x130",
+        " start_transaction+0x2a5/0x6a0",
+        " </TASK>",
+        "task:kworker/u2:1    state:D stack:0     pid:12    ppid:2      flags:0x00004000",
+        "Call Trace:",
+        " __schedule+0x2f1/0x8b0",
+        " btrfs_commit_transaction+0x8e1/0xc30",
+    ];
+    let reports = parse_reports(&messages);
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].class, CrashClass::Hang);
+    assert_eq!(reports[0].function.as_deref(), Some("wait_current_trans"));
+    assert_eq!(
+        reports[0].frames,
+        vec![
+            "__schedule",
+            "schedule",
+            "wait_current_trans",
+            "start_transaction",
+            "__schedule",
+            "btrfs_commit_transaction"
+        ]
+    );
+    assert_eq!(reports[0].title(), "hang in wait_current_trans");
+}
+
+#[test]
+fn test_no_false_positives() {
+    let messages = [
+        "BTRFS info (device loop0): using free space tree",
+        "loop0: detected capacity change from 0 to 262144",
+        "random driver: DEBUG: a WARNING: that isn't one",
+        "BTRFS error (device loop0): bad tree block start, want 30408704 have 0",
+    ];
+    assert!(parse_reports(&messages).is_empty());
+}
+
+#[test]
+fn test_crash_class_names() {
+    for name in DEFAULT_CRASH_CLASSES.split(',') {
+        let class: CrashClass = name.parse().unwrap();
+        assert_eq!(class.to_string(), name);
+    }
+    assert!("nope".parse::<CrashClass>().is_err());
+}
@@ -0,0 +1,162 @@
+use std::cmp;
+use std::fs::{self, copy, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use nix::ioctl_write_ptr;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use static_assertions::const_assert;
+use testcase::{MountOptions, Program};
+
+pub mod crash;
+pub mod kmsg;
+pub mod minimize;
+pub mod mount;
+pub mod repro;
+pub mod workload;
+
+use mount::Mounter;
+
+/// Where the image under test is written to
+pub const FUZZED_IMAGE_PATH: &str = "/tmp/btrfsimage";
+/// Where the filesystem under test is mounted
+pub const MOUNTPOINT: &str = "/mnt/btrfs";
+/// Extra device images are copied to `EXTRA_DEVICE_PATH_PREFIX` + device index
+const EXTRA_DEVICE_PATH_PREFIX: &str = "/tmp/btrfsdevice";
+/// How long a killed child gets to exit before we give up on it
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// See /usr/include/linux/btrfs.h
+pub const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+const BTRFS_FORGET_DEV_IOCTL_SEQ: u8 = 5;
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+
+#[repr(C, packed)]
+pub struct BtrfsIoctlVolArgs {
+    fd: i64,
+    name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+const_assert!(std::mem::size_of::<BtrfsIoctlVolArgs>() == 4096);
+
+ioctl_write_ptr!(
+    btrfs_forget_dev,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_FORGET_DEV_IOCTL_SEQ,
+    BtrfsIoctlVolArgs
+);
+
+/// Make fresh copies of the extra device images
+///
+/// The extra devices get written to during each test so they must be reset between test cases,
+/// otherwise state from a previous test could leak into the next one.
+///
+/// Returns paths to every device image in the filesystem, starting with the fuzzed image.
+pub fn reset_extra_devices(extra_devices: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut images = vec![PathBuf::from(FUZZED_IMAGE_PATH)];
+    for (i, device) in extra_devices.iter().enumerate() {
+        let path = PathBuf::from(format!("{}{}", EXTRA_DEVICE_PATH_PREFIX, i));
+        copy(device, &path).with_context(|| format!("Failed to copy {}", device.display()))?;
+        images.push(path);
+    }
+
+    Ok(images)
+}
+
+/// Reset btrfs device cache
+///
+/// Necessary to clean up kernel state between test cases
+pub fn reset_btrfs_devices() -> Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/btrfs-control")
+        .with_context(|| "Failed to open btrfs control file".to_string())?;
+    let fd = file.as_raw_fd();
+
+    let args: BtrfsIoctlVolArgs = unsafe { std::mem::zeroed() };
+    unsafe { btrfs_forget_dev(fd, &args) }
+        .with_context(|| "Failed to forget btrfs devs".to_string())?;
+
+    Ok(())
+}
+
+/// Wait for `child` to change state, giving up after `timeout` if specified
+///
+/// Returns `None` if the child is still running once `timeout` is up.
+pub fn wait_for_child(child: Pid, timeout: Option<Duration>) -> Result<Option<WaitStatus>> {
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return Ok(Some(waitpid(child, None)?)),
+    };
+
+    // Most test cases finish quickly so start polling fast and back off from there
+    let start = Instant::now();
+    let mut delay = Duration::from_micros(50);
+    loop {
+        match waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+            WaitStatus::StillAlive => (),
+            status => return Ok(Some(status)),
+        }
+
+        if start.elapsed() >= timeout {
+            return Ok(None);
+        }
+
+        thread::sleep(delay);
+        delay = cmp::min(delay * 2, Duration::from_millis(10));
+    }
+}
+
+/// Dump the stacks of all tasks stuck in uninterruptible sleep into kmsg
+fn dump_blocked_tasks() -> Result<()> {
+    fs::write("/proc/sysrq-trigger", "w")
+        .with_context(|| "Failed to dump blocked tasks with sysrq".to_string())
+}
+
+/// Kill `child` after it ran for too long. The stacks of blocked tasks are dumped into kmsg first
+/// so the hang gets a signature.
+///
+/// Returns false if the child is stuck in the kernel and didn't exit within `KILL_GRACE_PERIOD`.
+/// Either way the caller has to `Mounter::reset()` b/c the child never unmounts.
+pub fn kill_hung_child(child: Pid) -> Result<bool> {
+    dump_blocked_tasks()?;
+    kill(child, Signal::SIGKILL)?;
+
+    Ok(wait_for_child(child, Some(KILL_GRACE_PERIOD))?.is_some())
+}
+
+/// Test code
+///
+/// Note how this doesn't return errors. That's because our definition of error is a kernel BUG()
+/// or panic. We expect that some operations here fail (such as mount(2))
+///
+/// `after_step` is called after mounting and after every operation in `program`.
+pub fn work<P: AsRef<Path>, F: FnMut()>(
+    mounter: &mut Mounter,
+    images: &[P],
+    mount_options: &MountOptions,
+    program: &Program,
+    debug: bool,
+    mut after_step: F,
+) {
+    // Keep the mount alive until the program is done
+    let _mount = match mounter.mount(images, MOUNTPOINT, mount_options) {
+        Ok(m) => m,
+        Err(e) => {
+            if debug {
+                eprintln!("Mount error: {}", e);
+            }
+
+            return;
+        }
+    };
+
+    after_step();
+
+    workload::run(program, MOUNTPOINT, debug, &mut after_step);
+}
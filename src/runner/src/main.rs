@@ -1,56 +1,50 @@
 use std::cmp;
-use std::convert::TryInto;
-use std::fs::{create_dir_all, OpenOptions};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io::{self, Read, Write};
-use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
-use libc::c_void;
-use nix::errno::{errno, Errno};
-use nix::fcntl::{open, OFlag};
-use nix::ioctl_write_ptr;
-use nix::sys::stat::Mode;
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{fork, lseek, ForkResult, Whence};
-use rmp_serde::decode::from_read_ref;
-use static_assertions::const_assert;
+use imgcompress::{DecompressOptions, HeaderField};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::{fork, ForkResult};
 use structopt::StructOpt;
+use testcase::{Dictionary, MountOptions, Program, Testcase, Verdict};
+
+use runner::crash::{BtrfsSymbols, CrashReporter};
+use runner::kmsg::{self, CrashClass};
+use runner::mount::Mounter;
+use runner::{
+    kill_hung_child, reset_btrfs_devices, reset_extra_devices, wait_for_child, work,
+    FUZZED_IMAGE_PATH, MOUNTPOINT,
+};
 
 mod constants;
 mod forkserver;
 mod kcov;
-mod mount;
+mod pc_table;
 
+use constants::AFL_MAP_SIZE;
 use forkserver::{Forkserver, RunStatus};
-use kcov::Kcov;
-use mount::Mounter;
-
-const FUZZED_IMAGE_PATH: &str = "/tmp/btrfsimage";
+use kcov::{Kcov, KcovMode, DEFAULT_COVER_SIZE};
+use pc_table::PcTable;
 
-/// See /usr/include/linux/btrfs.h
-const BTRFS_IOCTL_MAGIC: u8 = 0x94;
-const BTRFS_FORGET_DEV_IOCTL_SEQ: u8 = 5;
-const BTRFS_PATH_NAME_MAX: usize = 4087;
-
-#[repr(C, packed)]
-pub struct BtrfsIoctlVolArgs {
-    fd: i64,
-    name: [u8; BTRFS_PATH_NAME_MAX + 1],
-}
-const_assert!(std::mem::size_of::<BtrfsIoctlVolArgs>() == 4096);
-
-ioctl_write_ptr!(
-    btrfs_forget_dev,
-    BTRFS_IOCTL_MAGIC,
-    BTRFS_FORGET_DEV_IOCTL_SEQ,
-    BtrfsIoctlVolArgs
-);
+/// `--stats-file` is rewritten every `STATS_INTERVAL` runs
+const STATS_INTERVAL: u64 = 100;
 
 enum TestcaseStatus {
-    Ok,
+    Ok {
+        /// See `testcase::id()`
+        id: String,
+        /// Decompressed image. Also written to the path passed to `get_next_testcase()`.
+        image: Vec<u8>,
+        /// Program to run against the image
+        program: Program,
+        mount_options: MountOptions,
+    },
     NoMore,
 }
 
@@ -60,61 +54,218 @@ struct Opt {
     /// Turn on debug output
     #[structopt(short, long)]
     debug: bool,
+    /// Pristine image of an additional device in a multi-device filesystem. The fuzzed image is
+    /// always the first device. May be specified more than once.
+    #[structopt(long = "extra-device", parse(from_os_str))]
+    extra_devices: Vec<PathBuf>,
+    /// File describing the operations to run against the mounted image, one per line. Overrides
+    /// the program carried by each test case.
+    #[structopt(long, parse(from_os_str))]
+    workload: Option<PathBuf>,
+    /// Comma separated mount flags and btrfs mount options to mount with, eg `ro,compress=zstd`.
+    /// Overrides the mount options carried by each test case.
+    #[structopt(long)]
+    mount_options: Option<MountOptions>,
+    /// Comma separated kinds of kernel reports that count as failures. Available classes are
+    /// bug, warning, kasan, ubsan, gpf, lockdep, hung-task, hang, btrfs-critical,
+    /// transaction-abort and panic.
+    #[structopt(long, use_delimiter = true, default_value = kmsg::DEFAULT_CRASH_CLASSES)]
+    crash_classes: Vec<CrashClass>,
+    /// Comma separated kinds of kernel reports that make a test case interesting instead of
+    /// failing it. AFL keeps the input the first time each signature shows up. Takes precedence
+    /// over --crash-classes.
+    #[structopt(long, use_delimiter = true)]
+    interesting_classes: Vec<CrashClass>,
+    /// Directory to save a crash signature to for every failing, hanging or interesting test
+    /// case. Signature files are named after the id of the test case and note the verdict.
+    #[structopt(long, parse(from_os_str))]
+    signature_dir: Option<PathBuf>,
+    /// File listing the functions defined in fs/btrfs, one per line. Used to pick frames for
+    /// crash signatures. Falls back to guessing from function names if not specified.
+    #[structopt(long, parse(from_os_str))]
+    btrfs_symbols: Option<PathBuf>,
+    /// Node header field to leave as fuzzed instead of restoring. Every field is restored by
+    /// default. May be given more than once. Available fields are bytenr, fsid, chunk-tree-uuid
+    /// and generation.
+    #[structopt(long, number_of_values = 1)]
+    no_header_fixup: Vec<HeaderField>,
+    /// Milliseconds a test case may run before it's killed and reported as a hang. Should be
+    /// at least afl-fuzz's -t so AFL files the test case under hangs. No limit if not specified.
+    #[structopt(long)]
+    timeout: Option<u64>,
+    /// File to collect the operands of comparisons btrfs makes into. The mutator splices them
+    /// into fields. Comparisons aren't collected if not specified.
+    #[structopt(long, parse(from_os_str))]
+    cmp_dictionary: Option<PathBuf>,
+    /// Collect comparisons on every Nth test case. Every collection runs the test case again,
+    /// so test cases that crashed or hung are skipped.
+    #[structopt(long, default_value = "64")]
+    cmp_interval: u64,
+    /// Don't collect coverage from btrfs workers and kthreads. That coverage only shows up on
+    /// kernels annotated by scripts/docker/kcov_remote.sh.
+    #[structopt(long)]
+    no_remote_kcov: bool,
+    /// Number of words in each kcov buffer. A run that executes more PCs than fit is truncated.
+    /// Defaults to 16384.
+    #[structopt(long)]
+    kcov_size: Option<usize>,
+    /// Report each PC the test case executes once instead of edges between them. The buffer is
+    /// drained between operations so long workloads aren't truncated as long as no single
+    /// operation fills it.
+    #[structopt(long)]
+    kcov_dedup: bool,
+    /// File to write runner statistics to, eg how many runs saturated the kcov buffer
+    #[structopt(long, parse(from_os_str))]
+    stats_file: Option<PathBuf>,
+    /// vmlinux of the running kernel. Gives every basic block kcov instruments its own slot in
+    /// the coverage map instead of hashing edges into a 64K map. The map is sized to fit.
+    #[structopt(long, parse(from_os_str))]
+    vmlinux: Option<PathBuf>,
 }
 
-/// Opens kmsg fd and seeks to end.
-///
-/// Note we avoid using the higher level std::fs interfaces b/c /dev/kmsg is a bit special in that
-/// each read(2) returns exactly 1 entry in the kernel's printk buffer. So we don't want any high
-/// level APIs issuing multiple reads. The fd must also be opened in non-blocking mode otherwise
-/// reads will block until a new entry is available.
-fn open_kmsg() -> Result<i32> {
-    let fd = open(
-        "/dev/kmsg",
-        OFlag::O_RDONLY | OFlag::O_NONBLOCK,
-        Mode::empty(),
-    )?;
-    lseek(fd, 0, Whence::SeekEnd)?;
-    Ok(fd)
+/// Counters written to `--stats-file`
+#[derive(Debug, Default)]
+struct Stats {
+    runs: u64,
+    /// Runs that filled the kcov buffer and lost coverage
+    saturated_runs: u64,
+    /// Runs that filled the remote kcov buffer and lost coverage
+    remote_saturated_runs: u64,
+    /// Most kcov entries a single run has recorded
+    max_kcov_entries: usize,
 }
 
-fn kmsg_contains_bug(fd: i32) -> Result<bool> {
-    let mut buf: Vec<u8> = vec![0; 8192];
-    let mut found = false;
+impl Stats {
+    /// Record the coverage of a single run
+    fn record(&mut self, kcov: &Kcov, remote_kcov: Option<&Kcov>) {
+        self.runs += 1;
+        self.max_kcov_entries = cmp::max(self.max_kcov_entries, kcov.len());
+        if kcov.saturated() {
+            self.saturated_runs += 1;
+        }
+        if remote_kcov.is_some_and(|k| k.saturated()) {
+            self.remote_saturated_runs += 1;
+        }
+    }
 
-    // NB: make sure we consume all the entries in kmsg otherwise the next test might see entries
-    // from the previous run
-    loop {
-        let n = unsafe { libc::read(fd, (&mut buf).as_mut_ptr() as *mut c_void, buf.len()) };
-        match n.cmp(&0) {
-            cmp::Ordering::Equal => break,
-            cmp::Ordering::Less => {
-                let errno = Errno::from_i32(errno());
-                if errno == Errno::EAGAIN {
-                    // No more entries in kmsg
-                    break;
-                } else {
-                    bail!("Failed to read from /dev/kmsg");
-                }
-            }
-            cmp::Ordering::Greater => {
-                buf[n as usize] = 0;
+    /// Save in the same `key : value` format as AFL's fuzzer_stats
+    fn save(&self, path: &Path, kcov_size: usize) -> Result<()> {
+        let content = format!(
+            "runs                  : {}\n\
+             saturated_runs        : {}\n\
+             remote_saturated_runs : {}\n\
+             max_kcov_entries      : {}\n\
+             kcov_size             : {}\n",
+            self.runs,
+            self.saturated_runs,
+            self.remote_saturated_runs,
+            self.max_kcov_entries,
+            kcov_size
+        );
+
+        // Don't let anyone read a partially written file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to rename {} to {}", tmp.display(), path.display()))
+    }
+}
+
+/// Learns the operands of comparisons the kernel makes
+struct CmpCollector {
+    kcov: Kcov,
+    /// Separate from the fuzzing mounter so a rerun that hangs can't leave it unusable
+    mounter: Mounter,
+    dictionary: Dictionary,
+    /// Where `dictionary` is saved
+    path: PathBuf,
+}
+
+impl CmpCollector {
+    fn new(path: &Path, kcov_size: usize, nr_devices: usize) -> Result<Self> {
+        Ok(Self {
+            kcov: Kcov::new(KcovMode::TraceCmp, kcov_size)?,
+            mounter: Mounter::new(nr_devices)?,
+            // Pick up where a previous runner left off
+            dictionary: Dictionary::load(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Run a test case again while tracing comparisons and learn from them. Nothing is
+    /// reported: whatever the test case triggers was already reported by its first run.
+    #[allow(clippy::too_many_arguments)]
+    fn collect(
+        &mut self,
+        image: &[u8],
+        extra_devices: &[PathBuf],
+        mount_options: &MountOptions,
+        program: &Program,
+        timeout: Option<Duration>,
+        debug: bool,
+    ) -> Result<RunStatus> {
+        // The first run may have written to the images so start over from scratch
+        fs::write(FUZZED_IMAGE_PATH, image)
+            .with_context(|| format!("Failed to write {}", FUZZED_IMAGE_PATH))?;
+        reset_btrfs_devices()?;
+        let images = reset_extra_devices(extra_devices)?;
+
+        let status = fork_work_and_wait(
+            &mut self.kcov,
+            None,
+            &mut self.mounter,
+            &images,
+            mount_options,
+            program,
+            timeout,
+            debug,
+        )?;
+        if !matches!(status, RunStatus::Hang) {
+            self.learn(debug)?;
+        }
+
+        Ok(status)
+    }
+
+    /// Add the comparisons collected in the last run to the dictionary
+    fn learn(&mut self, debug: bool) -> Result<()> {
+        let comparisons = self.kcov.comparisons();
+        if debug && self.kcov.saturated() {
+            println!("kcov comparison buffer saturated, comparisons were dropped");
+        }
 
-                let line = String::from_utf8_lossy(&buf);
-                if line.contains("BUG") || line.contains("UBSAN:") {
-                    found = true;
+        let mut learned = 0;
+        for cmp in &comparisons {
+            for val in cmp.operands() {
+                if self.dictionary.insert(val) {
+                    learned += 1;
                 }
             }
         }
-    }
 
-    Ok(found)
+        if debug {
+            println!(
+                "{} kcov comparisons, {} new dictionary values",
+                comparisons.len(),
+                learned
+            );
+        }
+
+        if learned > 0 {
+            self.dictionary.save(&self.path)?;
+        }
+
+        Ok(())
+    }
 }
 
-/// Get next testcase from AFL and write it into file `into`
+/// Get next testcase from AFL and write its image into file `into`
 ///
 /// Returns true on success, false on no more input
-fn get_next_testcase<P: AsRef<Path>>(into: P) -> Result<TestcaseStatus> {
+fn get_next_testcase<P: AsRef<Path>>(
+    into: P,
+    decompress_opts: &DecompressOptions,
+) -> Result<TestcaseStatus> {
     let mut buffer = Vec::new();
 
     // AFL feeds inputs via stdin
@@ -126,8 +277,8 @@ fn get_next_testcase<P: AsRef<Path>>(into: P) -> Result<TestcaseStatus> {
     }
 
     // Decompress input
-    let deserialized = from_read_ref(&buffer)?;
-    let image = imgcompress::decompress(&deserialized)?;
+    let testcase = Testcase::decode(&buffer)?;
+    let image = imgcompress::decompress_with(&testcase.image, decompress_opts)?;
 
     // Write out FS image
     let mut file = OpenOptions::new()
@@ -138,100 +289,96 @@ fn get_next_testcase<P: AsRef<Path>>(into: P) -> Result<TestcaseStatus> {
 
     file.write_all(&image)?;
 
-    Ok(TestcaseStatus::Ok)
+    Ok(TestcaseStatus::Ok {
+        id: testcase::id(&buffer),
+        image,
+        program: testcase.program,
+        mount_options: testcase.mount_options,
+    })
 }
 
-/// Reset btrfs device cache
-///
-/// Necessary to clean up kernel state between test cases
-fn reset_btrfs_devices() -> Result<()> {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open("/dev/btrfs-control")
-        .with_context(|| "Failed to open btrfs control file".to_string())?;
-    let fd = file.as_raw_fd();
-
-    let args: BtrfsIoctlVolArgs = unsafe { std::mem::zeroed() };
-    unsafe { btrfs_forget_dev(fd, &args) }
-        .with_context(|| "Failed to forget btrfs devs".to_string())?;
-
-    Ok(())
-}
-
-/// Test code
-///
-/// Note how this doesn't return errors. That's because our definition of error is a kernel BUG()
-/// or panic. We expect that some operations here fail (such as mount(2))
-fn work<P: AsRef<Path>>(mounter: &mut Mounter, image: P, debug: bool) {
-    let r = mounter.mount(image.as_ref(), "/mnt/btrfs");
+/// Record the coverage collected by `kcov` into AFL's `shmem`. Edge transitions are recorded
+/// unless PCs were deduplicated, in which case each PC is recorded once. With a `pc_table`
+/// every PC is recorded in its own slot.
+fn record_coverage(
+    shmem: &mut [u8],
+    kcov: &Kcov,
+    pc_table: Option<&PcTable>,
+    name: &str,
+    debug: bool,
+) {
+    let pcs = kcov.pcs();
 
     if debug {
-        match r {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Mount error: {}", e);
-                return;
-            }
+        println!("{} {} entries", pcs.len(), name);
+        if kcov.saturated() {
+            println!(
+                "{} buffer saturated at {} entries, coverage was truncated",
+                name,
+                kcov.capacity()
+            );
         }
     }
 
-    let nested_dir_path = "/mnt/btrfs/one/two/three/four/five/six";
-    let ret = create_dir_all(nested_dir_path);
-    if debug {
-        match ret {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Failed to create some directories in work fn: {}", e);
-                return;
+    if let Some(table) = pc_table {
+        let mut unknown = 0;
+        for pc in pcs {
+            match table.index(pc) {
+                Some(idx) => shmem[idx] = shmem[idx].saturating_add(1),
+                None => unknown += 1,
             }
         }
-    }
 
-    let mut file = match OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(format!("{}/file", nested_dir_path))
-    {
-        Ok(f) => f,
-        Err(e) => {
-            if debug {
-                eprintln!("Failed to open test file: {}", e);
-            }
-
-            return;
+        if debug && unknown > 0 {
+            println!("{} {} entries not in the PC table", unknown, name);
         }
-    };
 
-    match writeln!(file, "hello world") {
-        Ok(_) => (),
-        Err(e) => {
-            if debug {
-                eprintln!("Failed to write to test file: {}", e);
-                return;
-            }
-        }
+        return;
     }
 
-    match file.sync_all() {
-        Ok(()) => (),
-        Err(e) => {
-            if debug {
-                eprintln!("Failed to sync test file: {}", e);
-                return;
-            }
+    let unique = kcov.mode() == KcovMode::UniquePc;
+    let mut prev_loc: u64 = 0xDEAD; // Our compile time "random"
+    for current_loc in pcs {
+        // First calculate which idx in shmem to write to. Mask with 0xFFFF for 16 bits b/c
+        // AFL_MAP_SIZE == 1 << 16.
+        let mixed: u64 = if unique {
+            current_loc & 0xFFFF
+        } else {
+            (current_loc & 0xFFFF) ^ prev_loc
+        };
+        prev_loc = (current_loc & 0xFFFF) >> 1;
+
+        // Increment value in shmem
+        let (val, overflow) = shmem[mixed as usize].overflowing_add(1);
+        if overflow {
+            shmem[mixed as usize] = u8::MAX;
+        } else {
+            shmem[mixed as usize] = val;
+        }
+
+        if debug {
+            println!("{} entry: 0x{:x}", name, current_loc);
         }
     }
 }
 
 /// Fork a child and execute test case.
 ///
+/// `report` is the reporter kernel reports are checked with and the id of the test case they're
+/// filed under. Without it nothing is reported and kmsg is left for the caller to deal with.
+///
+/// The caller has to `Mounter::reset()` after a hang b/c the killed child never unmounts.
+///
 /// NB: Returning an error crashes the fuzzer. DO NOT return an error unless it's truly unrecoverable.
+#[allow(clippy::too_many_arguments)]
 fn fork_work_and_wait<P: AsRef<Path>>(
     kcov: &mut Kcov,
-    kmsg: i32,
+    report: Option<(&CrashReporter, &str)>,
     mounter: &mut Mounter,
-    image: P,
+    images: &[P],
+    mount_options: &MountOptions,
+    program: &Program,
+    timeout: Option<Duration>,
     debug: bool,
 ) -> Result<RunStatus> {
     const EXIT_OK: i32 = 88;
@@ -239,11 +386,37 @@ fn fork_work_and_wait<P: AsRef<Path>>(
 
     match fork()? {
         ForkResult::Parent { child } => {
-            let res = waitpid(child, None)?;
+            let res = match wait_for_child(child, timeout)? {
+                Some(res) => res,
+                None => {
+                    if debug {
+                        println!("Test case timed out, killing child={}", child);
+                    }
 
-            if kmsg_contains_bug(kmsg)? {
-                return Ok(RunStatus::Failure);
-            }
+                    let killed = kill_hung_child(child)?;
+                    if let Some((reporter, id)) = report {
+                        reporter.check_hang(id)?;
+                    }
+                    if !killed {
+                        bail!(
+                            "Forked child={} is stuck in the kernel and cannot be killed",
+                            child
+                        );
+                    }
+
+                    return Ok(RunStatus::Hang);
+                }
+            };
+
+            let finding = match report {
+                Some((reporter, id)) => reporter.check(id)?,
+                None => None,
+            };
+            let interesting = match finding {
+                Some(f) if f.signature.verdict == Verdict::Interesting => Some(f.signature.key),
+                Some(f) => return Ok(RunStatus::Failure(Some(f.class))),
+                None => None,
+            };
 
             match res {
                 WaitStatus::Exited(pid, rc) => {
@@ -251,9 +424,12 @@ fn fork_work_and_wait<P: AsRef<Path>>(
                         bail!("Forked child={} had an unclean exit={}", pid, rc);
                     }
 
-                    Ok(RunStatus::Success)
+                    match interesting {
+                        Some(key) => Ok(RunStatus::Interesting(key)),
+                        None => Ok(RunStatus::Success),
+                    }
                 }
-                WaitStatus::Signaled(_, _, _) => Ok(RunStatus::Failure),
+                WaitStatus::Signaled(_, _, _) => Ok(RunStatus::Failure(None)),
                 _ => bail!("Unexpected waitpid() status={:?}", res),
             }
         }
@@ -268,7 +444,10 @@ fn fork_work_and_wait<P: AsRef<Path>>(
                 }
             }
 
-            work(mounter, image, debug);
+            work(mounter, images, mount_options, program, debug, || {
+                kcov.drain()
+            });
+            kcov.finish();
 
             // Kcov is automatically disabled when the child terminates
             exit(EXIT_OK);
@@ -279,68 +458,187 @@ fn fork_work_and_wait<P: AsRef<Path>>(
 fn _main() -> Result<()> {
     let opts = Opt::from_args();
 
+    // The map size is negotiated in the handshake so the PC table has to come first
+    let pc_table = match &opts.vmlinux {
+        Some(path) => Some(PcTable::load(path)?),
+        None => None,
+    };
+    let map_size = pc_table.as_ref().map_or(AFL_MAP_SIZE, |t| t.map_size());
+    if opts.debug {
+        if let Some(table) = &pc_table {
+            println!("{} PCs in PC table, map size={}", table.len(), map_size);
+        }
+    }
+
     // Initialize forkserver and handshake with AFL
-    let mut forkserver = Forkserver::new()?;
+    let mut forkserver = Forkserver::new(map_size)?;
 
     // Initialize kernel coverage interface
-    let mut kcov = Kcov::new()?;
+    let kcov_size = opts.kcov_size.unwrap_or(DEFAULT_COVER_SIZE);
+    let kcov_mode = if opts.kcov_dedup {
+        KcovMode::UniquePc
+    } else {
+        KcovMode::TracePc
+    };
+    let mut kcov = Kcov::new(kcov_mode, kcov_size)?;
+    // Collects coverage from btrfs background work the child triggers
+    let mut remote_kcov = if opts.no_remote_kcov {
+        None
+    } else {
+        Some(Kcov::new(KcovMode::TracePc, kcov_size)?)
+    };
+    if opts.cmp_interval == 0 {
+        bail!("--cmp-interval must be at least 1");
+    }
+    let mut cmp_collector = match &opts.cmp_dictionary {
+        Some(path) => Some(CmpCollector::new(
+            path,
+            kcov_size,
+            opts.extra_devices.len() + 1,
+        )?),
+        None => None,
+    };
+    let mut stats = Stats::default();
 
     // Open /dev/kmsg
-    let kmsg = open_kmsg()?;
+    let reporter = CrashReporter::new(
+        opts.crash_classes.clone(),
+        opts.interesting_classes.clone(),
+        opts.signature_dir.clone(),
+        BtrfsSymbols::load(opts.btrfs_symbols.as_ref())?,
+        opts.debug,
+    )?;
+
+    // Create persistent loopdevs to use
+    let mut mounter = Mounter::new(opts.extra_devices.len() + 1)?;
 
-    // Create a persistent loopdev to use
-    let mut mounter = Mounter::new()?;
+    let workload = match &opts.workload {
+        Some(path) => Some(Program::from_file(path)?),
+        None => None,
+    };
+    let decompress_opts = DecompressOptions {
+        skip_header_fixups: opts.no_header_fixup.clone(),
+    };
+    let timeout = opts.timeout.map(Duration::from_millis);
 
     loop {
         // Tell AFL we want to start a new run
         forkserver.new_run()?;
 
         // Now pull the next testcase from AFL and write it to tmpfs
-        match get_next_testcase(FUZZED_IMAGE_PATH)? {
-            TestcaseStatus::Ok => (),
-            TestcaseStatus::NoMore => break,
-        };
+        let (id, image, program, mount_options) =
+            match get_next_testcase(FUZZED_IMAGE_PATH, &decompress_opts)? {
+                TestcaseStatus::Ok {
+                    id,
+                    image,
+                    program,
+                    mount_options,
+                } => (id, image, program, mount_options),
+                TestcaseStatus::NoMore => break,
+            };
+        let program = workload.as_ref().unwrap_or(&program);
+        let mount_options = opts.mount_options.unwrap_or(mount_options);
 
         // Reset kernel state
         reset_btrfs_devices()?;
+        let images = reset_extra_devices(&opts.extra_devices)?;
 
-        // Fork a child and perform test
-        let status =
-            fork_work_and_wait(&mut kcov, kmsg, &mut mounter, FUZZED_IMAGE_PATH, opts.debug)?;
+        // The child inherits the handle so it must be enabled before forking
+        if let Some(remote) = remote_kcov.as_mut() {
+            remote.enable_remote(kcov::common_handle(stats.runs))?;
+        }
 
-        // When the child exits coverage is disabled so we're good to read memory mapped data here
-        let coverage = kcov.coverage();
-        let size = coverage[0].load(Ordering::Relaxed);
+        // Fork a child and perform test
+        let status = fork_work_and_wait(
+            &mut kcov,
+            Some((&reporter, &id)),
+            &mut mounter,
+            &images,
+            &mount_options,
+            program,
+            timeout,
+            opts.debug,
+        )?;
+        if matches!(status, RunStatus::Hang) {
+            mounter.reset(MOUNTPOINT)?;
+        }
+        // Test cases that crashed or hung are likely to do it again when rerun
+        let completed = matches!(status, RunStatus::Success | RunStatus::Interesting(_));
 
-        if opts.debug {
-            println!("{} kcov entries", size);
+        // Background work still running now is dropped. Most of it is flushed by the unmount.
+        if let Some(remote) = remote_kcov.as_mut() {
+            remote.disable()?;
         }
 
-        // Report edge transitions to AFL
+        // When the child exits coverage is disabled so we're good to read memory mapped data here.
+        // Report edge transitions to AFL.
         let shmem = forkserver.shmem();
-        let mut prev_loc: u64 = 0xDEAD; // Our compile time "random"
-        for i in 0..size {
-            // First calculate which idx in shmem to write to
-            let current_loc: u64 = coverage[i + 1].load(Ordering::Relaxed).try_into().unwrap();
-            // Mask with 0xFFFF for 16 bits b/c AFL_MAP_SIZE == 1 << 16
-            let mixed: u64 = (current_loc & 0xFFFF) ^ prev_loc;
-            prev_loc = (current_loc & 0xFFFF) >> 1;
-
-            // Increment value in shmem
-            let (val, overflow) = shmem[mixed as usize].overflowing_add(1);
-            if overflow {
-                shmem[mixed as usize] = u8::MAX;
-            } else {
-                shmem[mixed as usize] = val;
-            }
+        record_coverage(shmem, &kcov, pc_table.as_ref(), "kcov", opts.debug);
+        if let Some(remote) = &remote_kcov {
+            record_coverage(shmem, remote, pc_table.as_ref(), "remote kcov", opts.debug);
+        }
 
-            if opts.debug {
-                println!("kcov entry: 0x{:x}", current_loc);
-            }
+        // Give every kind of interesting report an edge of its own so AFL keeps the first input
+        // that triggers it
+        if let RunStatus::Interesting(key) = &status {
+            let slots = match &pc_table {
+                Some(table) => table.spare_slots(),
+                None => 0..shmem.len(),
+            };
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            let idx = slots.start + (hasher.finish() % slots.len() as u64) as usize;
+            shmem[idx] = shmem[idx].saturating_add(1);
         }
 
         // Report run status to AFL
         forkserver.report(status)?;
+
+        // AFL doesn't time us until the next run starts so anything done from here on is free as
+        // far as AFL is concerned
+        stats.record(&kcov, remote_kcov.as_ref());
+        if let Some(path) = &opts.stats_file {
+            if stats.runs.is_multiple_of(STATS_INTERVAL) {
+                stats.save(path, kcov_size)?;
+            }
+        }
+
+        // Collecting comparisons is best effort. Failing to collect must not stop fuzzing.
+        if let Some(collector) = cmp_collector.as_mut() {
+            if completed && stats.runs.is_multiple_of(opts.cmp_interval) {
+                let res = collector.collect(
+                    &image,
+                    &opts.extra_devices,
+                    &mount_options,
+                    program,
+                    timeout,
+                    opts.debug,
+                );
+
+                // Throw away whatever the rerun logged so it isn't blamed on the next test case
+                if let Err(e) = reporter.read_messages() {
+                    eprintln!("Failed to discard kmsg from comparison run: {}", e);
+                }
+
+                match res {
+                    Ok(RunStatus::Hang) => {
+                        eprintln!("Comparison run hung, no longer collecting comparisons");
+                        // The killed child never unmounted so it's still on the mountpoint
+                        // the fuzzing mounter uses
+                        if let Err(e) = collector.mounter.reset(MOUNTPOINT) {
+                            eprintln!("Failed to reset comparison run mounter: {}", e);
+                        }
+                        cmp_collector = None;
+                    }
+                    Ok(_) => (),
+                    Err(e) => eprintln!("Failed to collect comparisons: {}", e),
+                }
+            }
+        }
+    }
+
+    if let Some(path) = &opts.stats_file {
+        stats.save(path, kcov_size)?;
     }
 
     Ok(())
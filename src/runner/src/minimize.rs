@@ -0,0 +1,98 @@
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+#[cfg(test)]
+use imgcompress::diff::changes_test_image;
+use imgcompress::diff::{changes, Change};
+use imgcompress::CompressedBtrfsImage;
+
+/// Ranges in `data` of every metadata extent that differs from `baseline`
+fn changed_extents(image: &CompressedBtrfsImage, baseline: &[u8]) -> Vec<Range<usize>> {
+    image
+        .extents()
+        .map(|(_, range)| range)
+        .filter(|range| image.data[range.clone()] != baseline[range.clone()])
+        .collect()
+}
+
+/// Revert `range` in `image` back to `baseline`. The revert is undone if `image` no longer
+/// reproduces.
+fn try_revert<F>(
+    image: &mut CompressedBtrfsImage,
+    baseline: &[u8],
+    range: Range<usize>,
+    reproduces: &mut F,
+) -> Result<bool>
+where
+    F: FnMut(&CompressedBtrfsImage) -> Result<bool>,
+{
+    let fuzzed = image.data[range.clone()].to_vec();
+    image.data[range.clone()].copy_from_slice(&baseline[range.clone()]);
+    if reproduces(image)? {
+        return Ok(true);
+    }
+
+    image.data[range].copy_from_slice(&fuzzed);
+    Ok(false)
+}
+
+/// Revert differences between `image` and `baseline` one metadata extent at a time and then one
+/// field at a time. A revert is only kept if `reproduces` still returns true afterwards.
+///
+/// Returns the changes that are left, ie the ones needed to reproduce.
+pub fn minimize<F>(
+    image: &mut CompressedBtrfsImage,
+    baseline: &[u8],
+    mut reproduces: F,
+) -> Result<Vec<Change>>
+where
+    F: FnMut(&CompressedBtrfsImage) -> Result<bool>,
+{
+    if image.data.len() != baseline.len() {
+        bail!(
+            "Image has {} bytes of metadata but baseline has {}",
+            image.data.len(),
+            baseline.len()
+        );
+    }
+
+    for range in changed_extents(image, baseline) {
+        try_revert(image, baseline, range, &mut reproduces)?;
+    }
+
+    for change in changes(image, baseline, &image.data) {
+        try_revert(image, baseline, change.range, &mut reproduces)?;
+    }
+
+    Ok(changes(image, baseline, &image.data))
+}
+
+#[test]
+fn test_minimize() {
+    let mut image = changes_test_image();
+    let baseline = image.data.clone();
+    for byte in image.data.iter_mut() {
+        *byte = 0xff;
+    }
+
+    // Pretend the crash only needs nritems in the first extent
+    let mut runs = 0;
+    let remaining = minimize(&mut image, &baseline, |image| {
+        runs += 1;
+        Ok(image.data[8..12] == [0xff; 4])
+    })
+    .unwrap();
+
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].range, 8..12);
+    assert_eq!(image.data[8..12], [0xff; 4]);
+    assert!(image.data[..8].iter().all(|b| *b == 0));
+    assert!(image.data[12..].iter().all(|b| *b == 0));
+    // 2 extents, then bytenr, nritems and the unknown tail of the first extent
+    assert_eq!(runs, 5);
+
+    // Nothing to do if nothing changed
+    let mut image = changes_test_image();
+    let remaining = minimize(&mut image, &baseline, |_| panic!("shouldn't run")).unwrap();
+    assert!(remaining.is_empty());
+}
@@ -1,4 +1,4 @@
-use std::fs;
+use std::fs::{self, OpenOptions};
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -7,36 +7,99 @@ use anyhow::{anyhow, bail, Context, Result};
 use loopdev::{LoopControl, LoopDevice};
 use nix::fcntl::{fcntl, FcntlArg, FdFlag};
 use sys_mount::{FilesystemType, MountFlags, Unmount, UnmountFlags};
+use testcase::MountOptions;
+
+/// Scratch file used to reserve loop devices in `Mounter::new`
+const LOOPDEV_PLACEHOLDER_PATH: &str = "/tmp/btrfs-fuzz-loopdev-placeholder";
 
 pub struct Mounter {
-    loopdev: LoopDevice,
-    /// If a file is attached to the loopdev
+    /// One loopdev per device in the filesystem. The first loopdev is the one that gets mounted.
+    loopdevs: Vec<LoopDevice>,
+    /// If files are attached to the loopdevs
     attached: AtomicBool,
 }
 
 impl Mounter {
-    pub fn new() -> Result<Self> {
+    /// Create a mounter that can mount filesystems spanning `nr_devices` devices
+    pub fn new(nr_devices: usize) -> Result<Self> {
+        if nr_devices == 0 {
+            bail!("Mounter needs at least one device");
+        }
+
         let control =
             LoopControl::open().with_context(|| "Failed to open loop control".to_string())?;
-        let device = control
-            .next_free()
-            .with_context(|| "Failed to get next free loop dev".to_string())?;
-
-        // Disable O_CLOEXEC on underlying loopdev FD so that instances of this mounter
-        // may be used in forked child processes.
-        let fd = device.as_raw_fd();
-        let mut flags = FdFlag::from_bits(fcntl(fd, FcntlArg::F_GETFD)?)
-            .ok_or_else(|| anyhow!("Failed to interpret FdFlag"))?;
-        flags &= !FdFlag::FD_CLOEXEC;
-        fcntl(fd, FcntlArg::F_SETFD(flags))?;
+
+        // `LoopControl::next_free()` keeps handing out the same loopdev until something is
+        // attached to it. So temporarily attach a placeholder file to each loopdev as we go.
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(LOOPDEV_PLACEHOLDER_PATH)
+            .with_context(|| "Failed to create loop dev placeholder file".to_string())?
+            .set_len(4096)?;
+
+        let mut loopdevs = Vec::with_capacity(nr_devices);
+        for _ in 0..nr_devices {
+            let device = control
+                .next_free()
+                .with_context(|| "Failed to get next free loop dev".to_string())?;
+            device
+                .attach_file(LOOPDEV_PLACEHOLDER_PATH)
+                .with_context(|| "Failed to reserve loop dev".to_string())?;
+
+            // Disable O_CLOEXEC on underlying loopdev FD so that instances of this mounter
+            // may be used in forked child processes.
+            let fd = device.as_raw_fd();
+            let mut flags = FdFlag::from_bits(fcntl(fd, FcntlArg::F_GETFD)?)
+                .ok_or_else(|| anyhow!("Failed to interpret FdFlag"))?;
+            flags &= !FdFlag::FD_CLOEXEC;
+            fcntl(fd, FcntlArg::F_SETFD(flags))?;
+
+            loopdevs.push(device);
+        }
+
+        for device in &loopdevs {
+            device.detach()?;
+        }
+        fs::remove_file(LOOPDEV_PLACEHOLDER_PATH)?;
 
         Ok(Self {
-            loopdev: device,
+            loopdevs,
             attached: AtomicBool::new(false),
         })
     }
 
-    pub fn mount<P: AsRef<Path>>(&mut self, src: P, dest: &'static str) -> Result<Mount> {
+    /// Recover from a child that was killed while it had a filesystem mounted on `dest`
+    ///
+    /// The child never got to unmount the filesystem or detach the loopdevs, so do it here. The
+    /// old loopdevs stay busy until the kernel lets go of the filesystem so replace them with
+    /// fresh ones.
+    pub fn reset(&mut self, dest: &str) -> Result<()> {
+        // The child may have been killed before it mounted anything
+        let _ = sys_mount::unmount(dest, UnmountFlags::DETACH);
+
+        // Busy loopdevs are detached automatically once the filesystem is released
+        for loopdev in &self.loopdevs {
+            let _ = loopdev.detach();
+        }
+
+        *self = Self::new(self.loopdevs.len())?;
+
+        Ok(())
+    }
+
+    /// Mount the filesystem made up of `srcs` onto `dest` with `options`.
+    ///
+    /// `srcs` must contain exactly one image per device this mounter was created with. The
+    /// first image is the one passed to mount(2); the rest are passed in through `device=`
+    /// mount options.
+    pub fn mount<P: AsRef<Path>>(
+        &mut self,
+        srcs: &[P],
+        dest: &'static str,
+        options: &MountOptions,
+    ) -> Result<Mount<'_>> {
         // Will fail if directory already exists
         let _ = fs::create_dir(dest);
 
@@ -44,32 +107,67 @@ impl Mounter {
             bail!("Loop dev is still being used by a previous mount");
         }
 
-        self.loopdev
-            .attach_file(src)
-            .with_context(|| "Failed to attach file to loop dev".to_string())?;
+        if srcs.len() != self.loopdevs.len() {
+            bail!(
+                "Got {} images but mounter has {} loop devs",
+                srcs.len(),
+                self.loopdevs.len()
+            );
+        }
+
+        let mut flags = MountFlags::empty();
+        for name in options.flag_names() {
+            flags |= mount_flag(name)?;
+        }
+
+        for (i, (loopdev, src)) in self.loopdevs.iter().zip(srcs).enumerate() {
+            if let Err(e) = loopdev.attach_file(src) {
+                // Don't leave the loopdevs we already attached dangling
+                for attached in &self.loopdevs[..i] {
+                    attached.detach()?;
+                }
+
+                return Err(e).with_context(|| "Failed to attach file to loop dev".to_string());
+            }
+        }
         self.attached.store(true, Ordering::SeqCst);
 
+        let mut paths = Vec::with_capacity(self.loopdevs.len());
+        for loopdev in &self.loopdevs {
+            paths.push(
+                loopdev
+                    .path()
+                    .ok_or_else(|| anyhow!("Failed to get path of loop dev"))?,
+            );
+        }
+        let mut data: Vec<String> = paths[1..]
+            .iter()
+            .map(|p| format!("device={}", p.display()))
+            .collect();
+        data.extend(options.option_names().iter().map(|o| o.to_string()));
+        let data = data.join(",");
+
         let mount = sys_mount::Mount::new(
-            self.loopdev
-                .path()
-                .ok_or_else(|| anyhow!("Failed to get path of loop dev"))?,
+            &paths[0],
             dest,
             FilesystemType::Manual("btrfs"),
-            MountFlags::empty(),
-            None,
+            flags,
+            if data.is_empty() { None } else { Some(&data) },
         )
         .with_context(|| "Failed to mount btrfs image".to_string());
 
         match mount {
             Ok(m) => Ok(Mount {
                 inner: m,
-                loopdev: &self.loopdev,
+                loopdevs: &self.loopdevs,
                 attached: &self.attached,
             }),
             Err(e) => {
-                // Be careful to detach the backing file from the loopdev if the mount fails,
+                // Be careful to detach the backing files from the loopdevs if the mount fails,
                 // otherwise following attaches will fail with EBUSY
-                self.loopdev.detach()?;
+                for loopdev in &self.loopdevs {
+                    loopdev.detach()?;
+                }
                 self.attached.store(false, Ordering::SeqCst);
                 Err(e)
             }
@@ -77,11 +175,27 @@ impl Mounter {
     }
 }
 
+/// Translate a flag name out of `testcase::MOUNT_FLAGS`
+fn mount_flag(name: &str) -> Result<MountFlags> {
+    let flag = match name {
+        "ro" => MountFlags::RDONLY,
+        "sync" => MountFlags::SYNCHRONOUS,
+        "dirsync" => MountFlags::DIRSYNC,
+        "noatime" => MountFlags::NOATIME,
+        "nodiratime" => MountFlags::NODIRATIME,
+        _ => bail!("Unknown mount flag={}", name),
+    };
+
+    Ok(flag)
+}
+
 impl Drop for Mounter {
     fn drop(&mut self) {
         // Panic here if detaching fails b/c otherwise we'd slowly leak resources.
         if self.attached.load(Ordering::SeqCst) {
-            self.loopdev.detach().unwrap();
+            for loopdev in &self.loopdevs {
+                loopdev.detach().unwrap();
+            }
         }
     }
 }
@@ -91,7 +205,7 @@ impl Drop for Mounter {
 /// Will umount on drop.
 pub struct Mount<'a> {
     inner: sys_mount::Mount,
-    loopdev: &'a LoopDevice,
+    loopdevs: &'a [LoopDevice],
     attached: &'a AtomicBool,
 }
 
@@ -99,7 +213,17 @@ impl<'a> Drop for Mount<'a> {
     fn drop(&mut self) {
         // Panic here if detaching fails b/c otherwise we'd slowly leak resources.
         self.inner.unmount(UnmountFlags::empty()).unwrap();
-        self.loopdev.detach().unwrap();
+        for loopdev in self.loopdevs {
+            loopdev.detach().unwrap();
+        }
         self.attached.store(false, Ordering::SeqCst);
     }
 }
+
+#[test]
+fn test_mount_flags() {
+    for name in testcase::MOUNT_FLAGS {
+        assert!(mount_flag(name).is_ok());
+    }
+    assert!(mount_flag("nope").is_err());
+}
@@ -0,0 +1,149 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use object::read::ReadCache;
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind};
+
+/// Function the compiler calls at the start of every basic block it instruments for kcov
+const KCOV_TRACE_PC_FUNC: &str = "__sanitizer_cov_trace_pc";
+
+/// Opcode of x86 `call rel32`
+const X86_CALL_REL32: u8 = 0xe8;
+const X86_CALL_REL32_LEN: usize = 5;
+
+/// Slots past the PCs for coverage that doesn't belong to a PC, eg interesting kernel reports
+const SPARE_SLOTS: usize = 1024;
+
+/// AFL++ rounds map sizes up to a multiple of this
+const AFL_MAP_SIZE_ALIGN: usize = 64;
+
+/// Every PC kcov can record, each with a slot of its own in the coverage map.
+///
+/// Only btrfs is instrumented (see scripts/docker/config_kernel.sh) so these are the basic
+/// blocks in fs/btrfs.
+pub struct PcTable {
+    /// Sorted. A PC's index is its slot.
+    pcs: Vec<u64>,
+}
+
+impl PcTable {
+    /// Find every instrumented PC in `vmlinux`. It must be the image the running kernel was
+    /// booted from, with KASLR disabled.
+    pub fn load<P: AsRef<Path>>(vmlinux: P) -> Result<Self> {
+        let path = vmlinux.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        // vmlinux is mostly debug info so only read in the parts we need
+        let cache = ReadCache::new(file);
+        let elf = object::File::parse(&cache)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        let target = elf
+            .symbols()
+            .find(|s| s.name() == Ok(KCOV_TRACE_PC_FUNC))
+            .ok_or_else(|| anyhow!("{} not found, is kcov enabled?", KCOV_TRACE_PC_FUNC))?
+            .address();
+
+        let mut pcs = Vec::new();
+        for section in elf.sections().filter(|s| s.kind() == SectionKind::Text) {
+            let code = section
+                .data()
+                .with_context(|| format!("Failed to read section {:?}", section.name()))?;
+            pcs.extend(call_sites(code, section.address(), target));
+        }
+
+        if pcs.is_empty() {
+            bail!("No calls to {} in {}", KCOV_TRACE_PC_FUNC, path.display());
+        }
+
+        Ok(Self::from_pcs(pcs))
+    }
+
+    fn from_pcs(mut pcs: Vec<u64>) -> Self {
+        pcs.sort_unstable();
+        pcs.dedup();
+
+        Self { pcs }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pcs.len()
+    }
+
+    /// Slot of `pc` in the coverage map. `None` if kcov isn't supposed to record `pc`.
+    pub fn index(&self, pc: u64) -> Option<usize> {
+        self.pcs.binary_search(&pc).ok()
+    }
+
+    /// Slots for coverage that doesn't belong to a PC
+    pub fn spare_slots(&self) -> Range<usize> {
+        self.len()..self.len() + SPARE_SLOTS
+    }
+
+    /// Size of a coverage map that fits every PC and the spare slots
+    pub fn map_size(&self) -> u32 {
+        let size = self.spare_slots().end.next_multiple_of(AFL_MAP_SIZE_ALIGN);
+        size.try_into().unwrap()
+    }
+}
+
+/// Return addresses of every `call target` in `code`, which is loaded at `addr`. kcov records
+/// the return address for each call site.
+///
+/// Code isn't disassembled so the bytes of another instruction could in theory pass for a call.
+/// All 4 bytes of the offset must line up though, so it doesn't happen in practice.
+fn call_sites(code: &[u8], addr: u64, target: u64) -> impl Iterator<Item = u64> + '_ {
+    code.windows(X86_CALL_REL32_LEN)
+        .enumerate()
+        .filter(|(_, insn)| insn[0] == X86_CALL_REL32)
+        .filter_map(move |(i, insn)| {
+            let ret = addr + (i + X86_CALL_REL32_LEN) as u64;
+            let rel = i32::from_le_bytes(insn[1..].try_into().unwrap());
+            if ret.wrapping_add(rel as i64 as u64) == target {
+                Some(ret)
+            } else {
+                None
+            }
+        })
+}
+
+#[test]
+fn test_call_sites() {
+    let addr = 0xffff_ffff_8100_0000;
+    let target = 0xffff_ffff_8100_0100;
+    #[rustfmt::skip]
+    let code = [
+        // call target
+        0xe8, 0xfb, 0x00, 0x00, 0x00,
+        // nop
+        0x90,
+        // call somewhere else
+        0xe8, 0x00, 0x00, 0x00, 0x00,
+        // call target again
+        0xe8, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let mut code = code.to_vec();
+    let ret = addr + 16;
+    let rel = (target as i64 - ret as i64) as i32;
+    code[12..16].copy_from_slice(&rel.to_le_bytes());
+
+    assert_eq!(
+        call_sites(&code, addr, target).collect::<Vec<_>>(),
+        vec![addr + 5, addr + 16]
+    );
+}
+
+#[test]
+fn test_pc_table() {
+    let table = PcTable::from_pcs(vec![0x30, 0x10, 0x20, 0x10]);
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.index(0x10), Some(0));
+    assert_eq!(table.index(0x30), Some(2));
+    assert_eq!(table.index(0x18), None);
+    assert_eq!(table.spare_slots(), 3..3 + SPARE_SLOTS);
+    assert_eq!(table.map_size() as usize % AFL_MAP_SIZE_ALIGN, 0);
+    assert!(table.map_size() as usize >= table.spare_slots().end);
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+#[cfg(test)]
+use imgcompress::structs::BTRFS_SUPERBLOCK_SIZE;
+use imgcompress::structs::{BtrfsSuperblock, BTRFS_SUPERBLOCK_MAGIC, BTRFS_SUPERBLOCK_OFFSET};
+use imgcompress::{parse, CompressedBtrfsImage, DecompressOptions, HeaderField};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::{fork, ForkResult};
+use structopt::StructOpt;
+use testcase::{MountOptions, Program, Signature, Testcase, Verdict};
+
+use crate::crash::{BtrfsSymbols, CrashReporter};
+use crate::kmsg::{self, CrashClass};
+use crate::mount::Mounter;
+use crate::{
+    kill_hung_child, reset_btrfs_devices, reset_extra_devices, wait_for_child, work,
+    FUZZED_IMAGE_PATH, MOUNTPOINT,
+};
+
+/// Options shared by every tool that runs test cases outside of the fuzzer
+#[derive(Debug, StructOpt)]
+pub struct ReproOpts {
+    /// Turn on debug output
+    #[structopt(short, long)]
+    pub debug: bool,
+    /// Pristine image of an additional device in a multi-device filesystem. The input image is
+    /// always the first device. May be specified more than once.
+    #[structopt(long = "extra-device", parse(from_os_str))]
+    pub extra_devices: Vec<PathBuf>,
+    /// File describing the operations to run against the mounted image, one per line. Overrides
+    /// the program carried by each test case.
+    #[structopt(long, parse(from_os_str))]
+    pub workload: Option<PathBuf>,
+    /// Comma separated mount flags and options to mount with. See `runner --help`.
+    #[structopt(long)]
+    pub mount_options: Option<MountOptions>,
+    /// Comma separated kinds of kernel reports that count as crashes. See `runner --help`.
+    #[structopt(long, use_delimiter = true, default_value = kmsg::DEFAULT_CRASH_CLASSES)]
+    pub crash_classes: Vec<CrashClass>,
+    /// File listing the functions defined in fs/btrfs, one per line. See `runner --help`.
+    #[structopt(long, parse(from_os_str))]
+    pub btrfs_symbols: Option<PathBuf>,
+    /// Node header field to leave as fuzzed. May be given more than once. See `runner --help`.
+    #[structopt(long, number_of_values = 1)]
+    pub no_header_fixup: Vec<HeaderField>,
+    /// Milliseconds an input may run before it's killed and reported as a hang. No limit if
+    /// not specified.
+    #[structopt(long)]
+    pub timeout: Option<u64>,
+}
+
+/// Returns true if `buf` is an uncompressed btrfs image rather than a serialized test case
+pub fn is_raw_image(buf: &[u8]) -> bool {
+    match parse::read::<BtrfsSuperblock>(buf, BTRFS_SUPERBLOCK_OFFSET) {
+        Ok(superblock) => superblock.magic == BTRFS_SUPERBLOCK_MAGIC,
+        Err(_) => false,
+    }
+}
+
+/// Turn the contents of an input file into an image, the options to mount it with and the
+/// program to run against it
+pub fn load_input(
+    buf: &[u8],
+    opts: &DecompressOptions,
+) -> Result<(Vec<u8>, MountOptions, Program)> {
+    if is_raw_image(buf) {
+        return Ok((buf.to_vec(), MountOptions::default(), Program::default()));
+    }
+
+    let testcase = Testcase::decode(buf)?;
+    let image = imgcompress::decompress_with(&testcase.image, opts)?;
+
+    Ok((image, testcase.mount_options, testcase.program))
+}
+
+/// Runs test cases through the same mount and workload path as the runner
+pub struct Reproducer {
+    reporter: CrashReporter,
+    mounter: Mounter,
+    extra_devices: Vec<PathBuf>,
+    workload: Option<Program>,
+    mount_options: Option<MountOptions>,
+    decompress_opts: DecompressOptions,
+    timeout: Option<Duration>,
+    debug: bool,
+}
+
+impl Reproducer {
+    pub fn new(opts: &ReproOpts) -> Result<Self> {
+        let workload = match &opts.workload {
+            Some(path) => Some(Program::from_file(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            reporter: CrashReporter::new(
+                opts.crash_classes.clone(),
+                Vec::new(),
+                None,
+                BtrfsSymbols::load(opts.btrfs_symbols.as_ref())?,
+                opts.debug,
+            )?,
+            mounter: Mounter::new(opts.extra_devices.len() + 1)?,
+            extra_devices: opts.extra_devices.clone(),
+            workload,
+            mount_options: opts.mount_options,
+            decompress_opts: DecompressOptions {
+                skip_header_fixups: opts.no_header_fixup.clone(),
+            },
+            timeout: opts.timeout.map(Duration::from_millis),
+            debug: opts.debug,
+        })
+    }
+
+    /// Run the input file at `input`. See `Reproducer::run`.
+    pub fn run_file(&mut self, input: &Path) -> Result<(Option<Signature>, Vec<String>)> {
+        let buf = fs::read(input).with_context(|| format!("Failed to read {}", input.display()))?;
+        let (image, mount_options, program) = load_input(&buf, &self.decompress_opts)?;
+
+        self.run(&image, &mount_options, &program, &testcase::id(&buf))
+    }
+
+    /// Decompress `image` with the same fixups the runner would use
+    pub fn decompress(&self, image: &CompressedBtrfsImage) -> Result<Vec<u8>> {
+        imgcompress::decompress_with(image, &self.decompress_opts)
+    }
+
+    /// Run `program` against `image` mounted with `mount_options` and return the signature of the crash, if any, along with
+    /// everything the kernel logged during the run
+    ///
+    /// Runs that time out are killed and get a signature with a `Verdict::Hang` verdict.
+    pub fn run(
+        &mut self,
+        image: &[u8],
+        mount_options: &MountOptions,
+        program: &Program,
+        id: &str,
+    ) -> Result<(Option<Signature>, Vec<String>)> {
+        let program = self.workload.as_ref().unwrap_or(program);
+        let mount_options = self.mount_options.as_ref().unwrap_or(mount_options);
+
+        fs::write(FUZZED_IMAGE_PATH, image)
+            .with_context(|| format!("Failed to write {}", FUZZED_IMAGE_PATH))?;
+
+        // Reset kernel state
+        reset_btrfs_devices()?;
+        let images = reset_extra_devices(&self.extra_devices)?;
+
+        // Don't blame this input for anything logged before it ran
+        self.reporter.read_messages()?;
+
+        // Run in a child exactly like the runner does so an oops only kills the child
+        match fork()? {
+            ForkResult::Parent { child } => {
+                let status = match wait_for_child(child, self.timeout)? {
+                    Some(status) => status,
+                    None => {
+                        if self.debug {
+                            println!("Input timed out, killing child={}", child);
+                        }
+
+                        let killed = kill_hung_child(child)?;
+                        let messages = self.reporter.read_messages()?;
+                        let sig = self.reporter.check_hang_messages(&messages, id)?;
+                        if !killed {
+                            bail!(
+                                "Forked child={} is stuck in the kernel and cannot be killed",
+                                child
+                            );
+                        }
+                        self.mounter.reset(MOUNTPOINT)?;
+
+                        return Ok((Some(sig), messages));
+                    }
+                };
+                let messages = self.reporter.read_messages()?;
+                let sig = self
+                    .reporter
+                    .check_messages(&messages, id)?
+                    .map(|f| f.signature);
+
+                let sig = match (sig, status) {
+                    (Some(sig), _) => Some(sig),
+                    (None, WaitStatus::Signaled(_, signal, _)) => Some(Signature {
+                        key: format!("signal:{:?}", signal),
+                        title: format!("Killed by {:?}", signal),
+                        verdict: Verdict::Crash,
+                    }),
+                    (None, WaitStatus::Exited(_, 0)) => None,
+                    (None, _) => bail!("Unexpected waitpid() status={:?}", status),
+                };
+
+                Ok((sig, messages))
+            }
+            ForkResult::Child => {
+                work(
+                    &mut self.mounter,
+                    &images,
+                    mount_options,
+                    program,
+                    self.debug,
+                    || (),
+                );
+                exit(0);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_is_raw_image() {
+    let mut image = vec![0; BTRFS_SUPERBLOCK_OFFSET + BTRFS_SUPERBLOCK_SIZE];
+    assert!(!is_raw_image(&image));
+    assert!(!is_raw_image(&[0x92]));
+
+    let superblock: &mut BtrfsSuperblock =
+        parse::read_mut(&mut image, BTRFS_SUPERBLOCK_OFFSET).unwrap();
+    superblock.magic = BTRFS_SUPERBLOCK_MAGIC;
+    assert!(is_raw_image(&image));
+}
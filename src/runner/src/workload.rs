@@ -0,0 +1,448 @@
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use libc::c_void;
+use nix::errno::Errno;
+use nix::fcntl::{fallocate, FallocateFlags};
+use nix::{ioctl_none, ioctl_read, ioctl_readwrite, ioctl_write_int, ioctl_write_ptr};
+use static_assertions::const_assert;
+use testcase::{Op, Program};
+
+use crate::BTRFS_IOCTL_MAGIC;
+
+/// Cap on write, truncate and fallocate lengths so a workload can't fill up the filesystem
+const MAX_LEN: u64 = 1 << 20;
+/// Max depth `Op::Readdir` descends to
+const MAX_READDIR_DEPTH: usize = 16;
+/// Directory (relative to the mount point) all the files live in
+const WORK_DIR: &str = "one/two/three/four/five/six";
+const XATTR_NAME: &str = "user.btrfs-fuzz";
+
+/// See /usr/include/linux/btrfs.h
+const BTRFS_SYNC_SEQ: u8 = 8;
+const FICLONE_SEQ: u8 = 9;
+const BTRFS_SNAP_CREATE_V2_SEQ: u8 = 23;
+const BTRFS_SUBVOL_CREATE_V2_SEQ: u8 = 24;
+const BTRFS_SCRUB_SEQ: u8 = 27;
+const BTRFS_DEV_INFO_SEQ: u8 = 30;
+const BTRFS_FS_INFO_SEQ: u8 = 31;
+const BTRFS_BALANCE_V2_SEQ: u8 = 32;
+const BTRFS_SEND_SEQ: u8 = 38;
+const BTRFS_SUBVOL_NAME_MAX: usize = 4039;
+const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+const BTRFS_BALANCE_DATA: u64 = 1 << 0;
+const BTRFS_BALANCE_SYSTEM: u64 = 1 << 1;
+const BTRFS_BALANCE_METADATA: u64 = 1 << 2;
+/// We don't use any balance filters so `struct btrfs_balance_args` is left opaque
+const BTRFS_BALANCE_ARGS_SIZE: usize = 136;
+/// Devids past this aren't looked for when scrubbing. Fuzzed devids can be arbitrarily large.
+const MAX_SCRUB_DEVID: u64 = 64;
+
+#[repr(C, packed)]
+pub struct BtrfsIoctlVolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; BTRFS_SUBVOL_NAME_MAX + 1],
+}
+const_assert!(size_of::<BtrfsIoctlVolArgsV2>() == 4096);
+
+#[repr(C, packed)]
+pub struct BtrfsIoctlScrubArgs {
+    devid: u64,
+    start: u64,
+    end: u64,
+    flags: u64,
+    progress: [u64; 15],
+    unused: [u64; 109],
+}
+const_assert!(size_of::<BtrfsIoctlScrubArgs>() == 1024);
+
+#[repr(C, packed)]
+pub struct BtrfsIoctlFsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    nodesize: u32,
+    sectorsize: u32,
+    clone_alignment: u32,
+    csum_type: u16,
+    csum_size: u16,
+    flags: u64,
+    generation: u64,
+    metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+const_assert!(size_of::<BtrfsIoctlFsInfoArgs>() == 1024);
+
+#[repr(C, packed)]
+pub struct BtrfsIoctlDevInfoArgs {
+    devid: u64,
+    uuid: [u8; 16],
+    bytes_used: u64,
+    total_bytes: u64,
+    unused: [u64; 379],
+    path: [u8; 1024],
+}
+const_assert!(size_of::<BtrfsIoctlDevInfoArgs>() == 4096);
+
+#[repr(C, packed)]
+pub struct BtrfsIoctlBalanceArgs {
+    flags: u64,
+    state: u64,
+    data: [u8; BTRFS_BALANCE_ARGS_SIZE],
+    meta: [u8; BTRFS_BALANCE_ARGS_SIZE],
+    sys: [u8; BTRFS_BALANCE_ARGS_SIZE],
+    stat: [u64; 3],
+    unused: [u64; 72],
+}
+const_assert!(size_of::<BtrfsIoctlBalanceArgs>() == 1024);
+
+#[repr(C, packed)]
+pub struct BtrfsIoctlSendArgs {
+    send_fd: i64,
+    clone_sources_count: u64,
+    clone_sources: *mut u64,
+    parent_root: u64,
+    flags: u64,
+    version: u32,
+    reserved: [u8; 28],
+}
+const_assert!(size_of::<BtrfsIoctlSendArgs>() == 72);
+
+ioctl_none!(btrfs_sync, BTRFS_IOCTL_MAGIC, BTRFS_SYNC_SEQ);
+ioctl_write_int!(ficlone, BTRFS_IOCTL_MAGIC, FICLONE_SEQ);
+ioctl_write_ptr!(
+    btrfs_snap_create_v2,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_SNAP_CREATE_V2_SEQ,
+    BtrfsIoctlVolArgsV2
+);
+ioctl_write_ptr!(
+    btrfs_subvol_create_v2,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_SUBVOL_CREATE_V2_SEQ,
+    BtrfsIoctlVolArgsV2
+);
+ioctl_readwrite!(
+    btrfs_scrub,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_SCRUB_SEQ,
+    BtrfsIoctlScrubArgs
+);
+ioctl_readwrite!(
+    btrfs_dev_info,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_DEV_INFO_SEQ,
+    BtrfsIoctlDevInfoArgs
+);
+ioctl_read!(
+    btrfs_fs_info,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_FS_INFO_SEQ,
+    BtrfsIoctlFsInfoArgs
+);
+ioctl_readwrite!(
+    btrfs_balance_v2,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_BALANCE_V2_SEQ,
+    BtrfsIoctlBalanceArgs
+);
+ioctl_write_ptr!(
+    btrfs_send,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_SEND_SEQ,
+    BtrfsIoctlSendArgs
+);
+/// Every devid in the filesystem mounted at `root`
+fn devids(root: &File) -> Result<Vec<u64>> {
+    let mut fs_info: BtrfsIoctlFsInfoArgs = unsafe { std::mem::zeroed() };
+    unsafe { btrfs_fs_info(root.as_raw_fd(), &mut fs_info) }
+        .with_context(|| "Failed to get filesystem info".to_string())?;
+
+    // Devids don't have to be contiguous so ask about each one until every device is found
+    let mut devids = Vec::new();
+    let last = fs_info.max_id.min(MAX_SCRUB_DEVID);
+    for devid in 1..=last {
+        if devids.len() as u64 >= fs_info.num_devices {
+            break;
+        }
+
+        let mut args: BtrfsIoctlDevInfoArgs = unsafe { std::mem::zeroed() };
+        args.devid = devid;
+        match unsafe { btrfs_dev_info(root.as_raw_fd(), &mut args) } {
+            Ok(_) => devids.push(devid),
+            Err(nix::Error::Sys(Errno::ENODEV)) => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to get info for devid={}", devid))
+            }
+        }
+    }
+
+    Ok(devids)
+}
+
+/// State shared between the ops in a single workload run
+struct RunState {
+    mountpoint: PathBuf,
+    /// Number of subvolumes and snapshots created so far. Used to generate unique names.
+    nr_subvols: usize,
+    /// Most recently created read-only snapshot
+    readonly_snapshot: Option<PathBuf>,
+}
+
+impl RunState {
+    fn file(&self, idx: u64) -> PathBuf {
+        self.mountpoint.join(WORK_DIR).join(format!("file{}", idx))
+    }
+
+    /// Create the arguments to make a new subvolume or snapshot under the mount point
+    ///
+    /// Returns the arguments along with the path the new subvolume will show up at.
+    fn new_subvol_args(&mut self, prefix: &str) -> (Box<BtrfsIoctlVolArgsV2>, PathBuf) {
+        let name = format!("{}{}", prefix, self.nr_subvols);
+        self.nr_subvols += 1;
+
+        // Box it b/c the args are a full page
+        let mut args: Box<BtrfsIoctlVolArgsV2> = Box::new(unsafe { std::mem::zeroed() });
+        args.name[..name.len()].copy_from_slice(name.as_bytes());
+
+        (args, self.mountpoint.join(name))
+    }
+}
+
+fn cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid path={}", path.display()))
+}
+
+/// Turn the return value of a libc call into a `Result`
+fn check_libc(ret: isize, what: &str) -> Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("Failed to {}", what));
+    }
+
+    Ok(())
+}
+
+fn open_rw(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+fn setxattr(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let path = cstring(path)?;
+    let name = CString::new(name)?;
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const c_void,
+            value.len(),
+            0,
+        )
+    };
+
+    check_libc(ret as isize, "setxattr")
+}
+
+fn readdir(path: &Path, depth: usize) -> Result<()> {
+    if depth > MAX_READDIR_DEPTH {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.is_dir() {
+            readdir(&entry.path(), depth + 1)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read every regular file under `path`. Errors reading one file don't stop the others from
+/// being read b/c corrupt data is expected.
+fn readall(path: &Path, depth: usize) -> Result<()> {
+    if depth > MAX_READDIR_DEPTH {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.is_dir() {
+            let _ = readall(&entry.path(), depth + 1);
+        } else if metadata.is_file() {
+            let mut buf = Vec::new();
+            let _ = File::open(entry.path()).and_then(|f| f.take(MAX_LEN).read_to_end(&mut buf));
+        }
+    }
+
+    Ok(())
+}
+
+fn run_op(op: &Op, ctx: &mut RunState) -> Result<()> {
+    match *op {
+        Op::Mkdir => fs::create_dir_all(ctx.mountpoint.join(WORK_DIR))?,
+        Op::Write { file, offset, len } => {
+            let mut f = open_rw(&ctx.file(file))?;
+            f.seek(SeekFrom::Start(offset.min(MAX_LEN)))?;
+            let buf: Vec<u8> = (0..len.min(MAX_LEN)).map(|i| i as u8).collect();
+            f.write_all(&buf)?;
+        }
+        Op::Read { file } => {
+            let mut buf = Vec::new();
+            File::open(ctx.file(file))?
+                .take(MAX_LEN)
+                .read_to_end(&mut buf)?;
+        }
+        Op::Fsync { file } => File::open(ctx.file(file))?.sync_all()?,
+        Op::Truncate { file, len } => open_rw(&ctx.file(file))?.set_len(len.min(MAX_LEN))?,
+        Op::Fallocate { file, offset, len } | Op::PunchHole { file, offset, len } => {
+            let flags = match op {
+                Op::PunchHole { .. } => {
+                    FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE
+                }
+                _ => FallocateFlags::empty(),
+            };
+            let f = open_rw(&ctx.file(file))?;
+            fallocate(
+                f.as_raw_fd(),
+                flags,
+                offset.min(MAX_LEN).try_into()?,
+                len.min(MAX_LEN).try_into()?,
+            )?;
+        }
+        Op::Rename { from, to } => fs::rename(ctx.file(from), ctx.file(to))?,
+        Op::Link { from, to } => fs::hard_link(ctx.file(from), ctx.file(to))?,
+        Op::Unlink { file } => fs::remove_file(ctx.file(file))?,
+        Op::Setxattr { file } => setxattr(&ctx.file(file), XATTR_NAME, b"btrfs-fuzz")?,
+        Op::Listxattr { file } => {
+            let path = cstring(&ctx.file(file))?;
+            let mut buf: Vec<u8> = vec![0; 4096];
+            let ret =
+                unsafe { libc::listxattr(path.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len()) };
+            check_libc(ret, "listxattr")?;
+        }
+        Op::Removexattr { file } => {
+            let path = cstring(&ctx.file(file))?;
+            let name = CString::new(XATTR_NAME)?;
+            let ret = unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) };
+            check_libc(ret as isize, "removexattr")?;
+        }
+        Op::Compress { file } => {
+            let path = ctx.file(file);
+            open_rw(&path)?;
+            setxattr(&path, "btrfs.compression", b"zstd")?;
+        }
+        Op::Reflink { from, to } => {
+            let src = File::open(ctx.file(from))?;
+            let dst = open_rw(&ctx.file(to))?;
+            unsafe { ficlone(dst.as_raw_fd(), src.as_raw_fd() as _) }
+                .with_context(|| "Failed to reflink".to_string())?;
+        }
+        Op::Readdir => readdir(&ctx.mountpoint, 0)?,
+        Op::ReadAll => readall(&ctx.mountpoint, 0)?,
+        Op::Subvol => {
+            let root = File::open(&ctx.mountpoint)?;
+            let (args, _) = ctx.new_subvol_args("subvol");
+            unsafe { btrfs_subvol_create_v2(root.as_raw_fd(), &*args) }
+                .with_context(|| "Failed to create subvolume".to_string())?;
+        }
+        Op::Snapshot { readonly } => {
+            let root = File::open(&ctx.mountpoint)?;
+            let (mut args, path) = ctx.new_subvol_args("snap");
+            args.fd = root.as_raw_fd().into();
+            if readonly {
+                args.flags = BTRFS_SUBVOL_RDONLY;
+            }
+            unsafe { btrfs_snap_create_v2(root.as_raw_fd(), &*args) }
+                .with_context(|| "Failed to create snapshot".to_string())?;
+
+            if readonly {
+                ctx.readonly_snapshot = Some(path);
+            }
+        }
+        Op::Send => {
+            if ctx.readonly_snapshot.is_none() {
+                run_op(&Op::Snapshot { readonly: true }, ctx)?;
+            }
+
+            let snapshot = match &ctx.readonly_snapshot {
+                Some(s) => File::open(s)?,
+                None => bail!("Failed to find read-only snapshot to send"),
+            };
+            let devnull = OpenOptions::new().write(true).open("/dev/null")?;
+            let mut args: BtrfsIoctlSendArgs = unsafe { std::mem::zeroed() };
+            args.send_fd = devnull.as_raw_fd().into();
+            unsafe { btrfs_send(snapshot.as_raw_fd(), &args) }
+                .with_context(|| "Failed to send snapshot".to_string())?;
+        }
+        Op::Sync => {
+            let root = File::open(&ctx.mountpoint)?;
+            unsafe { btrfs_sync(root.as_raw_fd()) }
+                .with_context(|| "Failed to sync filesystem".to_string())?;
+        }
+        Op::Scrub => {
+            let root = File::open(&ctx.mountpoint)?;
+            for devid in devids(&root)? {
+                let mut args: BtrfsIoctlScrubArgs = unsafe { std::mem::zeroed() };
+                args.devid = devid;
+                args.end = u64::MAX;
+                unsafe { btrfs_scrub(root.as_raw_fd(), &mut args) }
+                    .with_context(|| format!("Failed to scrub devid={}", devid))?;
+            }
+        }
+        Op::Balance => {
+            let root = File::open(&ctx.mountpoint)?;
+            let mut args: BtrfsIoctlBalanceArgs = unsafe { std::mem::zeroed() };
+            args.flags = BTRFS_BALANCE_DATA | BTRFS_BALANCE_METADATA | BTRFS_BALANCE_SYSTEM;
+            unsafe { btrfs_balance_v2(root.as_raw_fd(), &mut args) }
+                .with_context(|| "Failed to balance".to_string())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run every op in `program` against the filesystem mounted at `mountpoint`.
+///
+/// Failed ops don't stop the program b/c the ops after it can still reach new code. `after_op` is
+/// called after every op.
+pub fn run<P: AsRef<Path>, F: FnMut()>(
+    program: &Program,
+    mountpoint: P,
+    debug: bool,
+    mut after_op: F,
+) {
+    let mut ctx = RunState {
+        mountpoint: mountpoint.as_ref().to_path_buf(),
+        nr_subvols: 0,
+        readonly_snapshot: None,
+    };
+
+    for op in &program.ops {
+        if let Err(e) = run_op(op, &mut ctx) {
+            if debug {
+                eprintln!("Failed to run {:?}: {:#}", op, e);
+            }
+        }
+
+        after_op();
+    }
+}
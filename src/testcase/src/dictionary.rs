@@ -0,0 +1,114 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// Dictionaries stop growing past this many values
+const MAX_DICTIONARY_VALUES: usize = 4096;
+
+/// Anything at or above this is a kernel address rather than a value worth learning
+const KERNEL_ADDRESS_START: u64 = 0xffff_8000_0000_0000;
+
+/// Values the kernel was seen comparing against while running test cases, eg key types,
+/// objectids, generations and sizes.
+///
+/// The runner collects these with kcov and the mutator splices them into fields. Saved as one
+/// hex value per line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dictionary {
+    values: BTreeSet<u64>,
+}
+
+impl Dictionary {
+    /// Load the dictionary at `path`. A missing file is an empty dictionary.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read dictionary {}", path.display()))
+            }
+        };
+
+        let mut dictionary = Self::default();
+        for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let val = u64::from_str_radix(line.trim_start_matches("0x"), 16)
+                .with_context(|| format!("Invalid dictionary value={}", line))?;
+            dictionary.insert(val);
+        }
+
+        Ok(dictionary)
+    }
+
+    /// Save the dictionary to `path`. Readers never see a partially written dictionary.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let content: String = self.values.iter().map(|v| format!("{:#x}\n", v)).collect();
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to rename {} to {}", tmp.display(), path.display()))
+    }
+
+    /// Add `val` to the dictionary. Returns true if it wasn't there already.
+    ///
+    /// Values that are already easy to stumble upon and kernel addresses are skipped, as is
+    /// everything once the dictionary is full.
+    pub fn insert(&mut self, val: u64) -> bool {
+        if val <= 1 || val == u64::MAX || val >= KERNEL_ADDRESS_START {
+            return false;
+        }
+
+        if self.values.len() >= MAX_DICTIONARY_VALUES {
+            return false;
+        }
+
+        self.values.insert(val)
+    }
+
+    pub fn values(&self) -> Vec<u64> {
+        self.values.iter().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+#[test]
+fn test_dictionary() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("dictionary");
+    assert!(Dictionary::load(&path).unwrap().is_empty());
+
+    let mut dictionary = Dictionary::default();
+    assert!(dictionary.insert(0x84));
+    assert!(!dictionary.insert(0x84));
+    assert!(dictionary.insert(0x5f42485266535f4d));
+    assert!(!dictionary.insert(0));
+    assert!(!dictionary.insert(1));
+    assert!(!dictionary.insert(u64::MAX));
+    assert!(!dictionary.insert(0xffff_8881_0000_0000));
+    assert_eq!(dictionary.values(), vec![0x84, 0x5f42485266535f4d]);
+
+    dictionary.save(&path).unwrap();
+    assert_eq!(Dictionary::load(&path).unwrap(), dictionary);
+
+    fs::write(&path, "0x10\nnope\n").unwrap();
+    assert!(Dictionary::load(&path).is_err());
+
+    let mut full = Dictionary::default();
+    for val in 2..(MAX_DICTIONARY_VALUES as u64 + 10) {
+        full.insert(val);
+    }
+    assert_eq!(full.len(), MAX_DICTIONARY_VALUES);
+}
@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use rmp_serde::{decode::from_read_ref, Serializer};
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh64::xxh64;
+
+use imgcompress::CompressedBtrfsImage;
+
+mod dictionary;
+mod mount_options;
+mod program;
+mod signature;
+
+pub use dictionary::Dictionary;
+pub use mount_options::{MountOptions, MOUNT_FLAGS, MOUNT_OPTIONS};
+pub use program::{Op, Program, NR_FILES};
+pub use signature::{Signature, Verdict};
+
+/// Stable identifier for a serialized test case
+///
+/// AFL saves test cases byte for byte so the id of a saved crash matches the id the runner saw.
+pub fn id(buf: &[u8]) -> String {
+    format!("{:016x}", xxh64(buf, 0))
+}
+
+/// A single fuzzer input: a filesystem image, how to mount it and the program to run after it's
+/// mounted
+#[derive(Deserialize, Serialize, Default)]
+pub struct Testcase {
+    pub image: CompressedBtrfsImage,
+    pub program: Program,
+    #[serde(default)]
+    pub mount_options: MountOptions,
+}
+
+impl Testcase {
+    /// Deserialize a test case.
+    ///
+    /// Bare `CompressedBtrfsImage`s (eg seeds straight out of `imgcompress compress`) are also
+    /// accepted. They get the default program.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        match from_read_ref(buf) {
+            Ok(testcase) => Ok(testcase),
+            Err(_) => {
+                let image = from_read_ref(buf)
+                    .with_context(|| "Failed to deserialize test case or image".to_string())?;
+
+                Ok(Self {
+                    image,
+                    program: Program::default(),
+                    mount_options: MountOptions::default(),
+                })
+            }
+        }
+    }
+
+    /// Serialize this test case into `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        self.serialize(&mut Serializer::new(buf))?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_encode_decode() {
+    let mut testcase = Testcase::default();
+    testcase.image.data = vec![1, 2, 3];
+    testcase.program = "write 1 0 100\nsnapshot_ro\nsend".parse().unwrap();
+    testcase.mount_options = "ro,compress=zstd".parse().unwrap();
+
+    let mut buf = Vec::new();
+    testcase.encode(&mut buf).unwrap();
+    let decoded = Testcase::decode(&buf).unwrap();
+    assert_eq!(decoded.image.data, testcase.image.data);
+    assert_eq!(decoded.program, testcase.program);
+    assert_eq!(decoded.mount_options, testcase.mount_options);
+}
+
+#[test]
+fn test_decode_without_mount_options() {
+    #[derive(Serialize)]
+    struct OldTestcase {
+        image: CompressedBtrfsImage,
+        program: Program,
+    }
+
+    let old = OldTestcase {
+        image: CompressedBtrfsImage::default(),
+        program: "sync".parse().unwrap(),
+    };
+    let mut buf = Vec::new();
+    old.serialize(&mut Serializer::new(&mut buf)).unwrap();
+
+    let decoded = Testcase::decode(&buf).unwrap();
+    assert_eq!(decoded.program, old.program);
+    assert_eq!(decoded.mount_options, MountOptions::default());
+}
+
+#[test]
+fn test_decode_bare_image() {
+    let mut image = CompressedBtrfsImage::default();
+    image.data = vec![1, 2, 3];
+
+    let mut buf = Vec::new();
+    image.serialize(&mut Serializer::new(&mut buf)).unwrap();
+    let decoded = Testcase::decode(&buf).unwrap();
+    assert_eq!(decoded.image.data, image.data);
+    assert_eq!(decoded.program, Program::default());
+
+    assert!(Testcase::decode(&[0xc1]).is_err());
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// btrfs specific mount options a test case can turn on. They're passed to mount(2) in the data
+/// string.
+pub const MOUNT_OPTIONS: &[&str] = &[
+    "compress=zstd",
+    "compress-force=lzo",
+    "space_cache=v2",
+    "nospace_cache",
+    "clear_cache",
+    "nodatasum",
+    "nodatacow",
+    "ssd",
+    "nossd",
+    "discard=async",
+    "discard=sync",
+    "degraded",
+    "rescue=usebackuproot",
+    "rescue=ignorebadroots",
+    "rescue=nologreplay",
+    "skip_balance",
+    "autodefrag",
+    "flushoncommit",
+    "notreelog",
+    "commit=1",
+    "max_inline=0",
+    "noacl",
+    "user_subvol_rm_allowed",
+];
+
+/// Generic mount(2) flags a test case can turn on
+pub const MOUNT_FLAGS: &[&str] = &["ro", "sync", "dirsync", "noatime", "nodiratime"];
+
+/// How to mount a test case's image. Nothing is turned on by default.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    /// Bit `i` turns on `MOUNT_OPTIONS[i]`
+    pub options: u32,
+    /// Bit `i` turns on `MOUNT_FLAGS[i]`
+    pub flags: u32,
+}
+
+fn selected(bits: u32, names: &'static [&'static str]) -> Vec<&'static str> {
+    names
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| bits & (1 << i) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+impl MountOptions {
+    /// btrfs specific options that are turned on. Unknown bits are ignored.
+    pub fn option_names(&self) -> Vec<&'static str> {
+        selected(self.options, MOUNT_OPTIONS)
+    }
+
+    /// mount(2) flags that are turned on. Unknown bits are ignored.
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        selected(self.flags, MOUNT_FLAGS)
+    }
+}
+
+impl FromStr for MountOptions {
+    type Err = anyhow::Error;
+
+    /// Parse comma separated flags and options, eg `ro,compress=zstd,ssd`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut mount_options = Self::default();
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if let Some(i) = MOUNT_FLAGS.iter().position(|f| *f == name) {
+                mount_options.flags |= 1 << i;
+            } else if let Some(i) = MOUNT_OPTIONS.iter().position(|o| *o == name) {
+                mount_options.options |= 1 << i;
+            } else {
+                bail!("Unsupported mount option={}", name);
+            }
+        }
+
+        Ok(mount_options)
+    }
+}
+
+impl fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = self.flag_names();
+        names.extend(self.option_names());
+
+        write!(f, "{}", names.join(","))
+    }
+}
+
+#[test]
+fn test_mount_options() {
+    assert!(MOUNT_OPTIONS.len() <= 32);
+    assert!(MOUNT_FLAGS.len() <= 32);
+
+    let mount_options: MountOptions = "compress=zstd, ro,rescue=usebackuproot".parse().unwrap();
+    assert_eq!(mount_options.flag_names(), vec!["ro"]);
+    assert_eq!(
+        mount_options.option_names(),
+        vec!["compress=zstd", "rescue=usebackuproot"]
+    );
+    assert_eq!(
+        mount_options.to_string(),
+        "ro,compress=zstd,rescue=usebackuproot"
+    );
+    assert_eq!(
+        mount_options.to_string().parse::<MountOptions>().unwrap(),
+        mount_options
+    );
+
+    assert_eq!("".parse::<MountOptions>().unwrap(), MountOptions::default());
+    assert!("frobnicate".parse::<MountOptions>().is_err());
+
+    // Bits past the end of the tables don't select anything
+    let unknown = MountOptions {
+        options: 1 << 31,
+        flags: 1 << 31,
+    };
+    assert!(unknown.option_names().is_empty());
+    assert!(unknown.flag_names().is_empty());
+}
@@ -0,0 +1,394 @@
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Number of distinct files ops can refer to
+pub const NR_FILES: u64 = 4;
+
+/// Program for test cases that don't carry their own. Same format as `Program::from_file`.
+const DEFAULT_PROGRAM: &str = "
+readall
+mkdir
+write 0 0 4096
+fsync 0
+compress 1
+write 1 0 131072
+fsync 1
+reflink 0 2
+setxattr 2
+listxattr 2
+removexattr 2
+fallocate 3 0 65536
+punch_hole 3 4096 8192
+truncate 1 1000
+read 1
+rename 2 3
+link 0 2
+unlink 0
+readdir
+sync
+subvol
+snapshot
+snapshot_ro
+send
+scrub
+balance
+sync
+";
+
+/// A single filesystem operation.
+///
+/// Files are referred to by index (less than `NR_FILES`) so ops in a program can operate on
+/// the same files.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Create the directory all the files live in
+    Mkdir,
+    Write {
+        file: u64,
+        offset: u64,
+        len: u64,
+    },
+    Read {
+        file: u64,
+    },
+    Fsync {
+        file: u64,
+    },
+    Truncate {
+        file: u64,
+        len: u64,
+    },
+    Fallocate {
+        file: u64,
+        offset: u64,
+        len: u64,
+    },
+    PunchHole {
+        file: u64,
+        offset: u64,
+        len: u64,
+    },
+    Rename {
+        from: u64,
+        to: u64,
+    },
+    Link {
+        from: u64,
+        to: u64,
+    },
+    Unlink {
+        file: u64,
+    },
+    Setxattr {
+        file: u64,
+    },
+    Listxattr {
+        file: u64,
+    },
+    Removexattr {
+        file: u64,
+    },
+    /// Create `file` and turn on zstd compression for it
+    Compress {
+        file: u64,
+    },
+    /// Clone all of `from`'s extents into `to`
+    Reflink {
+        from: u64,
+        to: u64,
+    },
+    /// Recursively list and stat everything in the filesystem
+    Readdir,
+    Subvol,
+    Snapshot {
+        readonly: bool,
+    },
+    /// Send the last read-only snapshot to /dev/null. Takes a read-only snapshot if necessary.
+    Send,
+    Sync,
+    /// Scrub every device in the filesystem
+    Scrub,
+    Balance,
+    /// Read back every regular file in the filesystem, including the ones already in the image
+    ReadAll,
+}
+
+fn parse_args<const N: usize>(name: &str, args: &[u64]) -> Result<[u64; N]> {
+    args.try_into()
+        .map_err(|_| anyhow!("Op={} takes {} argument(s) but got {}", name, N, args.len()))
+}
+
+fn parse_file(file: u64) -> Result<u64> {
+    if file >= NR_FILES {
+        bail!("File index={} is out of range (max={})", file, NR_FILES - 1);
+    }
+
+    Ok(file)
+}
+
+impl FromStr for Op {
+    type Err = anyhow::Error;
+
+    /// Parse an op from its name followed by its space separated arguments, eg `write 0 0 4096`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tokens = s.split_whitespace();
+        let name = tokens.next().ok_or_else(|| anyhow!("Empty op"))?;
+        let args = tokens
+            .map(|t| {
+                t.parse::<u64>()
+                    .with_context(|| format!("Invalid argument={} for op={}", t, name))
+            })
+            .collect::<Result<Vec<u64>>>()?;
+
+        let op = match name {
+            "mkdir" => {
+                parse_args::<0>(name, &args)?;
+                Op::Mkdir
+            }
+            "write" => {
+                let [file, offset, len] = parse_args(name, &args)?;
+                Op::Write {
+                    file: parse_file(file)?,
+                    offset,
+                    len,
+                }
+            }
+            "read" => {
+                let [file] = parse_args(name, &args)?;
+                Op::Read {
+                    file: parse_file(file)?,
+                }
+            }
+            "fsync" => {
+                let [file] = parse_args(name, &args)?;
+                Op::Fsync {
+                    file: parse_file(file)?,
+                }
+            }
+            "truncate" => {
+                let [file, len] = parse_args(name, &args)?;
+                Op::Truncate {
+                    file: parse_file(file)?,
+                    len,
+                }
+            }
+            "fallocate" => {
+                let [file, offset, len] = parse_args(name, &args)?;
+                Op::Fallocate {
+                    file: parse_file(file)?,
+                    offset,
+                    len,
+                }
+            }
+            "punch_hole" => {
+                let [file, offset, len] = parse_args(name, &args)?;
+                Op::PunchHole {
+                    file: parse_file(file)?,
+                    offset,
+                    len,
+                }
+            }
+            "rename" => {
+                let [from, to] = parse_args(name, &args)?;
+                Op::Rename {
+                    from: parse_file(from)?,
+                    to: parse_file(to)?,
+                }
+            }
+            "link" => {
+                let [from, to] = parse_args(name, &args)?;
+                Op::Link {
+                    from: parse_file(from)?,
+                    to: parse_file(to)?,
+                }
+            }
+            "unlink" => {
+                let [file] = parse_args(name, &args)?;
+                Op::Unlink {
+                    file: parse_file(file)?,
+                }
+            }
+            "setxattr" => {
+                let [file] = parse_args(name, &args)?;
+                Op::Setxattr {
+                    file: parse_file(file)?,
+                }
+            }
+            "listxattr" => {
+                let [file] = parse_args(name, &args)?;
+                Op::Listxattr {
+                    file: parse_file(file)?,
+                }
+            }
+            "removexattr" => {
+                let [file] = parse_args(name, &args)?;
+                Op::Removexattr {
+                    file: parse_file(file)?,
+                }
+            }
+            "compress" => {
+                let [file] = parse_args(name, &args)?;
+                Op::Compress {
+                    file: parse_file(file)?,
+                }
+            }
+            "reflink" => {
+                let [from, to] = parse_args(name, &args)?;
+                Op::Reflink {
+                    from: parse_file(from)?,
+                    to: parse_file(to)?,
+                }
+            }
+            "readdir" => {
+                parse_args::<0>(name, &args)?;
+                Op::Readdir
+            }
+            "subvol" => {
+                parse_args::<0>(name, &args)?;
+                Op::Subvol
+            }
+            "snapshot" | "snapshot_ro" => {
+                parse_args::<0>(name, &args)?;
+                Op::Snapshot {
+                    readonly: name == "snapshot_ro",
+                }
+            }
+            "send" => {
+                parse_args::<0>(name, &args)?;
+                Op::Send
+            }
+            "sync" => {
+                parse_args::<0>(name, &args)?;
+                Op::Sync
+            }
+            "scrub" => {
+                parse_args::<0>(name, &args)?;
+                Op::Scrub
+            }
+            "balance" => {
+                parse_args::<0>(name, &args)?;
+                Op::Balance
+            }
+            "readall" => {
+                parse_args::<0>(name, &args)?;
+                Op::ReadAll
+            }
+            _ => bail!("Unknown op={}", name),
+        };
+
+        Ok(op)
+    }
+}
+
+/// A sequence of filesystem operations to run against a mounted image
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub ops: Vec<Op>,
+}
+
+impl FromStr for Program {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut ops = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            // Everything after a '#' is a comment
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            ops.push(
+                line.parse()
+                    .with_context(|| format!("Failed to parse line {}", i + 1))?,
+            );
+        }
+
+        Ok(Self { ops })
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        DEFAULT_PROGRAM
+            .parse()
+            .expect("Failed to parse default program")
+    }
+}
+
+impl Program {
+    /// Read a program from a file containing one op per line, eg:
+    ///
+    /// ```text
+    /// mkdir
+    /// write 0 0 4096  # file, offset, length
+    /// reflink 0 1     # from, to
+    /// snapshot_ro
+    /// send
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .with_context(|| format!("Failed to read program {}", path.display()))?
+            .parse()
+            .with_context(|| format!("Failed to parse program {}", path.display()))
+    }
+}
+
+#[test]
+fn test_parse_op() {
+    assert_eq!(
+        "write 1 4096 100".parse::<Op>().unwrap(),
+        Op::Write {
+            file: 1,
+            offset: 4096,
+            len: 100
+        }
+    );
+    assert_eq!(
+        "snapshot_ro".parse::<Op>().unwrap(),
+        Op::Snapshot { readonly: true }
+    );
+
+    // Wrong number of arguments
+    assert!("write 1 4096".parse::<Op>().is_err());
+    assert!("sync 1".parse::<Op>().is_err());
+    // File index out of range
+    assert!(format!("unlink {}", NR_FILES).parse::<Op>().is_err());
+    // Garbage
+    assert!("write a b c".parse::<Op>().is_err());
+    assert!("frobnicate".parse::<Op>().is_err());
+}
+
+#[test]
+fn test_parse_program() {
+    let program: Program = "
+        # Comment
+        mkdir
+        write 0 0 4096  # trailing comment
+
+        rename 0 1
+    "
+    .parse()
+    .unwrap();
+    assert_eq!(
+        program.ops,
+        vec![
+            Op::Mkdir,
+            Op::Write {
+                file: 0,
+                offset: 0,
+                len: 4096
+            },
+            Op::Rename { from: 0, to: 1 },
+        ]
+    );
+
+    // Default program should always be valid
+    assert!(!Program::default().ops.is_empty());
+}
@@ -0,0 +1,136 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+/// Extension of crash signature files
+const SIGNATURE_EXT: &str = "sig";
+
+/// How the runner reported a test case to AFL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Saved to `crashes/`
+    Crash,
+    /// Timed out, saved to `hangs/`
+    Hang,
+    /// Kernel logged something worth keeping but the run didn't fail. Saved to the queue.
+    Interesting,
+}
+
+impl FromStr for Verdict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let verdict = match s {
+            "crash" => Verdict::Crash,
+            "hang" => Verdict::Hang,
+            "interesting" => Verdict::Interesting,
+            _ => bail!("Unknown verdict={}", s),
+        };
+
+        Ok(verdict)
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Verdict::Crash => "crash",
+            Verdict::Hang => "hang",
+            Verdict::Interesting => "interesting",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Stable description of a crash. Test cases that hit the same bug have the same signature.
+///
+/// The runner saves one of these for every failing test case. See `Signature::path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// Report class plus the top btrfs frames, eg `kasan:btrfs_get_16:check_leaf`
+    pub key: String,
+    /// Human readable title of the kernel report
+    pub title: String,
+    pub verdict: Verdict,
+}
+
+impl Signature {
+    /// Path the signature for the test case with id `id` lives at inside `dir`
+    pub fn path<P: AsRef<Path>>(dir: P, id: &str) -> PathBuf {
+        dir.as_ref().join(format!("{}.{}", id, SIGNATURE_EXT))
+    }
+
+    /// Save the signature for the test case with id `id` into `dir`
+    pub fn write<P: AsRef<Path>>(&self, dir: P, id: &str) -> Result<()> {
+        let path = Self::path(dir, id);
+        let content = format!(
+            "signature: {}\ntitle: {}\nverdict: {}\n",
+            self.key, self.title, self.verdict
+        );
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write signature {}", path.display()))
+    }
+
+    /// Load the signature for the test case with id `id` from `dir`, if there is one
+    pub fn read<P: AsRef<Path>>(dir: P, id: &str) -> Result<Option<Self>> {
+        let path = Self::path(dir, id);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read signature {}", path.display()))?;
+
+        let mut key = None;
+        let mut title = None;
+        // Signatures from before verdicts were recorded only ever described crashes
+        let mut verdict = Verdict::Crash;
+        for line in content.lines() {
+            if let Some(k) = line.strip_prefix("signature: ") {
+                key = Some(k.to_string());
+            } else if let Some(t) = line.strip_prefix("title: ") {
+                title = Some(t.to_string());
+            } else if let Some(v) = line.strip_prefix("verdict: ") {
+                verdict = v.parse()?;
+            }
+        }
+
+        match (key, title) {
+            (Some(key), Some(title)) => Ok(Some(Self {
+                key,
+                title,
+                verdict,
+            })),
+            _ => bail!("Malformed signature {}", path.display()),
+        }
+    }
+}
+
+#[test]
+fn test_signature_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let sig = Signature {
+        key: "kasan:btrfs_get_16:check_leaf".to_string(),
+        title: "BUG: KASAN: slab-out-of-bounds in btrfs_get_16".to_string(),
+        verdict: Verdict::Hang,
+    };
+
+    assert_eq!(Signature::read(dir.path(), "abc").unwrap(), None);
+    sig.write(dir.path(), "abc").unwrap();
+    assert_eq!(Signature::read(dir.path(), "abc").unwrap(), Some(sig));
+
+    fs::write(
+        Signature::path(dir.path(), "old"),
+        "signature: kasan:btrfs_get_16\ntitle: BUG\n",
+    )
+    .unwrap();
+    let old = Signature::read(dir.path(), "old").unwrap().unwrap();
+    assert_eq!(old.verdict, Verdict::Crash);
+
+    fs::write(Signature::path(dir.path(), "bad"), "garbage").unwrap();
+    assert!(Signature::read(dir.path(), "bad").is_err());
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use structopt::StructOpt;
+use testcase::{Signature, Verdict};
+
+/// Key of the bucket for crashes the runner did not save a signature for
+const UNKNOWN_KEY: &str = "unknown";
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "triage", about = "Triage crashes found by btrfs-fuzz")]
+struct Opt {
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Group a crash directory into buckets by crash signature
+    Bucket {
+        /// Directory of crashing test cases, eg `output/crashes`
+        #[structopt(parse(from_os_str))]
+        crashes: PathBuf,
+        /// Directory the runner saved crash signatures to (`--signature-dir`)
+        #[structopt(long, parse(from_os_str))]
+        signatures: PathBuf,
+        /// Print every test case in each bucket instead of just one representative
+        #[structopt(long)]
+        all: bool,
+    },
+}
+
+/// Crashes that share a signature
+struct Bucket {
+    key: String,
+    title: String,
+    crashes: Vec<PathBuf>,
+}
+
+/// Sort every test case in `crashes` into a bucket. Biggest buckets come first.
+fn bucket<P: AsRef<Path>, Q: AsRef<Path>>(crashes: P, signatures: Q) -> Result<Vec<Bucket>> {
+    let crashes = crashes.as_ref();
+    let mut entries = fs::read_dir(crashes)
+        .with_context(|| format!("Failed to read {}", crashes.display()))?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Keep output stable across runs
+    entries.sort();
+
+    let mut buckets: HashMap<String, Bucket> = HashMap::new();
+    for path in entries {
+        // AFL drops a README.txt in the crash directory
+        if !path.is_file() || path.file_name() == Some("README.txt".as_ref()) {
+            continue;
+        }
+
+        let buf = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let sig = Signature::read(&signatures, &testcase::id(&buf))?.unwrap_or(Signature {
+            key: UNKNOWN_KEY.to_string(),
+            title: "no signature saved".to_string(),
+            verdict: Verdict::Crash,
+        });
+
+        buckets
+            .entry(sig.key.clone())
+            .or_insert_with(|| Bucket {
+                key: sig.key,
+                title: sig.title,
+                crashes: Vec::new(),
+            })
+            .crashes
+            .push(path);
+    }
+
+    let mut buckets: Vec<Bucket> = buckets.into_values().collect();
+    buckets.sort_by(|a, b| {
+        b.crashes
+            .len()
+            .cmp(&a.crashes.len())
+            .then_with(|| a.key.cmp(&b.key))
+    });
+
+    Ok(buckets)
+}
+
+fn print_buckets(buckets: &[Bucket], all: bool) {
+    let total: usize = buckets.iter().map(|b| b.crashes.len()).sum();
+    println!("{} crashes in {} buckets", total, buckets.len());
+
+    for b in buckets {
+        println!();
+        println!("[{}] {}", b.crashes.len(), b.key);
+        println!("\t{}", b.title);
+
+        let shown = if all { b.crashes.len() } else { 1 };
+        for crash in b.crashes.iter().take(shown) {
+            println!("\t{}", crash.display());
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let opts = Opt::from_args();
+
+    match opts.cmd {
+        Command::Bucket {
+            crashes,
+            signatures,
+            all,
+        } => {
+            let buckets = bucket(crashes, signatures)?;
+            print_buckets(&buckets, all);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_bucket() {
+    let crashes = tempfile::tempdir().unwrap();
+    let signatures = tempfile::tempdir().unwrap();
+
+    let sig = Signature {
+        key: "bug:btrfs_get_16:check_leaf".to_string(),
+        title: "kernel BUG at fs/btrfs/ctree.c".to_string(),
+        verdict: Verdict::Crash,
+    };
+    for (name, content) in &[("a", "aaaa"), ("b", "bbbb"), ("c", "cccc")] {
+        fs::write(crashes.path().join(name), content).unwrap();
+        if *name != "c" {
+            sig.write(signatures.path(), &testcase::id(content.as_bytes()))
+                .unwrap();
+        }
+    }
+    fs::write(crashes.path().join("README.txt"), "afl stuff").unwrap();
+
+    let buckets = bucket(crashes.path(), signatures.path()).unwrap();
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[0].key, sig.key);
+    assert_eq!(
+        buckets[0].crashes,
+        vec![crashes.path().join("a"), crashes.path().join("b")]
+    );
+    assert_eq!(buckets[1].key, UNKNOWN_KEY);
+    assert_eq!(buckets[1].crashes, vec![crashes.path().join("c")]);
+}
@@ -148,9 +148,13 @@ def cmd_seed(args):
 
         sh(f"mkfs.btrfs {image_path}")
 
+    compress_flags = "--data" if args.data else ""
+
     # Compress raw image into a new file and then remove the raw image
     compressed_image_path = f"{args.state_dir}/input/img_compressed"
-    sh(f"cargo run --bin imgcompress -- compress {image_path} {compressed_image_path}")
+    sh(
+        f"cargo run --bin imgcompress -- compress {compress_flags} {image_path} {compressed_image_path}"
+    )
     sh(f"rm {image_path}")
 
     # Copy files from checked in corpus over too
@@ -164,7 +168,9 @@ def cmd_seed(args):
         compressed_path = f"{args.state_dir}/input/{compressed_fname}"
 
         sh(f"zstd -d ./corpus/{f} -o {raw_path}")
-        sh(f"cargo run -p imgcompress compress -- {raw_path} {compressed_path}")
+        sh(
+            f"cargo run -p imgcompress compress -- {compress_flags} {raw_path} {compressed_path}"
+        )
         sh(f"rm {raw_path}")
 
     # Write a readme to describe what each directory contains
@@ -185,19 +191,29 @@ def cmd_repro(args):
 
     print(f"Reproducing {args.image}")
 
-    # Share the entire directory containing the image under test
-    image_dir = str(pathlib.Path(args.image).parent)
+    # Share the entire directory containing the image under test. If we're
+    # reproducing a whole directory of images, share that directory instead.
+    image_path = pathlib.Path(args.image)
+    if image_path.is_dir():
+        image_dir = str(image_path)
+        repro_input = "/state"
+    else:
+        image_dir = str(image_path.parent)
+        repro_input = f"/state/{image_path.name}"
     if image_dir[0] != "/":
         # Necessary so docker doesn't freak out
         image_dir = "./" + image_dir
 
-    image_fname = str(pathlib.Path(args.image).name)
-
     c = ["podman run"]
     c.append("-it")
     c.append("--privileged")
     c.append(f"-v {image_dir}:/state")
 
+    if args.summary:
+        summary_path = pathlib.Path(args.summary)
+        summary_dir = sanitize_docker_dir(str(summary_path.parent))
+        c.append(f"-v {summary_dir}:/summary")
+
     if args.remote:
         c.append(DOCKER_IMAGE_REMOTE)
     else:
@@ -208,21 +224,31 @@ def cmd_repro(args):
     p.sendline('/bin/bash -c "echo core > /proc/sys/kernel/core_pattern"')
 
     c = []
-    c.append("/btrfs-fuzz/runner")
-    c.append(f"< /state/{image_fname}")
+    c.append("/btrfs-fuzz/repro")
+    c.append("--btrfs-symbols /btrfs-fuzz/btrfs-symbols")
+    if args.summary:
+        c.append(f"--summary /summary/{summary_path.name}")
+    if args.timeout:
+        c.append(f"--timeout {args.timeout}")
+    c.append(repro_input)
 
     p.expect("root@.*#")
 
     if args.exit:
         # Send child output to stdout
         p.logfile_read = sys.stdout
-        p.sendline(" ".join(c))
+        p.sendline(" ".join(c) + '; echo "repro exit status: $?"')
 
+        p.expect(r"repro exit status: (\d+)")
+        rc = int(p.match.group(1))
         p.expect("root@.*#")
 
         # `C-a x` to exit qemu
         p.sendcontrol("a")
         p.send("x")
+
+        # See `repro --help` for what the exit status means
+        sys.exit(rc)
     else:
         p.sendline(" ".join(c))
 
@@ -336,18 +362,36 @@ def main():
         default="./_state",
         help="Shared state directory between host and VM",
     )
+    seed.add_argument(
+        "--data",
+        action="store_true",
+        help="Fuzz file data extents in addition to metadata",
+    )
     seed.set_defaults(func=cmd_seed)
 
     repro = subparsers.add_parser("repro", help="reproduce a test case")
     repro.add_argument(
         "image",
         type=str,
-        help="btrfs filesystem image to test against (must be imgcompress-compressed)",
+        help="test case or raw btrfs image to test against, or a directory of "
+        "either (eg `_state/output/crashes`)",
     )
     repro.add_argument(
         "--exit",
         action="store_true",
-        help="Exit VM after repro runs (useful for scripting)",
+        help="Exit VM after repro runs and exit with repro's exit status "
+        "(useful for scripting)",
+    )
+    repro.add_argument(
+        "--summary",
+        type=str,
+        help="Write a JSON summary of every run to this file",
+    )
+    repro.add_argument(
+        "--timeout",
+        type=int,
+        help="Milliseconds each input may run before it's killed and reported "
+        "as a hang (eg to reproduce `_state/output/hangs`)",
     )
     repro.set_defaults(func=cmd_repro)
 